@@ -39,6 +39,7 @@ pub struct Config {
     pub private_key: Option<String>,
     pub opa_sidecar_addr: Option<Url>,
     pub no_authz: bool,
+    pub principal_header: Option<String>,
     pub statsd_server: Option<String>,
     pub json_log: bool,
     pub log: String,
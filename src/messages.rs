@@ -30,6 +30,10 @@ pub enum TokenState {
     Error,
     /// task failed but is going to be retried
     Retry,
+    /// task was not run because its upstream task chose a different branch
+    Skipped,
+    /// sensor's condition didn't hold yet, it will be poked again after its poke interval
+    Sensing,
 }
 
 impl TokenState {
@@ -68,6 +72,8 @@ impl AsRef<str> for TokenState {
             TokenState::Error => "error",
             TokenState::Cancelled => "cancelled",
             TokenState::Retry => "retry",
+            TokenState::Skipped => "skipped",
+            TokenState::Sensing => "sensing",
         }
     }
 }
@@ -88,6 +94,8 @@ impl FromStr for TokenState {
             "error" => Ok(TokenState::Error),
             "cancelled" => Ok(TokenState::Cancelled),
             "retry" => Ok(TokenState::Retry),
+            "skipped" => Ok(TokenState::Skipped),
+            "sensing" => Ok(TokenState::Sensing),
             _ => Err(TokenStateParseError(format!("invalid token state: '{s}'"))),
         }
     }
@@ -104,6 +112,22 @@ pub struct TaskRequest {
     pub task_run_id: Uuid,
     pub task_id: Uuid,
     pub trigger_datetime: DateTime<Utc>,
+    #[serde(default)]
+    pub map: Option<MapInstance>,
+    /// environment variables given when the task was activated manually, these replace
+    /// variables of the same name from the task definition
+    #[serde(default)]
+    pub env_overrides: Option<Vec<String>>,
+}
+
+/// One instance of a mapped task, ie. a task run for a single element of the mapped list
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MapInstance {
+    /// all instances created from the same list share a batch id
+    pub batch_id: Uuid,
+    pub index: i32,
+    pub count: i32,
+    pub item: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -119,6 +143,34 @@ pub struct TaskDef {
     pub env: Option<Vec<String>>,
     pub paused: bool,
     pub timeout: Option<Duration>,
+    /// sensor tasks check this condition instead of running an image
+    #[serde(default)]
+    pub sensor: Option<SensorCondition>,
+}
+
+/// The condition a sensor task waits for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SensorCondition {
+    /// a GET request returns the status (any 2xx status if not given)
+    Http { url: String, status: Option<u16> },
+    /// a file exists at the path on the worker (eg. on a mounted volume)
+    File { path: String },
+    /// a stash key exists (in the job stash for the trigger datetime by default)
+    Stash {
+        key: String,
+        #[serde(default)]
+        scope: StashScope,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StashScope {
+    Global,
+    Project,
+    #[default]
+    Job,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -130,6 +182,9 @@ pub struct TaskProgress {
     pub finished_datetime: Option<DateTime<Utc>>,
     pub result: TokenState,
     pub worker_id: Uuid,
+    /// exit code of the task's container, if the engine could find it
+    #[serde(default)]
+    pub exit_code: Option<i64>,
 }
 
 // impl TaskProgress {
@@ -178,6 +233,9 @@ pub struct WorkerHeartbeat {
     pub last_seen_datetime: DateTime<Utc>,
     pub running_tasks: i32,
     pub total_tasks: i32,
+    /// how many tasks the worker runs at once, not sent by older workers
+    #[serde(default)]
+    pub max_tasks: Option<i32>,
     pub version: String,
 }
 
@@ -5,6 +5,16 @@ CREATE TABLE IF NOT EXISTS project (
     config JSONB
 );
 
+CREATE TABLE IF NOT EXISTS job_template (
+    id UUID PRIMARY KEY,
+    project_id UUID NOT NULL REFERENCES project(id),
+    name VARCHAR NOT NULL,
+    description VARCHAR,
+    raw_definition VARCHAR NOT NULL,
+    updated_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE(project_id, name)
+);
+
 CREATE TABLE IF NOT EXISTS job (
     id UUID PRIMARY KEY,
     name VARCHAR NOT NULL,
@@ -15,6 +25,24 @@ CREATE TABLE IF NOT EXISTS job (
     UNIQUE(project_id, name) INCLUDE (id)
 );
 
+ALTER TABLE job ADD COLUMN IF NOT EXISTS archived_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE job ADD COLUMN IF NOT EXISTS template_id UUID REFERENCES job_template(id);
+ALTER TABLE job ADD COLUMN IF NOT EXISTS template_instance JSONB;
+
+CREATE TABLE IF NOT EXISTS job_version (
+    job_id UUID NOT NULL REFERENCES job(id),
+    version INT NOT NULL,
+    created_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    principal VARCHAR,
+    hash VARCHAR NOT NULL,
+    raw_definition VARCHAR NOT NULL,
+    PRIMARY KEY(job_id, version)
+);
+
+-- a submission identical to the previous version refers to that version's definition
+ALTER TABLE job_version ALTER COLUMN raw_definition DROP NOT NULL;
+ALTER TABLE job_version ADD COLUMN IF NOT EXISTS definition_version INT;
+
 CREATE TABLE IF NOT EXISTS trigger (
     id UUID PRIMARY KEY,
     name VARCHAR NOT NULL,
@@ -30,6 +58,58 @@ CREATE TABLE IF NOT EXISTS trigger (
     UNIQUE(job_id, name) INCLUDE (id)
 );
 
+-- names of calendars in the job's project whose dates the trigger doesn't fire on
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS calendars VARCHAR[];
+
+-- 'time' triggers fire on their schedule, 'event' triggers when an event is posted,
+-- 'amqp' triggers for each message published to an exchange and 'stash' triggers when
+-- a project stash key is written
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS kind VARCHAR NOT NULL DEFAULT 'time';
+ALTER TABLE trigger ALTER COLUMN start_datetime DROP NOT NULL;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS amqp_exchange VARCHAR;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS amqp_routing_key VARCHAR;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS amqp_datetime_header VARCHAR;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS amqp_datetime_pointer VARCHAR;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS stash_project VARCHAR;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS stash_key VARCHAR;
+CREATE INDEX IF NOT EXISTS trigger_by_stash_key ON trigger(stash_project, stash_key)
+    WHERE kind = 'stash';
+
+-- limits on catchup, older missed periods are skipped (max age is in seconds)
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS catchup_max_runs INT;
+ALTER TABLE trigger ADD COLUMN IF NOT EXISTS catchup_max_age BIGINT;
+
+-- holidays and other dates when triggers using the calendar don't fire
+CREATE TABLE IF NOT EXISTS calendar (
+    id UUID PRIMARY KEY,
+    project_id UUID NOT NULL REFERENCES project(id),
+    name VARCHAR NOT NULL,
+    description VARCHAR,
+    dates DATE[] NOT NULL,
+    updated_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE(project_id, name)
+);
+
+-- trigger datetimes which are skipped (or explicitly not skipped, overriding a calendar)
+CREATE TABLE IF NOT EXISTS trigger_skip (
+    trigger_id UUID NOT NULL REFERENCES trigger(id),
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    skipped BOOLEAN NOT NULL,
+    reason VARCHAR NOT NULL,
+    updated_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE(trigger_id, trigger_datetime)
+);
+
+-- idempotency keys (or message ids) of events received by event and amqp triggers, and
+-- the write times which fired stash triggers
+CREATE TABLE IF NOT EXISTS trigger_event (
+    trigger_id UUID NOT NULL REFERENCES trigger(id),
+    idempotency_key VARCHAR NOT NULL,
+    trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    received_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE(trigger_id, idempotency_key)
+);
+
 CREATE TABLE IF NOT EXISTS task (
     id UUID PRIMARY KEY,
     name VARCHAR NOT NULL,
@@ -44,6 +124,15 @@ CREATE TABLE IF NOT EXISTS task (
     UNIQUE(job_id, name) INCLUDE (id)
 );
 
+ALTER TABLE task ADD COLUMN IF NOT EXISTS branch_names VARCHAR[];
+ALTER TABLE task ADD COLUMN IF NOT EXISTS branch_stash_key VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS map_stash_key VARCHAR;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS archived_datetime TIMESTAMP WITH TIME ZONE;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS sensor JSONB;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS sensor_poke_interval_secs BIGINT;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS sensor_timeout_secs BIGINT;
+ALTER TABLE task ADD COLUMN IF NOT EXISTS branch_exit_codes JSONB;
+
 CREATE TABLE IF NOT EXISTS token (
     task_id UUID NOT NULL REFERENCES task(id),
     trigger_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
@@ -52,6 +141,8 @@ CREATE TABLE IF NOT EXISTS token (
     UNIQUE(task_id, trigger_datetime)
 );
 
+ALTER TABLE token ADD COLUMN IF NOT EXISTS env_overrides VARCHAR[];
+
 CREATE TABLE IF NOT EXISTS worker (
     id UUID PRIMARY KEY,
     addr VARCHAR,
@@ -61,6 +152,8 @@ CREATE TABLE IF NOT EXISTS worker (
     version VARCHAR
 );
 
+ALTER TABLE worker ADD COLUMN IF NOT EXISTS max_tasks INT;
+
 CREATE TABLE IF NOT EXISTS scheduler (
     id UUID PRIMARY KEY,
     last_seen_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
@@ -83,9 +176,22 @@ CREATE TABLE IF NOT EXISTS task_run (
     attempt BIGINT NOT NULL
 );
 
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS map_batch_id UUID;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS map_index INT;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS map_count INT;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS map_item VARCHAR;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS manual BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS principal VARCHAR;
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS env_overrides VARCHAR[];
+ALTER TABLE task_run ADD COLUMN IF NOT EXISTS exit_code BIGINT;
+
 CREATE INDEX IF NOT EXISTS task_run_by_state
     ON task_run(state, finish_datetime, task_id);
 
+CREATE INDEX IF NOT EXISTS task_run_by_map_batch
+    ON task_run(map_batch_id, map_index)
+    WHERE map_batch_id IS NOT NULL;
+
 CREATE TABLE IF NOT EXISTS retry (
     task_run_id UUID NOT NULL REFERENCES task_run(id),
     retry_at_datetime TIMESTAMP WITH TIME ZONE NOT NULL,
@@ -107,6 +213,36 @@ CREATE TABLE IF NOT EXISTS task_edge (
     UNIQUE(parent_task_id, child_task_id, kind)
 );
 
+ALTER TABLE task_edge ADD COLUMN IF NOT EXISTS branch VARCHAR;
+
+-- edges from `depends_cyclic`, which are allowed to close a cycle
+ALTER TABLE task_edge ADD COLUMN IF NOT EXISTS cyclic BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- offset ranges create several edges between the same nodes, one per offset
+ALTER TABLE trigger_edge DROP CONSTRAINT IF EXISTS trigger_edge_trigger_id_task_id_key;
+CREATE UNIQUE INDEX IF NOT EXISTS trigger_edge_by_offset
+    ON trigger_edge(trigger_id, task_id, COALESCE(edge_offset, 0));
+
+ALTER TABLE task_edge DROP CONSTRAINT IF EXISTS task_edge_parent_task_id_child_task_id_kind_key;
+CREATE UNIQUE INDEX IF NOT EXISTS task_edge_by_offset
+    ON task_edge(parent_task_id, child_task_id, kind, COALESCE(edge_offset, 0));
+
+CREATE TABLE IF NOT EXISTS dynamic_reference (
+    task_id UUID NOT NULL REFERENCES task(id),
+    job_id UUID NOT NULL REFERENCES job(id),
+    UNIQUE(task_id, job_id)
+);
+
+-- dependencies on nodes in jobs which don't exist yet, they are linked when the job is created
+CREATE TABLE IF NOT EXISTS pending_edge (
+    task_id UUID NOT NULL REFERENCES task(id),
+    project_name VARCHAR NOT NULL,
+    job_name VARCHAR NOT NULL,
+    reference VARCHAR NOT NULL,
+    kind VARCHAR NOT NULL,
+    UNIQUE(task_id, reference, kind)
+);
+
 CREATE TABLE IF NOT EXISTS global_stash (
     name VARCHAR PRIMARY KEY,
     data BYTEA
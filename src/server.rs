@@ -17,14 +17,15 @@ use std::sync::{Arc, atomic::AtomicUsize};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+mod amqp_triggers;
 pub mod api;
 pub mod body_parser;
 mod cluster;
-mod execute;
+pub mod execute;
 mod heartbeat;
-mod progress;
+pub mod progress;
 mod requeue;
-mod retries;
+pub mod retries;
 pub mod tokens;
 mod trigger_time;
 pub mod triggers;
@@ -82,6 +83,11 @@ impl Server {
             self.clone(),
             updates::process_trigger_updates,
         );
+        spawn_or_crash(
+            "amqp_triggers",
+            self.clone(),
+            amqp_triggers::process_amqp_triggers,
+        );
         spawn_or_crash(
             "trigger_cluster_changes",
             self.clone(),
@@ -0,0 +1,371 @@
+use crate::{
+    messages::{TaskPriority, Token},
+    server::{
+        Server,
+        api::types::EVENT_STASH_KEY,
+        trigger_time::TriggerTime,
+        triggers::{TriggerChange, do_activate_trigger, send_to_token_processor},
+    },
+};
+use anyhow::Result;
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::TryStreamExt;
+use lapin::{
+    BasicProperties,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+};
+use postage::prelude::*;
+use serde_json::Value as JsonValue;
+use sqlx::{Postgres, Transaction};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+/// Changes to the AMQP triggers run by this scheduler, sent the same way as `TriggerChange` is
+/// for time triggers
+#[derive(Clone, Debug)]
+pub struct AmqpTriggerChange(pub TriggerChange);
+
+/// how long to wait before restarting a consumer which failed (eg. the exchange doesn't exist)
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// the broker deletes trigger queues with no consumer for this long (in milliseconds), so the
+/// queues of deleted triggers don't pile up
+const QUEUE_EXPIRES: i32 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(sqlx::FromRow, Debug)]
+struct AmqpTrigger {
+    id: Uuid,
+    exchange: String,
+    routing_key: Option<String>,
+    datetime_header: Option<String>,
+    datetime_pointer: Option<String>,
+}
+
+impl AmqpTrigger {
+    fn queue_name(&self) -> String {
+        format!("waterwheel.triggers.{}", self.id)
+    }
+
+    /// Get the trigger datetime from the message, or None if it should be the time the message
+    /// was received
+    fn message_datetime(
+        &self,
+        properties: &BasicProperties,
+        data: &[u8],
+    ) -> Result<Option<DateTime<Utc>>, String> {
+        if let Some(name) = &self.datetime_header {
+            let value = properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| headers.inner().get(name.as_str()))
+                .ok_or_else(|| format!("message has no header '{name}'"))?;
+
+            let datetime = match value {
+                AMQPValue::LongString(s) => parse_datetime(&s.to_string())?,
+                AMQPValue::ShortString(s) => parse_datetime(s.as_str())?,
+                AMQPValue::Timestamp(ts) => from_timestamp(*ts)?,
+                other => return Err(format!("header '{name}' is not a datetime: {other:?}")),
+            };
+            return Ok(Some(datetime));
+        }
+
+        if let Some(pointer) = &self.datetime_pointer {
+            let body: JsonValue = serde_json::from_slice(data)
+                .map_err(|err| format!("message body is not JSON: {err}"))?;
+
+            let datetime = body
+                .pointer(pointer)
+                .and_then(JsonValue::as_str)
+                .ok_or_else(|| format!("message body has no string at '{pointer}'"))?;
+            return Ok(Some(parse_datetime(datetime)?));
+        }
+
+        properties.timestamp().map(from_timestamp).transpose()
+    }
+}
+
+fn parse_datetime(s: &str) -> Result<DateTime<Utc>, String> {
+    s.parse()
+        .map_err(|err| format!("'{s}' is not a valid datetime: {err}"))
+}
+
+fn from_timestamp(ts: u64) -> Result<DateTime<Utc>, String> {
+    i64::try_from(ts)
+        .ok()
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+        .ok_or_else(|| format!("timestamp {ts} is out of range"))
+}
+
+/// Start and stop the consumers for the AMQP triggers this scheduler owns
+pub async fn process_amqp_triggers(server: Arc<Server>) -> Result<!> {
+    let mut change_rx = server
+        .post_office
+        .receive_mail::<AmqpTriggerChange>()
+        .await?;
+    let mut consumers: HashMap<Uuid, JoinHandle<()>> = HashMap::new();
+
+    while let Some(AmqpTriggerChange(change)) = change_rx.recv().await {
+        match change {
+            TriggerChange::Remove(ids) => {
+                for id in ids {
+                    if let Some(consumer) = consumers.remove(&id) {
+                        debug!(trigger_id=?id, "stopping AMQP trigger consumer");
+                        consumer.abort();
+                    }
+                }
+            }
+            TriggerChange::Add(ids) => {
+                for id in ids {
+                    // the trigger may have been updated, so restart its consumer
+                    if let Some(consumer) = consumers.remove(&id) {
+                        consumer.abort();
+                    }
+
+                    match get_amqp_trigger(&server, id).await? {
+                        Some(trigger) => {
+                            let consumer = tokio::spawn(run_consumer(server.clone(), trigger));
+                            consumers.insert(id, consumer);
+                        }
+                        None => debug!(trigger_id=?id, "AMQP trigger is paused or was removed"),
+                    }
+                }
+            }
+        }
+
+        trace!("running {} AMQP trigger consumers", consumers.len());
+    }
+
+    unreachable!("AMQP trigger change channel closed")
+}
+
+async fn get_amqp_trigger(server: &Server, id: Uuid) -> Result<Option<AmqpTrigger>> {
+    let trigger = sqlx::query_as(
+        "SELECT
+            t.id,
+            t.amqp_exchange AS exchange,
+            t.amqp_routing_key AS routing_key,
+            t.amqp_datetime_header AS datetime_header,
+            t.amqp_datetime_pointer AS datetime_pointer
+        FROM trigger t
+        JOIN job j ON t.job_id = j.id
+        WHERE t.id = $1
+        AND t.kind = 'amqp'
+        AND NOT j.paused",
+    )
+    .bind(id)
+    .fetch_optional(&server.db_pool)
+    .await?;
+
+    Ok(trigger)
+}
+
+async fn run_consumer(server: Arc<Server>, trigger: AmqpTrigger) {
+    loop {
+        match consume(&server, &trigger).await {
+            Ok(()) => warn!(trigger_id=?trigger.id, "AMQP trigger consumer stopped"),
+            Err(err) => warn!(trigger_id=?trigger.id, "AMQP trigger consumer failed: {:#}", err),
+        }
+        time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn consume(server: &Server, trigger: &AmqpTrigger) -> Result<()> {
+    let chan = server.amqp_conn.create_channel().await?;
+    let queue = trigger.queue_name();
+
+    let mut args = FieldTable::default();
+    args.insert("x-expires".into(), AMQPValue::LongInt(QUEUE_EXPIRES));
+
+    // the queue is durable so messages published while the trigger moves between
+    // schedulers aren't lost
+    chan.queue_declare(
+        &queue,
+        QueueDeclareOptions {
+            durable: true,
+            ..QueueDeclareOptions::default()
+        },
+        args,
+    )
+    .await?;
+
+    chan.queue_bind(
+        &queue,
+        &trigger.exchange,
+        trigger.routing_key.as_deref().unwrap_or(""),
+        QueueBindOptions::default(),
+        FieldTable::default(),
+    )
+    .await?;
+
+    let mut consumer = chan
+        .basic_consume(
+            &queue,
+            "scheduler",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    info!(trigger_id=?trigger.id, exchange=%trigger.exchange, "consuming AMQP trigger messages");
+
+    while let Some(delivery) = consumer.try_next().await? {
+        match trigger.message_datetime(&delivery.properties, &delivery.data) {
+            Ok(datetime) => {
+                let datetime = datetime.unwrap_or_else(|| Utc::now().trunc_subsecs(0));
+                fire_trigger(server, trigger, datetime, &delivery).await?;
+                delivery.ack(BasicAckOptions::default()).await?;
+            }
+            Err(err) => {
+                warn!(trigger_id=?trigger.id, "dropping AMQP trigger message: {}", err);
+                delivery
+                    .nack(BasicNackOptions {
+                        requeue: false,
+                        ..BasicNackOptions::default()
+                    })
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn fire_trigger(
+    server: &Server,
+    trigger: &AmqpTrigger,
+    trigger_datetime: DateTime<Utc>,
+    delivery: &Delivery,
+) -> Result<()> {
+    let pool = server.db_pool.clone();
+    let mut txn = pool.begin().await?;
+
+    // a message redelivered after it was processed, or published twice, is only run once
+    if let Some(message_id) = delivery.properties.message_id() {
+        let inserted: Option<(Uuid,)> = sqlx::query_as(
+            "INSERT INTO trigger_event(trigger_id, idempotency_key, trigger_datetime,
+                received_datetime)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(trigger_id, idempotency_key)
+            DO NOTHING
+            RETURNING trigger_id",
+        )
+        .bind(trigger.id)
+        .bind(message_id.as_str())
+        .bind(trigger_datetime)
+        .bind(Utc::now())
+        .fetch_optional(txn.as_mut())
+        .await?;
+
+        if inserted.is_none() {
+            info!(trigger_id=?trigger.id, %message_id, "ignoring duplicate AMQP trigger message");
+            return Ok(());
+        }
+    }
+
+    let tokens = do_activate_trigger(
+        &pool,
+        &mut txn,
+        TriggerTime {
+            scheduled_datetime: trigger_datetime,
+            trigger_id: trigger.id,
+            trigger_datetime,
+        },
+    )
+    .await?;
+
+    if let Ok(payload) = serde_json::from_slice::<JsonValue>(&delivery.data) {
+        for token in &tokens {
+            stash_payload(&mut txn, token, &payload).await?;
+        }
+    }
+
+    txn.commit().await?;
+
+    info!(trigger_id=?trigger.id, trigger_datetime=%trigger_datetime.to_rfc3339(),
+        "trigger fired by AMQP message");
+
+    send_to_token_processor(server, tokens, TaskPriority::Normal).await?;
+
+    Ok(())
+}
+
+/// Write the message into the job stash for the token's trigger datetime
+async fn stash_payload(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+    payload: &JsonValue,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO job_stash(job_id, trigger_datetime, name, data)
+        SELECT job_id, $2, $3, $4
+        FROM task
+        WHERE id = $1
+        ON CONFLICT (job_id, trigger_datetime, name)
+        DO UPDATE
+        SET data = $4",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(EVENT_STASH_KEY)
+    .bind(payload.to_string().as_bytes())
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use std::collections::BTreeMap;
+
+    fn trigger(header: Option<&str>, pointer: Option<&str>) -> AmqpTrigger {
+        AmqpTrigger {
+            id: Uuid::nil(),
+            exchange: "data".to_owned(),
+            routing_key: None,
+            datetime_header: header.map(str::to_owned),
+            datetime_pointer: pointer.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_message_datetime() {
+        let expected = Utc.with_ymd_and_hms(2024, 3, 1, 6, 0, 0).unwrap();
+        let body = br#"{"batch": {"date": "2024-03-01T06:00:00Z"}}"#;
+
+        let none = BasicProperties::default();
+        assert_eq!(trigger(None, None).message_datetime(&none, body), Ok(None));
+
+        let stamped = BasicProperties::default().with_timestamp(expected.timestamp() as u64);
+        assert_eq!(
+            trigger(None, None).message_datetime(&stamped, body),
+            Ok(Some(expected))
+        );
+
+        let pointer = trigger(None, Some("/batch/date"));
+        assert_eq!(pointer.message_datetime(&none, body), Ok(Some(expected)));
+        assert!(pointer.message_datetime(&none, b"{}").is_err());
+        assert!(pointer.message_datetime(&none, b"not json").is_err());
+
+        let headers = FieldTable::from(BTreeMap::from([(
+            "data-date".into(),
+            AMQPValue::LongString("2024-03-01T06:00:00Z".into()),
+        )]));
+        let with_header = BasicProperties::default().with_headers(headers);
+        let header = trigger(Some("data-date"), None);
+        assert_eq!(
+            header.message_datetime(&with_header, b""),
+            Ok(Some(expected))
+        );
+        assert!(header.message_datetime(&none, b"").is_err());
+    }
+}
@@ -7,6 +7,7 @@ use std::{path::Path, sync::Arc};
 use tracing::{debug, warn};
 
 pub mod auth;
+mod calendar;
 mod config_cache;
 mod heartbeat;
 mod job;
@@ -17,6 +18,7 @@ mod schedulers;
 mod stash;
 mod status;
 mod task;
+mod task_explain;
 mod task_logs;
 pub mod types;
 mod updates;
@@ -84,6 +86,21 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .get(project::get_by_id)
         .delete(project::delete);
     app.at("/api/projects/:id/jobs").get(project::list_jobs);
+    app.at("/api/projects/:id/forecast")
+        .get(job::get_project_forecast);
+    app.at("/api/projects/:id/apply").post(job::apply);
+    app.at("/api/projects/:id/templates")
+        .get(job::list_templates)
+        .post(job::create_template);
+    app.at("/api/projects/:id/templates/:name")
+        .get(job::get_template)
+        .delete(job::delete_template);
+    app.at("/api/projects/:id/calendars")
+        .get(calendar::list)
+        .post(calendar::create);
+    app.at("/api/projects/:id/calendars/:name")
+        .get(calendar::get)
+        .delete(calendar::delete);
 
     app.at("/int-api/projects/:id/config")
         .get(project::get_config);
@@ -102,6 +119,7 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .get(job::get_by_name)
         .post(job::create)
         .put(job::create);
+    app.at("/api/jobs/validate").post(job::validate);
     app.at("/api/jobs/:id")
         .get(job::get_by_id)
         .delete(job::delete);
@@ -112,6 +130,15 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
     app.at("/api/jobs/:id/graph").get(job::get_graph);
     app.at("/api/jobs/:id/duration").get(job::get_duration);
 
+    // job versions
+    app.at("/api/jobs/:id/versions").get(job::list_versions);
+    app.at("/api/jobs/:id/versions/:version")
+        .get(job::get_version);
+    app.at("/api/jobs/:id/versions/:version/diff")
+        .get(job::diff_version);
+    app.at("/api/jobs/:id/versions/:version/rollback")
+        .post(job::rollback);
+
     // job tokens
     app.at("/api/jobs/:id/tokens").get(job::get_tokens);
     app.at("/api/jobs/:id/tokens-overview")
@@ -142,6 +169,12 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
         .post(task::activate_multiple_tokens);
     app.at("/api/tasks/:id/tokens/:trigger_datetime")
         .put(task::activate_token);
+    app.at("/api/tasks/:id/tokens/:trigger_datetime/state")
+        .put(task::mark_token);
+    app.at("/api/tasks/:id/explain/:trigger_datetime")
+        .get(task_explain::explain);
+    app.at("/api/tasks/:id/clear-downstream")
+        .post(task::clear_downstream);
     app.at("/int-api/tasks/:id")
         .get(task::internal_get_task_def);
 
@@ -153,7 +186,18 @@ pub async fn make_app(config: Config) -> Result<highnoon::App<State>> {
     app.at("/api/task_runs/:id/logs").ws(task_logs::logs);
 
     // trigger times
+    app.at("/api/triggers/forecast").post(job::forecast_trigger);
     app.at("/api/triggers/:id").get(job::get_trigger);
+    app.at("/api/triggers/:id/forecast")
+        .get(job::get_trigger_forecast);
+    app.at("/api/triggers/:id/fire/:trigger_datetime")
+        .post(job::fire_trigger);
+    app.at("/api/triggers/:id/events")
+        .post(job::post_trigger_event);
+    app.at("/api/triggers/:id/skips")
+        .get(job::list_trigger_skips);
+    app.at("/api/triggers/:id/skips/:trigger_datetime")
+        .put(job::set_trigger_skip);
 
     // workers
     app.at("/api/workers").get(workers::list);
@@ -64,6 +64,17 @@ fn derive_principal<S: highnoon::State>(req: &highnoon::Request<S>) -> Result<Pr
     Ok(Principal { bearer })
 }
 
+/// Name of the user making the request, as provided by the authentication proxy.
+/// This is for recording who did something, it must not be used for authorization.
+pub fn principal_name(req: &highnoon::Request<State>) -> Option<String> {
+    let header = req.state().config.principal_header.as_ref()?;
+
+    req.headers()
+        .get(header.as_str())
+        .and_then(|val| val.to_str().ok())
+        .map(|val| val.to_owned())
+}
+
 fn derive_http<S: highnoon::State>(req: &highnoon::Request<S>) -> Result<Http> {
     let mut headers = HashMap::new();
 
@@ -0,0 +1,227 @@
+use super::{State, auth, request_ext::RequestExt, types::Calendar};
+use crate::server::body_parser::read_from_body;
+use chrono::{DateTime, NaiveDate, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use serde::Serialize;
+use sqlx::{PgExecutor, PgPool};
+use tracing::info;
+use uuid::Uuid;
+
+pub async fn calendar_exists<'e>(
+    executor: impl PgExecutor<'e>,
+    project: &str,
+    name: &str,
+) -> highnoon::Result<bool> {
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT c.id
+        FROM calendar c
+        JOIN project p ON p.id = c.project_id
+        WHERE p.name = $1
+        AND c.name = $2",
+    )
+    .bind(project)
+    .bind(name)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ListCalendar {
+    calendar_id: Uuid,
+    name: String,
+    description: String,
+    num_dates: i32,
+    updated_datetime: DateTime<Utc>,
+}
+
+pub async fn list(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+
+    auth::list()
+        .project(project_id)
+        .kind("calendar")
+        .check(&req)
+        .await?;
+
+    let calendars: Vec<ListCalendar> = sqlx::query_as(
+        "SELECT
+            id AS calendar_id,
+            name,
+            COALESCE(description, '') AS description,
+            COALESCE(array_length(dates, 1), 0) AS num_dates,
+            updated_datetime
+        FROM calendar
+        WHERE project_id = $1
+        ORDER BY name",
+    )
+    .bind(project_id)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Ok(Json(calendars))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct GetCalendar {
+    calendar_id: Uuid,
+    name: String,
+    description: String,
+    dates: Vec<NaiveDate>,
+    updated_datetime: DateTime<Utc>,
+}
+
+pub async fn get(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let name = req.param("name")?;
+
+    auth::get()
+        .project(project_id)
+        .kind("calendar")
+        .check(&req)
+        .await?;
+
+    let calendar: Option<GetCalendar> = sqlx::query_as(
+        "SELECT
+            id AS calendar_id,
+            name,
+            COALESCE(description, '') AS description,
+            dates,
+            updated_datetime
+        FROM calendar
+        WHERE project_id = $1
+        AND name = $2",
+    )
+    .bind(project_id)
+    .bind(name)
+    .fetch_optional(&req.get_pool())
+    .await?;
+
+    match calendar {
+        Some(calendar) => Json(calendar).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct CreatedCalendar {
+    calendar_id: Uuid,
+}
+
+/// Create or replace a calendar. Triggers look their calendars up each time they fire, so the
+/// new dates apply without updating the jobs.
+pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let mut calendar: Calendar = read_from_body(&mut req).await?;
+
+    auth::update()
+        .project(project_id)
+        .kind("calendar")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+
+    let exists: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM project WHERE id = $1")
+        .bind(project_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    if exists.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    calendar.dates.sort();
+    calendar.dates.dedup();
+
+    let (calendar_id,): (Uuid,) = sqlx::query_as(
+        "INSERT INTO calendar(id, project_id, name, description, dates, updated_datetime)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT(project_id, name)
+        DO UPDATE
+        SET description = $4,
+            dates = $5,
+            updated_datetime = $6
+        RETURNING id",
+    )
+    .bind(Uuid::new_v4())
+    .bind(project_id)
+    .bind(&calendar.name)
+    .bind(&calendar.description)
+    .bind(&calendar.dates)
+    .bind(Utc::now())
+    .fetch_one(&pool)
+    .await?;
+
+    info!("updated calendar {} -> {}", calendar.name, calendar_id);
+
+    Response::status(StatusCode::CREATED).json(CreatedCalendar { calendar_id })
+}
+
+/// Get the names of the (unarchived) jobs with triggers using the calendar
+async fn get_calendar_jobs(
+    pool: &PgPool,
+    project_id: Uuid,
+    name: &str,
+) -> highnoon::Result<Vec<String>> {
+    let jobs: Vec<(String,)> = sqlx::query_as(
+        "SELECT DISTINCT j.name
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        WHERE j.project_id = $1
+        AND $2 = ANY(g.calendars)
+        AND j.archived_datetime IS NULL
+        ORDER BY j.name",
+    )
+    .bind(project_id)
+    .bind(name)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(jobs.into_iter().map(|(name,)| name).collect())
+}
+
+/// Delete a calendar. This is refused while any (unarchived) jobs use it.
+pub async fn delete(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let name = req.param("name")?;
+
+    auth::delete()
+        .project(project_id)
+        .kind("calendar")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+
+    let jobs = get_calendar_jobs(&pool, project_id, name).await?;
+    if !jobs.is_empty() {
+        return Err(highnoon::Error::http((
+            StatusCode::CONFLICT,
+            format!(
+                "calendar is used by {} jobs: {}",
+                jobs.len(),
+                jobs.join(", ")
+            ),
+        )));
+    }
+
+    let done = sqlx::query(
+        "DELETE FROM calendar
+        WHERE project_id = $1
+        AND name = $2",
+    )
+    .bind(project_id)
+    .bind(name)
+    .execute(&pool)
+    .await?;
+
+    if done.rows_affected() == 0 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    info!(?project_id, "deleted calendar {}", name);
+
+    StatusCode::NO_CONTENT.into_response()
+}
@@ -19,16 +19,18 @@ pub async fn post(mut req: Request<State>) -> highnoon::Result<impl Responder> {
             last_seen_datetime,
             running_tasks,
             total_tasks,
-            version
+            version,
+            max_tasks
         )
-        VALUES($1, $2, $3, $4, $5, $6)
+        VALUES($1, $2, $3, $4, $5, $6, $7)
         ON CONFLICT(id)
         DO UPDATE
         SET addr = $2,
             last_seen_datetime = $3,
             running_tasks = $4,
             total_tasks = $5,
-            version = $6",
+            version = $6,
+            max_tasks = $7",
     )
     .bind(beat.uuid)
     .bind(&beat.addr)
@@ -36,6 +38,7 @@ pub async fn post(mut req: Request<State>) -> highnoon::Result<impl Responder> {
     .bind(beat.running_tasks)
     .bind(beat.total_tasks)
     .bind(&beat.version)
+    .bind(beat.max_tasks)
     .execute(&req.get_pool())
     .await?;
 
@@ -4,30 +4,49 @@ use crate::{
         api::{State, auth, config_cache, request_ext::RequestExt, types::Job, updates},
         body_parser::read_from_body,
     },
-    util::{is_pg_integrity_error, pg_error},
 };
+use chrono::{DateTime, Utc};
 use highnoon::{Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use sqlx::PgPool;
 use tracing::{info, warn};
 use uuid::Uuid;
 
+mod apply;
+mod cycles;
+pub mod defaults;
+mod delete;
 mod duration;
+mod forecast;
 mod graph;
 pub mod reference;
 mod task_runs;
 mod tasks;
+mod template;
 mod tokens;
 mod triggers;
+mod upsert;
+mod validate;
+mod versions;
 
 pub use self::{
+    apply::apply,
+    delete::delete,
     duration::get_duration,
+    forecast::{forecast_trigger, get_project_forecast, get_trigger_forecast},
     graph::get_graph,
     tasks::list_tasks,
+    template::{create_template, delete_template, get_template, list_templates},
     tokens::{
         clear_tokens_trigger_datetime, get_tokens, get_tokens_overview, get_tokens_trigger_datetime,
     },
-    triggers::{get_trigger, get_triggers_by_job},
+    triggers::{
+        fire_stash_triggers, fire_trigger, get_trigger, get_triggers_by_job, list_trigger_skips,
+        post_trigger_event, set_trigger_skip,
+    },
+    validate::validate,
+    versions::{diff_version, get_version, list_versions, rollback},
 };
 use crate::{
     messages::{ProcessToken, TriggerUpdate},
@@ -65,83 +84,36 @@ pub async fn get_project_id(pool: &PgPool, name: &str) -> highnoon::Result<Uuid>
 }
 
 pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
-    let pool = req.get_pool();
+    let value: JsonValue = read_from_body(&mut req).await?;
+    let (job, link) = template::declared_job(&req.get_pool(), value).await?;
+    apply_job(&req, &job, link.as_ref()).await
+}
 
-    let job: Job = read_from_body(&mut req).await?;
+/// Create or update a job from its definition, and notify the scheduler and workers
+async fn apply_job(
+    req: &Request<State>,
+    job: &Job,
+    link: Option<&template::TemplateLink>,
+) -> highnoon::Result<Response> {
+    let pool = req.get_pool();
 
     let project_id = get_project_id(&pool, &job.project).await?;
-    auth::update().job(job.uuid, project_id).check(&req).await?;
-
-    let mut txn = pool.begin().await?;
-
-    let query = sqlx::query(
-        "INSERT INTO job(
-            id, name, project_id, description, paused, raw_definition
-        ) VALUES (
-            $1, $2, $3, $4,
-            COALESCE($5, FALSE),
-            $6
-        )
-        ON CONFLICT(id)
-        DO UPDATE
-        SET name = $2,
-            project_id = $3,
-            description = $4,
-            paused = COALESCE($5, job.paused),
-            raw_definition = $6",
-    );
-
-    let res = query
-        .bind(job.uuid)
-        .bind(&job.name)
-        .bind(project_id)
-        .bind(&job.description)
-        .bind(job.paused)
-        .bind(serde_json::to_string(&job)?)
-        .execute(txn.as_mut())
-        .await;
-
-    match pg_error(res)? {
-        Ok(_done) => {
-            info!("created job {} -> {}", job.name, job.uuid);
-        }
-        Err(err) => {
-            warn!("error creating job: {}", err);
-            return if is_pg_integrity_error(&err) {
-                StatusCode::CONFLICT.into_response()
-            } else {
-                Err(err.into())
-            };
-        }
-    };
+    auth::update().job(job.uuid, project_id).check(req).await?;
 
-    let mut triggers_to_tx = Vec::new();
-    let mut tasks_to_tx = Vec::new();
+    let principal = auth::principal_name(req);
 
-    // insert the triggers
-    for trigger in &job.triggers {
-        let id = triggers::create_trigger(&mut txn, &job, trigger).await?;
-        triggers_to_tx.push(id);
-    }
-
-    for task in &job.tasks {
-        let id = tasks::create_task(&mut txn, task, &job).await?;
-        tasks_to_tx.push(id);
-    }
+    let mut txn = pool.begin().await?;
 
-    for task in &job.tasks {
-        tasks::create_task_edges(&mut txn, task, &job).await?;
-    }
+    let mut upsert = upsert::upsert_job(&mut txn, job, project_id, principal.as_deref()).await?;
+    template::set_job_template(&mut txn, upsert.job_id, link).await?;
+    upsert::upsert_job_edges(&mut txn, job, &mut upsert).await?;
+    cycles::check_cycles(&mut txn, &[upsert.job_id]).await?;
 
     txn.commit().await?;
 
-    updates::send_trigger_update(req.get_channel(), TriggerUpdate(triggers_to_tx)).await?;
+    let diff = upsert::notify_job_upsert(req.get_channel(), upsert).await?;
 
-    for id in tasks_to_tx {
-        config_cache::send(req.get_channel(), ConfigUpdate::TaskDef(id)).await?;
-    }
-
-    StatusCode::CREATED.into_response()
+    Response::status(StatusCode::CREATED).json(diff)
 }
 
 #[derive(Deserialize)]
@@ -157,6 +129,7 @@ struct GetJob {
     pub name: String,
     pub description: String,
     pub paused: bool,
+    pub archived_datetime: Option<DateTime<Utc>>,
 }
 
 pub async fn get_by_name(req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -168,7 +141,8 @@ pub async fn get_by_name(req: Request<State>) -> highnoon::Result<impl Responder
             j.name AS name,
             j.project_id AS project_id,
             j.description AS description,
-            j.paused AS paused
+            j.paused AS paused,
+            j.archived_datetime AS archived_datetime
         FROM job j
         JOIN project p ON j.project_id = p.id
         WHERE j.name = $1
@@ -195,6 +169,7 @@ struct GetJobExtra {
     pub name: String,
     pub description: String,
     pub paused: bool,
+    pub archived_datetime: Option<DateTime<Utc>>,
     pub raw_definition: String,
     pub active_tasks: i64,
     pub waiting_tasks: i64,
@@ -224,6 +199,7 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<impl Responder>
             p.id AS project_id,
             j.description AS description,
             j.paused AS paused,
+            j.archived_datetime AS archived_datetime,
             j.raw_definition AS raw_definition,
             (
                 SELECT COUNT(1)
@@ -266,38 +242,6 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<impl Responder>
     }
 }
 
-pub async fn delete(req: Request<State>) -> highnoon::Result<StatusCode> {
-    let id = req.param("id")?.parse::<Uuid>()?;
-
-    auth::delete().job(id, None).check(&req).await?;
-
-    // TODO - this breaks because of foreign key constraints
-    // should we even allow deleting a job?
-    let res = sqlx::query(
-        "DELETE FROM job
-        WHERE id = $1",
-    )
-    .bind(id)
-    .execute(&req.get_pool())
-    .await;
-
-    match pg_error(res)? {
-        Ok(done) => {
-            if done.rows_affected() == 1 {
-                info!("deleted job {}", id);
-                Ok(StatusCode::NO_CONTENT)
-            } else {
-                info!("no job with id {}", id);
-                Ok(StatusCode::NOT_FOUND)
-            }
-        }
-        Err(err) => {
-            warn!("error deleting job: {}", err);
-            Ok(StatusCode::INTERNAL_SERVER_ERROR)
-        }
-    }
-}
-
 pub async fn get_paused(req: Request<State>) -> highnoon::Result<impl Responder> {
     let id = req.param("id")?.parse::<Uuid>()?;
 
@@ -334,7 +278,8 @@ pub async fn set_paused(mut req: Request<State>) -> impl Responder {
     let row = sqlx::query(
         "UPDATE job
         SET paused = $2
-        WHERE id = $1",
+        WHERE id = $1
+        AND archived_datetime IS NULL",
     )
     .bind(job_id)
     .bind(paused)
@@ -0,0 +1,205 @@
+use crate::server::{
+    api::{
+        State, auth,
+        job::{
+            cycles::check_cycles,
+            delete::{archive_job, get_job_members, notify_job_removed},
+            template::{declared_job, set_job_template},
+            upsert::{JobDiff, notify_job_upsert, upsert_job, upsert_job_edges},
+            validate::{Problem, check_jobs},
+        },
+        request_ext::RequestExt,
+        types::Job,
+    },
+    body_parser::read_list_from_body,
+};
+use highnoon::{Request, Responder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct ApplyQuery {
+    #[serde(default)]
+    prune: bool,
+}
+
+#[derive(Serialize)]
+struct JobProblems {
+    job: String,
+    problems: Vec<Problem>,
+}
+
+#[derive(Serialize)]
+struct AppliedJob {
+    job_id: Uuid,
+    name: String,
+    #[serde(flatten)]
+    diff: JobDiff,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct PrunedJob {
+    job_id: Uuid,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Applied {
+    jobs: Vec<AppliedJob>,
+    pruned: Vec<PrunedJob>,
+}
+
+/// Create or update every job in a project at once.
+///
+/// The jobs are validated together, so they can refer to each other before they exist, and
+/// applied in a single transaction. With `?prune=true` any other jobs in the project are
+/// archived.
+pub async fn apply(mut req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let ApplyQuery { prune } = req.query()?;
+    let values: Vec<JsonValue> = read_list_from_body(&mut req).await?;
+
+    let pool = req.get_pool();
+
+    let mut jobs: Vec<Job> = Vec::new();
+    let mut links = Vec::new();
+    for value in values {
+        let (job, link) = declared_job(&pool, value).await?;
+        jobs.push(job);
+        links.push(link);
+    }
+
+    let row: Option<(String,)> = sqlx::query_as("SELECT name FROM project WHERE id = $1")
+        .bind(project_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    let Some((project,)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if prune && jobs.is_empty() {
+        return Err(highnoon::Error::bad_request(
+            "refusing to prune every job in the project, no jobs were given",
+        ));
+    }
+
+    for job in &jobs {
+        auth::update().job(job.uuid, project_id).check(&req).await?;
+    }
+
+    let checked = check_jobs(&pool, &jobs).await?;
+
+    let failed: Vec<JobProblems> = jobs
+        .iter()
+        .zip(checked.problems)
+        .filter_map(|(job, mut problems)| {
+            if job.project != project {
+                problems.push(Problem::new(
+                    "/project",
+                    format!("job is in project '{}', not '{project}'", job.project),
+                ));
+            }
+
+            // jobs in this project which aren't being applied are about to be archived
+            if prune {
+                let to = format!("{}/{}/", job.project, job.name);
+                let from = format!("{project}/");
+
+                for edge in &checked.edges {
+                    if edge.to.starts_with(&to)
+                        && edge.from.starts_with(&from)
+                        && checked.external.contains_key(&edge.from)
+                    {
+                        problems.push(Problem::new(
+                            "/tasks",
+                            format!("{} depends on {} which would be pruned", edge.to, edge.from),
+                        ));
+                    }
+                }
+            }
+
+            (!problems.is_empty()).then(|| JobProblems {
+                job: job.name.clone(),
+                problems,
+            })
+        })
+        .collect();
+
+    if !failed.is_empty() {
+        return Response::status(StatusCode::BAD_REQUEST).json(failed);
+    }
+
+    let principal = auth::principal_name(&req);
+
+    let mut txn = pool.begin().await?;
+
+    // create all the jobs before any edges, so the jobs can depend on each other
+    let mut upserts = Vec::new();
+    for (job, link) in jobs.iter().zip(&links) {
+        let upsert = upsert_job(&mut txn, job, project_id, principal.as_deref()).await?;
+        set_job_template(&mut txn, upsert.job_id, link.as_ref()).await?;
+        upserts.push(upsert);
+    }
+
+    for (job, upsert) in jobs.iter().zip(upserts.iter_mut()) {
+        upsert_job_edges(&mut txn, job, upsert).await?;
+    }
+
+    let job_ids: Vec<Uuid> = jobs.iter().map(|job| job.uuid).collect();
+    check_cycles(&mut txn, &job_ids).await?;
+
+    let mut pruned: Vec<PrunedJob> = Vec::new();
+    let mut pruned_members = Vec::new();
+
+    if prune {
+        pruned = sqlx::query_as(
+            "SELECT
+                id AS job_id,
+                name
+            FROM job
+            WHERE project_id = $1
+            AND archived_datetime IS NULL
+            AND NOT id = ANY($2)
+            ORDER BY name
+            FOR UPDATE",
+        )
+        .bind(project_id)
+        .bind(&job_ids)
+        .fetch_all(txn.as_mut())
+        .await?;
+
+        for job in &pruned {
+            auth::delete()
+                .job(job.job_id, project_id)
+                .check(&req)
+                .await?;
+
+            pruned_members.push(get_job_members(&mut txn, job.job_id).await?);
+            archive_job(&mut txn, job.job_id).await?;
+            info!("pruned job {} -> {}", job.name, job.job_id);
+        }
+    }
+
+    txn.commit().await?;
+
+    let mut applied = Vec::new();
+    for (job, upsert) in jobs.iter().zip(upserts) {
+        applied.push(AppliedJob {
+            job_id: job.uuid,
+            name: job.name.clone(),
+            diff: notify_job_upsert(req.get_channel(), upsert).await?,
+        });
+    }
+
+    for members in pruned_members {
+        notify_job_removed(req.get_channel(), members).await?;
+    }
+
+    Response::ok().json(Applied {
+        jobs: applied,
+        pruned,
+    })
+}
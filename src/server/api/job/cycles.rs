@@ -0,0 +1,129 @@
+use sqlx::{Postgres, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+/// Find a cycle in a directed graph given as a list of edges.
+///
+/// Returns the nodes of the first cycle found, starting and ending with the same node
+/// (eg. `[a, b, c, a]`), or `None` if the graph is acyclic.
+pub fn find_cycle<N: Ord + Clone>(edges: &[(N, N)]) -> Option<Vec<N>> {
+    let mut children: BTreeMap<&N, Vec<&N>> = BTreeMap::new();
+    for (from, to) in edges {
+        children.entry(from).or_default().push(to);
+    }
+
+    let mut done = BTreeSet::new();
+
+    for start in children.keys() {
+        if done.contains(start) {
+            continue;
+        }
+
+        // iterative depth first search, `path` is the chain of nodes currently being visited
+        // and `stack` holds the index of the next child to visit for each of them
+        let mut path: Vec<&N> = vec![start];
+        let mut stack: Vec<usize> = vec![0];
+
+        while let Some(node) = path.last().copied() {
+            let idx = stack
+                .last_mut()
+                .expect("stack and path have the same length");
+            let next = children.get(node).and_then(|c| c.get(*idx)).copied();
+            *idx += 1;
+
+            match next {
+                Some(child) if done.contains(child) => {}
+                Some(child) => {
+                    if let Some(pos) = path.iter().position(|n| *n == child) {
+                        let mut cycle: Vec<N> = path[pos..].iter().map(|n| (*n).clone()).collect();
+                        cycle.push(child.clone());
+                        return Some(cycle);
+                    }
+                    path.push(child);
+                    stack.push(0);
+                }
+                None => {
+                    done.insert(node);
+                    path.pop();
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Check the edges stored for the jobs don't form a cycle, following edges through any other
+/// jobs. Edges from `depends_cyclic` are allowed to close a cycle since they point at an earlier
+/// run. Call this once every job changed in the transaction has its edges.
+pub async fn check_cycles(
+    txn: &mut Transaction<'_, Postgres>,
+    job_ids: &[Uuid],
+) -> highnoon::Result<()> {
+    let edges: Vec<(String, String)> = sqlx::query_as(
+        "WITH RECURSIVE downstream(parent_task_id, child_task_id) AS (
+            SELECT e.parent_task_id, e.child_task_id
+            FROM task_edge e
+            JOIN task pt ON pt.id = e.parent_task_id
+            WHERE pt.job_id = ANY($1)
+            AND NOT e.cyclic
+            UNION
+            SELECT e.parent_task_id, e.child_task_id
+            FROM task_edge e
+            JOIN downstream d ON d.child_task_id = e.parent_task_id
+            WHERE NOT e.cyclic
+        )
+        SELECT
+            pp.name || '/' || pj.name || '/task/' || pt.name,
+            cp.name || '/' || cj.name || '/task/' || ct.name
+        FROM downstream d
+        JOIN task pt ON pt.id = d.parent_task_id
+        JOIN job pj ON pj.id = pt.job_id
+        JOIN project pp ON pp.id = pj.project_id
+        JOIN task ct ON ct.id = d.child_task_id
+        JOIN job cj ON cj.id = ct.job_id
+        JOIN project cp ON cp.id = cj.project_id",
+    )
+    .bind(job_ids)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    match find_cycle(&edges) {
+        Some(cycle) => Err(highnoon::Error::bad_request(format!(
+            "dependency cycle: {} (use depends_cyclic with an offset if this is intended)",
+            cycle.join(" -> ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::find_cycle;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_acyclic() {
+        assert_eq!(find_cycle::<&str>(&[]), None);
+        assert_eq!(find_cycle(&[("a", "b"), ("b", "c"), ("a", "c")]), None);
+        // diamond
+        assert_eq!(
+            find_cycle(&[("a", "b"), ("a", "c"), ("b", "d"), ("c", "d")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert_eq!(find_cycle(&[("a", "a")]), Some(vec!["a", "a"]));
+        assert_eq!(
+            find_cycle(&[("a", "b"), ("b", "a")]),
+            Some(vec!["a", "b", "a"])
+        );
+        assert_eq!(
+            find_cycle(&[("x", "a"), ("a", "b"), ("b", "c"), ("c", "a")]),
+            Some(vec!["a", "b", "c", "a"])
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use crate::server::api::types::{DockerDefaults, Job, Retry, Task, TaskDefaults};
+use serde_json::Value as JsonValue;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+/// Key in the project config holding the project's task defaults
+pub const PROJECT_DEFAULTS_KEY: &str = "task_defaults";
+
+pub fn parse_project_defaults(config: Option<&JsonValue>) -> serde_json::Result<TaskDefaults> {
+    match config.and_then(|config| config.get(PROJECT_DEFAULTS_KEY)) {
+        Some(defaults) => serde_json::from_value(defaults.clone()),
+        None => Ok(TaskDefaults::default()),
+    }
+}
+
+pub async fn get_project_config<'e>(
+    executor: impl PgExecutor<'e>,
+    project_id: Uuid,
+) -> highnoon::Result<Option<JsonValue>> {
+    let row: Option<(Option<JsonValue>,)> = sqlx::query_as(
+        "SELECT config
+        FROM project
+        WHERE id = $1",
+    )
+    .bind(project_id)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.and_then(|(config,)| config))
+}
+
+/// Get the defaults for the job's tasks - the job's own defaults over the project's
+pub async fn get_job_defaults<'e>(
+    executor: impl PgExecutor<'e>,
+    job: &Job,
+    project_id: Uuid,
+) -> highnoon::Result<TaskDefaults> {
+    let config = get_project_config(executor, project_id).await?;
+    let project = parse_project_defaults(config.as_ref()).map_err(|err| {
+        highnoon::Error::bad_request(format!(
+            "invalid {PROJECT_DEFAULTS_KEY} in project config: {err}"
+        ))
+    })?;
+
+    Ok(layer(job.defaults.as_ref(), project))
+}
+
+/// Layer `over` on top of `base`, values set in `over` take precedence
+pub fn layer(over: Option<&TaskDefaults>, base: TaskDefaults) -> TaskDefaults {
+    let Some(over) = over else {
+        return base;
+    };
+
+    let docker = match (&over.docker, base.docker) {
+        (Some(o), Some(b)) => Some(DockerDefaults {
+            image: o.image.clone().or(b.image),
+            env: match (&o.env, b.env) {
+                (Some(o), Some(b)) => Some(merge_env(&b, o)),
+                (o, b) => o.clone().or(b),
+            },
+        }),
+        (o, b) => o.clone().or(b),
+    };
+
+    TaskDefaults {
+        docker,
+        retry: over.retry.clone().or(base.retry),
+        timeout: over.timeout.clone().or(base.timeout),
+    }
+}
+
+/// Merge two lists of `NAME=value` variables, variables in `over` replace those with the same
+/// name in `base`
+fn merge_env(base: &[String], over: &[String]) -> Vec<String> {
+    fn name(var: &str) -> &str {
+        var.split_once('=').map_or(var, |(name, _)| name)
+    }
+
+    let mut merged: Vec<String> = base.to_vec();
+    for var in over {
+        match merged.iter_mut().find(|m| name(m) == name(var)) {
+            Some(existing) => existing.clone_from(var),
+            None => merged.push(var.clone()),
+        }
+    }
+    merged
+}
+
+/// A task's settings after the defaults have been applied
+pub struct ResolvedTask<'a> {
+    pub image: Option<String>,
+    pub args: Option<&'a Vec<String>>,
+    pub env: Option<Vec<String>>,
+    pub retry: Option<&'a Retry>,
+    pub timeout: Option<&'a str>,
+}
+
+/// Apply the defaults to a task. Docker defaults are only used for tasks with a `docker`
+/// section, tasks without one don't run anything.
+pub fn resolve_task<'a>(
+    task: &'a Task,
+    defaults: &'a TaskDefaults,
+) -> Result<ResolvedTask<'a>, String> {
+    let mut resolved = ResolvedTask {
+        image: None,
+        args: None,
+        env: None,
+        retry: task.retry.as_ref().or(defaults.retry.as_ref()),
+        timeout: task.timeout.as_deref().or(defaults.timeout.as_deref()),
+    };
+
+    if let Some(docker) = &task.docker {
+        let docker_defaults = defaults.docker.as_ref();
+
+        resolved.image = docker
+            .image
+            .clone()
+            .or_else(|| docker_defaults.and_then(|d| d.image.clone()));
+
+        if resolved.image.is_none() {
+            return Err(format!(
+                "task '{}' has no docker image and there is no default image",
+                task.name
+            ));
+        }
+
+        resolved.args = Some(&docker.args);
+        resolved.env = match (docker_defaults.and_then(|d| d.env.as_ref()), &docker.env) {
+            (Some(base), Some(env)) => Some(merge_env(base, env)),
+            (base, env) => env.clone().or_else(|| base.cloned()),
+        };
+    }
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn defaults(value: JsonValue) -> TaskDefaults {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_merge_env() {
+        let base = vec!["A=1".to_owned(), "B=2".to_owned()];
+        let over = vec!["B=3".to_owned(), "C=4".to_owned()];
+        assert_eq!(merge_env(&base, &over), vec!["A=1", "B=3", "C=4"]);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let project = defaults(serde_json::json!({
+            "docker": {"image": "project:1", "env": ["A=project", "B=project"]},
+            "retry": {"max_attempts": 3},
+            "timeout": "1h",
+        }));
+        let job = defaults(serde_json::json!({
+            "docker": {"env": ["B=job"]},
+            "timeout": "30m",
+        }));
+        let merged = layer(Some(&job), project);
+
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "a",
+            "docker": {"args": [], "env": ["A=task"]},
+            "timeout": "5m",
+        }))
+        .unwrap();
+
+        let resolved = resolve_task(&task, &merged).unwrap();
+        assert_eq!(resolved.image.as_deref(), Some("project:1"));
+        assert_eq!(resolved.env.unwrap(), vec!["A=task", "B=job"]);
+        assert_eq!(resolved.retry.map(|r| r.max_attempts), Some(3));
+        assert_eq!(resolved.timeout, Some("5m"));
+
+        // tasks without docker don't get an image
+        let task: Task = serde_json::from_value(serde_json::json!({"name": "b"})).unwrap();
+        let resolved = resolve_task(&task, &merged).unwrap();
+        assert_eq!(resolved.image, None);
+        assert_eq!(resolved.timeout, Some("30m"));
+
+        // docker with no image anywhere
+        let task: Task =
+            serde_json::from_value(serde_json::json!({"name": "c", "docker": {"args": []}}))
+                .unwrap();
+        assert!(resolve_task(&task, &TaskDefaults::default()).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{
+    messages::{ConfigUpdate, TokenState, TriggerUpdate},
+    server::api::{State, auth, config_cache, job::tasks, request_ext::RequestExt, updates},
+    util::first,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Request, StatusCode};
+use lapin::Channel;
+use serde::Deserialize;
+use sqlx::{Postgres, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    purge: bool,
+}
+
+/// Delete a job.
+///
+/// By default the job is archived - it is paused, hidden from the job list, and cut off from
+/// other jobs, but its task runs are kept. With `?purge=true` the job and all of its history
+/// are removed from the database.
+pub async fn delete(req: Request<State>) -> highnoon::Result<StatusCode> {
+    let id = req.param("id")?.parse::<Uuid>()?;
+    let DeleteQuery { purge } = req.query()?;
+
+    auth::delete().job(id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+    let mut txn = pool.begin().await?;
+
+    let row: Option<(Option<DateTime<Utc>>,)> = sqlx::query_as(
+        "SELECT archived_datetime
+        FROM job
+        WHERE id = $1
+        FOR UPDATE",
+    )
+    .bind(id)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    let Some((archived_datetime,)) = row else {
+        info!("no job with id {}", id);
+        return Ok(StatusCode::NOT_FOUND);
+    };
+
+    let members = get_job_members(&mut txn, id).await?;
+
+    if purge {
+        if has_running_tasks(&mut txn, id).await? {
+            return Err(highnoon::Error::http((
+                StatusCode::CONFLICT,
+                "job has tasks queued or running, archive it and wait for them to stop first",
+            )));
+        }
+
+        purge_job(&mut txn, id).await?;
+        info!("purged job {}", id);
+    } else if archived_datetime.is_none() {
+        archive_job(&mut txn, id).await?;
+        info!("archived job {}", id);
+    } else {
+        info!("job {} is already archived", id);
+    }
+
+    txn.commit().await?;
+
+    notify_job_removed(req.get_channel(), members).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The triggers and tasks of a job
+pub struct JobMembers {
+    triggers: Vec<Uuid>,
+    tasks: Vec<Uuid>,
+}
+
+pub async fn get_job_members(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<JobMembers> {
+    let triggers: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM trigger
+        WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let tasks: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM task
+        WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    Ok(JobMembers {
+        triggers: triggers.into_iter().map(first).collect(),
+        tasks: tasks.into_iter().map(first).collect(),
+    })
+}
+
+/// Tell the scheduler and workers about an archived or purged job, once it has been committed
+pub async fn notify_job_removed(chan: &Channel, members: JobMembers) -> anyhow::Result<()> {
+    // paused and deleted triggers are both removed from the scheduler's queue
+    updates::send_trigger_update(chan, TriggerUpdate(members.triggers)).await?;
+
+    for task_id in members.tasks {
+        config_cache::send(chan, ConfigUpdate::TaskDef(task_id)).await?;
+    }
+
+    Ok(())
+}
+
+async fn has_running_tasks(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<bool> {
+    let (running,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS(
+            SELECT 1
+            FROM task_run tr
+            JOIN task t ON t.id = tr.task_id
+            WHERE t.job_id = $1
+            AND tr.state IN ($2, $3)
+        )",
+    )
+    .bind(job_id)
+    .bind(TokenState::Active)
+    .bind(TokenState::Running)
+    .fetch_one(txn.as_mut())
+    .await?;
+
+    Ok(running)
+}
+
+/// Pause the job, cancel its pending retries and remove any edges to or from other jobs
+/// (including pending ones). Tasks in other jobs depending on it are relinked, leaving pending
+/// edges which are linked again if the job is restored.
+/// Edges within the job are kept so the graph of an archived job can still be shown.
+pub async fn archive_job(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<()> {
+    let dependents = get_dependents(txn, job_id).await?;
+
+    sqlx::query(
+        "UPDATE job
+        SET archived_datetime = CURRENT_TIMESTAMP,
+            paused = TRUE
+        WHERE id = $1",
+    )
+    .bind(job_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM retry r
+        USING task_run tr, task t
+        WHERE tr.id = r.task_run_id
+        AND t.id = tr.task_id
+        AND t.job_id = $1",
+    )
+    .bind(job_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM task_edge e
+        USING task pt, task ct
+        WHERE pt.id = e.parent_task_id
+        AND ct.id = e.child_task_id
+        AND (pt.job_id = $1 OR ct.job_id = $1)
+        AND pt.job_id <> ct.job_id",
+    )
+    .bind(job_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM trigger_edge e
+        USING trigger g, task t
+        WHERE g.id = e.trigger_id
+        AND t.id = e.task_id
+        AND (g.job_id = $1 OR t.job_id = $1)
+        AND g.job_id <> t.job_id",
+    )
+    .bind(job_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM pending_edge e
+        USING task t
+        WHERE t.id = e.task_id
+        AND t.job_id = $1",
+    )
+    .bind(job_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    tasks::relink_tasks(txn, &dependents).await?;
+
+    Ok(())
+}
+
+/// Get the tasks in other jobs which depend on the job's triggers or tasks
+async fn get_dependents(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<Vec<Uuid>> {
+    let members = get_job_members(txn, job_id).await?;
+    tasks::get_dependents(txn, &members.tasks, &members.triggers).await
+}
+
+/// Delete the job and everything belonging to it, in foreign key order. Tasks in other jobs
+/// depending on it are relinked, leaving pending edges in case the job is created again.
+async fn purge_job(txn: &mut Transaction<'_, Postgres>, job_id: Uuid) -> highnoon::Result<()> {
+    let dependents = get_dependents(txn, job_id).await?;
+
+    let statements = [
+        "DELETE FROM retry r
+        USING task_run tr, task t
+        WHERE tr.id = r.task_run_id
+        AND t.id = tr.task_id
+        AND t.job_id = $1",
+        "DELETE FROM task_run tr
+        USING task t
+        WHERE t.id = tr.task_id
+        AND t.job_id = $1",
+        "DELETE FROM token k
+        USING task t
+        WHERE t.id = k.task_id
+        AND t.job_id = $1",
+        "DELETE FROM job_stash
+        WHERE job_id = $1",
+        "DELETE FROM job_version
+        WHERE job_id = $1",
+        "DELETE FROM trigger_edge e
+        WHERE e.trigger_id IN (SELECT id FROM trigger WHERE job_id = $1)
+        OR e.task_id IN (SELECT id FROM task WHERE job_id = $1)",
+        "DELETE FROM task_edge e
+        WHERE e.parent_task_id IN (SELECT id FROM task WHERE job_id = $1)
+        OR e.child_task_id IN (SELECT id FROM task WHERE job_id = $1)",
+        "DELETE FROM dynamic_reference d
+        WHERE d.job_id = $1
+        OR d.task_id IN (SELECT id FROM task WHERE job_id = $1)",
+        "DELETE FROM pending_edge e
+        WHERE e.task_id IN (SELECT id FROM task WHERE job_id = $1)",
+        "DELETE FROM trigger_skip s
+        WHERE s.trigger_id IN (SELECT id FROM trigger WHERE job_id = $1)",
+        "DELETE FROM trigger_event e
+        WHERE e.trigger_id IN (SELECT id FROM trigger WHERE job_id = $1)",
+        "UPDATE scheduler
+        SET waiting_for_trigger_id = NULL
+        WHERE waiting_for_trigger_id IN (SELECT id FROM trigger WHERE job_id = $1)",
+        "DELETE FROM trigger
+        WHERE job_id = $1",
+        "DELETE FROM task
+        WHERE job_id = $1",
+        "DELETE FROM job
+        WHERE id = $1",
+    ];
+
+    for sql in statements {
+        sqlx::query(sql).bind(job_id).execute(txn.as_mut()).await?;
+    }
+
+    tasks::relink_tasks(txn, &dependents).await?;
+
+    Ok(())
+}
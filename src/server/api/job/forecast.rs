@@ -0,0 +1,363 @@
+use crate::server::{
+    api::{
+        State, auth,
+        job::{triggers::check_schedule, validate::get_project},
+        request_ext::RequestExt,
+        types::{Trigger, TriggerKind},
+    },
+    body_parser::read_from_body,
+    trigger_time::TriggerTime,
+    triggers::Trigger as ScheduledTrigger,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 1000;
+const DEFAULT_WINDOW_DAYS: i64 = 7;
+const MAX_WINDOW_DAYS: i64 = 62;
+
+/// Skipped trigger datetimes and the calendars which apply to a trigger
+#[derive(Default)]
+struct Skips {
+    explicit: HashMap<DateTime<Utc>, (bool, String)>,
+    calendars: Vec<(String, Vec<NaiveDate>)>,
+}
+
+impl Skips {
+    async fn for_trigger(pool: &PgPool, trigger_id: Uuid) -> highnoon::Result<Skips> {
+        let explicit: Vec<(DateTime<Utc>, bool, String)> = sqlx::query_as(
+            "SELECT
+                trigger_datetime,
+                skipped,
+                reason
+            FROM trigger_skip
+            WHERE trigger_id = $1",
+        )
+        .bind(trigger_id)
+        .fetch_all(pool)
+        .await?;
+
+        let calendars = sqlx::query_as(
+            "SELECT
+                c.name,
+                c.dates
+            FROM trigger g
+            JOIN job j ON j.id = g.job_id
+            JOIN calendar c ON c.project_id = j.project_id
+                AND c.name = ANY(g.calendars)
+            WHERE g.id = $1
+            ORDER BY c.name",
+        )
+        .bind(trigger_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Skips {
+            explicit: explicit
+                .into_iter()
+                .map(|(datetime, skipped, reason)| (datetime, (skipped, reason)))
+                .collect(),
+            calendars,
+        })
+    }
+
+    async fn for_calendars(
+        pool: &PgPool,
+        project: &str,
+        names: &[String],
+    ) -> highnoon::Result<Skips> {
+        let calendars = sqlx::query_as(
+            "SELECT
+                c.name,
+                c.dates
+            FROM calendar c
+            JOIN project p ON p.id = c.project_id
+            WHERE p.name = $1
+            AND c.name = ANY($2)
+            ORDER BY c.name",
+        )
+        .bind(project)
+        .bind(names)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Skips {
+            explicit: HashMap::new(),
+            calendars,
+        })
+    }
+
+    /// Why the trigger datetime will be skipped, or None if it will run. This matches the
+    /// scheduler: a recorded skip (or un-skip) wins over the calendars.
+    fn reason(&self, trigger_datetime: DateTime<Utc>) -> Option<String> {
+        if let Some((skipped, reason)) = self.explicit.get(&trigger_datetime) {
+            return skipped.then(|| reason.clone());
+        }
+
+        let date = trigger_datetime.date_naive();
+        self.calendars
+            .iter()
+            .find(|(_, dates)| dates.contains(&date))
+            .map(|(name, _)| format!("calendar {name}"))
+    }
+}
+
+#[derive(Serialize)]
+struct ForecastTime {
+    trigger_datetime: DateTime<Utc>,
+    scheduled_datetime: DateTime<Utc>,
+    skipped: bool,
+    skip_reason: Option<String>,
+}
+
+impl ForecastTime {
+    fn new(time: TriggerTime, skips: &Skips) -> ForecastTime {
+        let skip_reason = skips.reason(time.trigger_datetime);
+        ForecastTime {
+            trigger_datetime: time.trigger_datetime,
+            scheduled_datetime: time.scheduled_datetime,
+            skipped: skip_reason.is_some(),
+            skip_reason,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ForecastQuery {
+    after: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+fn check_limit(limit: Option<usize>) -> Result<usize, String> {
+    match limit.unwrap_or(DEFAULT_LIMIT) {
+        limit @ 1..=MAX_LIMIT => Ok(limit),
+        _ => Err(format!("limit must be between 1 and {MAX_LIMIT}")),
+    }
+}
+
+/// The next times a saved trigger will fire
+pub async fn get_trigger_forecast(req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let query: ForecastQuery = req.query()?;
+    let limit = check_limit(query.limit).map_err(highnoon::Error::bad_request)?;
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid, TriggerKind)> = sqlx::query_as(
+        "SELECT job_id, kind
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id, kind)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::get()
+        .job(job_id, None)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    if kind != TriggerKind::Time {
+        return Err(highnoon::Error::bad_request(
+            "only time triggers have a schedule to forecast",
+        ));
+    }
+
+    let trigger: ScheduledTrigger = sqlx::query_as(
+        "SELECT
+            id,
+            start_datetime,
+            end_datetime,
+            earliest_trigger_datetime,
+            latest_trigger_datetime,
+            period,
+            cron,
+            trigger_offset,
+            catchup,
+            catchup_max_runs,
+            catchup_max_age
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_one(&pool)
+    .await?;
+
+    let skips = Skips::for_trigger(&pool, trigger_id).await?;
+
+    let times: Vec<ForecastTime> = trigger
+        .forecast(query.after.unwrap_or_else(Utc::now), None, limit)?
+        .into_iter()
+        .map(|time| ForecastTime::new(time, &skips))
+        .collect();
+
+    Json(times).into_response()
+}
+
+#[derive(Deserialize)]
+struct ForecastDefinition {
+    /// project to look the trigger's calendars up in
+    project: Option<String>,
+    trigger: Trigger,
+    after: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+/// The next times a trigger definition would fire, without saving it
+pub async fn forecast_trigger(mut req: Request<State>) -> highnoon::Result<Response> {
+    let body: ForecastDefinition = read_from_body(&mut req).await?;
+    let limit = check_limit(body.limit).map_err(highnoon::Error::bad_request)?;
+
+    if let Err(err) = check_schedule(&body.trigger) {
+        return Err(highnoon::Error::bad_request(err.to_string()));
+    }
+
+    let trigger = ScheduledTrigger::from_definition(Uuid::nil(), &body.trigger)
+        .map_err(|err| highnoon::Error::bad_request(err.to_string()))?;
+
+    let pool = req.get_pool();
+
+    let skips = match (&body.project, &body.trigger.calendars) {
+        (Some(project), Some(names)) => {
+            let Some(project_id) = get_project(&pool, project).await? else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+
+            auth::list()
+                .project(project_id)
+                .kind("calendar")
+                .check(&req)
+                .await?;
+
+            Skips::for_calendars(&pool, project, names).await?
+        }
+        _ => Skips::default(),
+    };
+
+    let times: Vec<ForecastTime> = trigger
+        .forecast(body.after.unwrap_or_else(Utc::now), None, limit)
+        .map_err(|err| highnoon::Error::bad_request(err.to_string()))?
+        .into_iter()
+        .map(|time| ForecastTime::new(time, &skips))
+        .collect();
+
+    Json(times).into_response()
+}
+
+#[derive(Deserialize)]
+struct ProjectForecastQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ProjectTrigger {
+    job_id: Uuid,
+    job_name: String,
+    paused: bool,
+    trigger_name: String,
+    #[sqlx(flatten)]
+    trigger: ScheduledTrigger,
+}
+
+#[derive(Serialize)]
+struct ProjectForecastTime {
+    job_id: Uuid,
+    job_name: String,
+    paused: bool,
+    trigger_id: Uuid,
+    trigger_name: String,
+    #[serde(flatten)]
+    time: ForecastTime,
+}
+
+/// Every trigger time in the project scheduled within a window (the next week by default),
+/// across all the project's jobs, in the order they will fire
+pub async fn get_project_forecast(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id = req.param("id")?.parse::<Uuid>()?;
+    let query: ProjectForecastQuery = req.query()?;
+
+    let from = query.from.unwrap_or_else(Utc::now);
+    let to = query
+        .to
+        .unwrap_or_else(|| from + Duration::days(DEFAULT_WINDOW_DAYS));
+
+    if to <= from || to - from > Duration::days(MAX_WINDOW_DAYS) {
+        return Err(highnoon::Error::bad_request(format!(
+            "'to' must be after 'from' and at most {MAX_WINDOW_DAYS} days later"
+        )));
+    }
+
+    auth::list().project(project_id).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let triggers: Vec<ProjectTrigger> = sqlx::query_as(
+        "SELECT
+            j.id AS job_id,
+            j.name AS job_name,
+            j.paused AS paused,
+            g.name AS trigger_name,
+            g.id,
+            g.start_datetime,
+            g.end_datetime,
+            g.earliest_trigger_datetime,
+            g.latest_trigger_datetime,
+            g.period,
+            g.cron,
+            g.trigger_offset,
+            g.catchup,
+            g.catchup_max_runs,
+            g.catchup_max_age
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        WHERE j.project_id = $1
+        AND j.archived_datetime IS NULL
+        AND g.kind = 'time'",
+    )
+    .bind(project_id)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut times = Vec::new();
+
+    for trigger in triggers {
+        let forecast = trigger.trigger.forecast(from, Some(to), MAX_LIMIT)?;
+        if forecast.is_empty() {
+            continue;
+        }
+
+        let skips = Skips::for_trigger(&pool, forecast[0].trigger_id).await?;
+
+        for time in forecast {
+            times.push(ProjectForecastTime {
+                job_id: trigger.job_id,
+                job_name: trigger.job_name.clone(),
+                paused: trigger.paused,
+                trigger_id: time.trigger_id,
+                trigger_name: trigger.trigger_name.clone(),
+                time: ForecastTime::new(time, &skips),
+            });
+        }
+    }
+
+    times.sort_by(|a, b| {
+        (a.time.scheduled_datetime, &a.job_name, &a.trigger_name).cmp(&(
+            b.time.scheduled_datetime,
+            &b.job_name,
+            &b.trigger_name,
+        ))
+    });
+
+    Json(times).into_response()
+}
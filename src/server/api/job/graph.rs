@@ -18,12 +18,23 @@ struct Edge {
     from: Uuid,
     to: Uuid,
     kind: String,
+    /// offsets in seconds of all the edges between the two nodes, an offset range has several
+    offsets: Vec<i64>,
+}
+
+/// A dependency on a node in a job which doesn't exist yet
+#[derive(Serialize, sqlx::FromRow)]
+struct PendingEdge {
+    to: Uuid,
+    reference: String,
+    kind: String,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
 struct Graph {
     nodes: Vec<Node>,
     edges: Vec<Edge>,
+    pending: Vec<PendingEdge>,
 }
 
 #[derive(Deserialize)]
@@ -52,6 +63,7 @@ pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder>
             ) AS state
         FROM task t
         WHERE t.job_id = $1
+        AND t.archived_datetime IS NULL
         UNION ALL
         SELECT
             g.id AS id,
@@ -68,28 +80,36 @@ pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder>
     .await?;
 
     let edges: Vec<Edge> = sqlx::query_as(
-        "SELECT DISTINCT
+        "SELECT
             te.parent_task_id AS \"from\",
             te.child_task_id AS to,
-            te.kind AS kind
+            te.kind AS kind,
+            array_agg(COALESCE(te.edge_offset, 0) ORDER BY COALESCE(te.edge_offset, 0)) AS offsets
         FROM task_edge te
-        JOIN task t ON (t.id = te.parent_task_id OR t.id = te.child_task_id)
-        WHERE t.job_id = $1
+        WHERE EXISTS (
+            SELECT 1
+            FROM task t
+            WHERE (t.id = te.parent_task_id OR t.id = te.child_task_id)
+            AND t.job_id = $1
+        )
+        GROUP BY te.parent_task_id, te.child_task_id, te.kind
         UNION ALL
         SELECT
             ge.trigger_id AS \"from\",
             ge.task_id AS to,
-            'trigger' AS kind
+            'trigger' AS kind,
+            array_agg(COALESCE(ge.edge_offset, 0) ORDER BY COALESCE(ge.edge_offset, 0)) AS offsets
         FROM trigger_edge ge
         JOIN task t ON t.id = ge.task_id
-        WHERE t.job_id = $1",
+        WHERE t.job_id = $1
+        GROUP BY ge.trigger_id, ge.task_id",
     )
     .bind(job_id)
     .fetch_all(&req.get_pool())
     .await?;
 
     let extra_nodes: Vec<Node> = sqlx::query_as(
-        "SELECT
+        "SELECT DISTINCT
             t.id AS id,
             'task' AS kind,
             t.name AS name,
@@ -105,7 +125,7 @@ pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder>
         JOIN task t2 ON t2.id = te.child_task_id
         WHERE t2.job_id = $1
         AND t.job_id != $1
-        UNION ALL
+        UNION
         SELECT
             g.id AS id,
             'trigger' AS kind,
@@ -125,5 +145,23 @@ pub async fn get_graph(req: Request<State>) -> highnoon::Result<impl Responder>
 
     nodes.extend(extra_nodes);
 
-    Ok(Json(Graph { nodes, edges }))
+    let pending: Vec<PendingEdge> = sqlx::query_as(
+        "SELECT
+            e.task_id AS to,
+            e.reference AS reference,
+            e.kind AS kind
+        FROM pending_edge e
+        JOIN task t ON t.id = e.task_id
+        WHERE t.job_id = $1
+        ORDER BY e.reference",
+    )
+    .bind(job_id)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Ok(Json(Graph {
+        nodes,
+        edges,
+        pending,
+    }))
 }
@@ -7,10 +7,12 @@ use std::{
     str::FromStr,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ReferenceKind {
     Trigger,
     Task,
+    /// every task in a job, except those which only run when another task fails
+    Job,
 }
 
 impl FromStr for ReferenceKind {
@@ -20,11 +22,12 @@ impl FromStr for ReferenceKind {
         match s {
             "trigger" => Ok(ReferenceKind::Trigger),
             "task" => Ok(ReferenceKind::Task),
+            "job" => Ok(ReferenceKind::Job),
             _ => Err(highnoon::Error::http((
                 highnoon::StatusCode::BAD_REQUEST,
                 format!(
-                    "failed to parse reference kind (expected \"task\" \
-                         or \"trigger\", got \"{s}\")"
+                    "failed to parse reference kind (expected \"task\", \"trigger\" \
+                         or \"job\", got \"{s}\")"
                 ),
             ))),
         }
@@ -36,17 +39,22 @@ impl Display for ReferenceKind {
         match self {
             ReferenceKind::Trigger => write!(f, "trigger"),
             ReferenceKind::Task => write!(f, "task"),
+            ReferenceKind::Job => write!(f, "job"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Reference {
     pub proj: Option<String>,
     pub job: Option<String>,
     pub kind: ReferenceKind,
     pub name: String,
+    pub branch: Option<String>,
     pub offset: Option<Duration>,
+    /// end of an offset range (inclusive), the reference is expanded into one edge per run of
+    /// the upstream node from `offset` to here
+    pub offset_end: Option<Duration>,
 }
 
 impl Display for Reference {
@@ -57,25 +65,158 @@ impl Display for Reference {
         if let Some(j) = &self.job {
             write!(f, "{j}/")?;
         }
-        write!(f, "{}/{}", self.kind, self.name)?;
+        match self.kind {
+            ReferenceKind::Job => write!(f, "job")?,
+            _ => write!(f, "{}/{}", self.kind, self.name)?,
+        }
+        if let Some(branch) = &self.branch {
+            write!(f, "#{branch}")?;
+        }
         if let Some(offset) = self.offset {
             let offset = offset
                 .to_std()
                 .expect("overflow converting chrono::Duration to std");
             write!(f, "@{}", humantime::format_duration(offset))?;
         }
+        if let Some(end) = self.offset_end {
+            let end = end
+                .to_std()
+                .expect("overflow converting chrono::Duration to std");
+            write!(f, "..{}", humantime::format_duration(end))?;
+        }
         Ok(())
     }
 }
 
+impl Reference {
+    /// Glob and job references can match a different set of nodes as the upstream job changes
+    pub fn is_dynamic(&self) -> bool {
+        self.kind == ReferenceKind::Job || is_glob(&self.name)
+    }
+
+    pub fn is_range(&self) -> bool {
+        self.offset_end.is_some()
+    }
+
+    /// The offsets of the edges an offset range expands into, `step` apart
+    pub fn range_offsets(&self, step: Duration) -> Result<Vec<Duration>, String> {
+        let start = self.offset.unwrap_or_else(Duration::zero);
+        let end = self.offset_end.unwrap_or(start);
+
+        if step <= Duration::zero() {
+            return Err(format!("offset range has no step: {self}"));
+        }
+
+        let count = (end - start).num_seconds() / step.num_seconds() + 1;
+        if count > MAX_RANGE_EDGES {
+            return Err(format!(
+                "offset range covers {count} runs, at most {MAX_RANGE_EDGES} are allowed: {self}"
+            ));
+        }
+
+        Ok((0..count as i32).map(|i| start + step * i).collect())
+    }
+
+    /// Check the reference can be used in `depends_cyclic`. It must point at tasks and have an
+    /// offset, so each run depends on an earlier one and the cycle moves forward in time.
+    pub fn check_cyclic(&self) -> Result<(), String> {
+        if self.kind == ReferenceKind::Trigger {
+            return Err(format!(
+                "depends_cyclic cannot reference a trigger since triggers can't be part of a \
+                cycle: {self}"
+            ));
+        }
+
+        if self.offset.is_none_or(|offset| offset <= Duration::zero()) {
+            return Err(format!(
+                "depends_cyclic needs an offset so each run depends on an earlier one: {self}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Does a node name match this reference's name (which may be a glob)
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern = regex::escape(&self.name)
+            .replace("\\*", ".*")
+            .replace("\\?", ".");
+        Regex::new(&format!("^{pattern}$")).is_ok_and(|re| re.is_match(name))
+    }
+
+    /// The reference's name as a SQL `LIKE` pattern
+    pub fn like_pattern(&self) -> String {
+        let mut pattern = String::new();
+        for c in self.name.chars() {
+            match c {
+                '*' => pattern.push('%'),
+                '?' => pattern.push('_'),
+                '%' | '_' | '\\' => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                }
+                c => pattern.push(c),
+            }
+        }
+        pattern
+    }
+}
+
+/// Name of the node a resolved reference points at, without the branch or offset
+pub fn node_name(reference: &Reference) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        reference.proj.as_deref().unwrap_or_default(),
+        reference.job.as_deref().unwrap_or_default(),
+        reference.kind,
+        reference.name
+    )
+}
+
+/// Limit on the number of edges a single offset range can expand into
+const MAX_RANGE_EDGES: i64 = 1000;
+
+/// Get the step of an offset range from the periods of the upstream triggers - the trigger
+/// itself for a trigger reference, or every trigger in the upstream task's job. `None` is a
+/// trigger without a fixed period (ie. a cron trigger).
+pub fn range_step(periods: &[Option<i64>]) -> Result<Duration, String> {
+    let Some(first) = periods.first() else {
+        return Err("the upstream job has no triggers, so an offset range has no step".to_owned());
+    };
+
+    match first {
+        Some(period) if periods.iter().all(|p| p == first) => Ok(Duration::seconds(*period)),
+        Some(_) => Err(
+            "the upstream triggers have different periods, so an offset range has no step"
+                .to_owned(),
+        ),
+        None => {
+            Err("cron triggers have no fixed period, so an offset range has no step".to_owned())
+        }
+    }
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+// trigger and task references are tried first, so `task/job` is still the task named "job".
+// Job references must name the job.
 static REFERENCE_PATTERN: Lazy<Regex> = Lazy::new(|| {
     Regex::new(
         "\
         ^\
+        (?:\
         ([\\w\\s]+/)?\
         ([\\w\\s]+/)?\
         (trigger|task)/\
-        ([\\w\\s]+)\
+        ([\\w\\s*?]+)\
+        (#[\\w\\s]+)?\
+        |\
+        ([\\w\\s]+/)?\
+        ([\\w\\s]+/)\
+        (job)\
+        )\
         (@.+)?\
         $",
     )
@@ -87,12 +228,14 @@ pub fn parse_reference(reference: &str) -> highnoon::Result<Reference> {
         highnoon::Error::bad_request(format!("invalid reference \'{reference}\'"))
     })?;
 
-    let mut proj = captures
-        .get(1)
-        .map(|c| c.as_str().trim_end_matches('/').to_owned());
-    let mut job = captures
-        .get(2)
-        .map(|c| c.as_str().trim_end_matches('/').to_owned());
+    // the project and job are captured by whichever branch matched
+    let (proj, job) = if captures.get(3).is_some() {
+        (captures.get(1), captures.get(2))
+    } else {
+        (captures.get(6), captures.get(7))
+    };
+    let mut proj = proj.map(|c| c.as_str().trim_end_matches('/').to_owned());
+    let mut job = job.map(|c| c.as_str().trim_end_matches('/').to_owned());
 
     if proj.is_some() && job.is_none() {
         // TODO - for a 3 part reference, the regex captures the first part as project and leaves job unmatched
@@ -101,29 +244,47 @@ pub fn parse_reference(reference: &str) -> highnoon::Result<Reference> {
 
     let kind = captures
         .get(3)
+        .or_else(|| captures.get(8))
         .expect("regex match is missing mandatory capture")
         .as_str()
         .parse()?;
+    // job references don't have a name
     let name = captures
         .get(4)
-        .expect("regex match is missing mandatory capture")
-        .as_str()
-        .to_owned();
-    let offset = captures
-        .get(5)
-        .map(|c| {
-            let offset = humantime::parse_duration(&c.as_str()[1..])?;
-            let offset = Duration::from_std(offset)?;
-            Ok::<_, anyhow::Error>(offset)
-        })
-        .transpose()?;
+        .map(|c| c.as_str().to_owned())
+        .unwrap_or_default();
+    let branch = captures.get(5).map(|c| c.as_str()[1..].to_owned());
+    let parse_offset = |s: &str| {
+        let offset = humantime::parse_duration(s)?;
+        let offset = Duration::from_std(offset)?;
+        Ok::<_, anyhow::Error>(offset)
+    };
+
+    let (offset, offset_end) = match captures.get(9).map(|c| &c.as_str()[1..]) {
+        None => (None, None),
+        Some(offsets) => match offsets.split_once("..") {
+            None => (Some(parse_offset(offsets)?), None),
+            Some((start, end)) => {
+                let start = parse_offset(start)?;
+                let end = parse_offset(end)?;
+                if end < start {
+                    return Err(highnoon::Error::bad_request(format!(
+                        "offset range ends before it starts in reference '{reference}'"
+                    )));
+                }
+                (Some(start), Some(end))
+            }
+        },
+    };
 
     Ok(Reference {
         proj,
         job,
         kind,
         name,
+        branch,
         offset,
+        offset_end,
     })
 }
 
@@ -156,7 +317,9 @@ mod test {
                 job: Some("b".to_owned()),
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: Some(Duration::days(1))
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
             }
         );
 
@@ -168,7 +331,9 @@ mod test {
                 job: Some("b".to_owned()),
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: Some(Duration::days(1))
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
             }
         );
 
@@ -180,7 +345,9 @@ mod test {
                 job: None,
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: Some(Duration::days(1))
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
             }
         );
 
@@ -192,7 +359,9 @@ mod test {
                 job: None,
                 kind: ReferenceKind::Trigger,
                 name: "c".to_string(),
-                offset: Some(Duration::days(1))
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
             }
         );
 
@@ -204,7 +373,9 @@ mod test {
                 job: Some("b".to_owned()),
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: None
+                branch: None,
+                offset: None,
+                offset_end: None
             }
         );
 
@@ -216,7 +387,9 @@ mod test {
                 job: None,
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: None
+                branch: None,
+                offset: None,
+                offset_end: None
             }
         );
 
@@ -228,9 +401,152 @@ mod test {
                 job: Some("b".to_owned()),
                 kind: ReferenceKind::Task,
                 name: "c".to_string(),
-                offset: None
+                branch: None,
+                offset: None,
+                offset_end: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_branch() {
+        let r = parse_reference("b/task/c#load@1d").unwrap();
+        assert_eq!(
+            r,
+            Reference {
+                proj: None,
+                job: Some("b".to_owned()),
+                kind: ReferenceKind::Task,
+                name: "c".to_string(),
+                branch: Some("load".to_owned()),
+                offset: Some(Duration::days(1)),
+                offset_end: None
+            }
+        );
+        assert_eq!(r.to_string(), "b/task/c#load@1day");
+
+        let r = parse_reference("task/c#skip").unwrap();
+        assert_eq!(r.branch, Some("skip".to_owned()));
+        assert_eq!(r.offset, None);
+
+        // empty branch name
+        assert_matches!(parse_reference("task/c#"), Err(_));
+        // non word char in branch
+        assert_matches!(parse_reference("task/c#a!"), Err(_));
+    }
+
+    #[test]
+    fn test_parse_dynamic() {
+        let r = parse_reference("a/b/job@1d").unwrap();
+        assert_eq!(
+            r,
+            Reference {
+                proj: Some("a".to_owned()),
+                job: Some("b".to_owned()),
+                kind: ReferenceKind::Job,
+                name: "".to_owned(),
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
+            }
+        );
+        assert_eq!(r.to_string(), "a/b/job@1day");
+        assert!(r.is_dynamic());
+
+        let r = parse_reference("b/job").unwrap();
+        assert_eq!(r.proj, None);
+        assert_eq!(r.job, Some("b".to_owned()));
+
+        let r = parse_reference("a/b/task/load_*").unwrap();
+        assert!(r.is_dynamic());
+        assert!(r.matches("load_orders"));
+        assert!(r.matches("load_"));
+        assert!(!r.matches("unload_orders"));
+        assert_eq!(r.like_pattern(), "load\\_%");
+
+        let r = parse_reference("task/load_?").unwrap();
+        assert!(r.matches("load_a"));
+        assert!(!r.matches("load_ab"));
+        assert!(!parse_reference("task/load").unwrap().is_dynamic());
+
+        // job references can't have a branch
+        assert_matches!(parse_reference("a/b/job#load"), Err(_));
+        // and must name the job
+        assert_matches!(parse_reference("job"), Err(_));
+    }
+
+    #[test]
+    fn test_parse_node_named_job() {
+        let r = parse_reference("task/job").unwrap();
+        assert_eq!(
+            r,
+            Reference {
+                proj: None,
+                job: None,
+                kind: ReferenceKind::Task,
+                name: "job".to_owned(),
+                branch: None,
+                offset: None,
+                offset_end: None
+            }
+        );
+
+        let r = parse_reference("trigger/job@1d").unwrap();
+        assert_eq!(
+            r,
+            Reference {
+                proj: None,
+                job: None,
+                kind: ReferenceKind::Trigger,
+                name: "job".to_owned(),
+                branch: None,
+                offset: Some(Duration::days(1)),
+                offset_end: None
             }
         );
+
+        let r = parse_reference("a/b/task/job@1d").unwrap();
+        assert_eq!(r.kind, ReferenceKind::Task);
+        assert_eq!(r.job, Some("b".to_owned()));
+        assert_eq!(r.name, "job");
+    }
+
+    #[test]
+    fn test_offset_range() {
+        let r = parse_reference("task/daily_load@0d..6d").unwrap();
+        assert_eq!(r.offset, Some(Duration::zero()));
+        assert_eq!(r.offset_end, Some(Duration::days(6)));
+        assert_eq!(r.to_string(), "task/daily_load@0s..6days");
+
+        let offsets = r.range_offsets(Duration::days(1)).unwrap();
+        assert_eq!(offsets.len(), 7);
+        assert_eq!(offsets[6], Duration::days(6));
+
+        // a partial step stops before the end
+        let offsets = r.range_offsets(Duration::days(4)).unwrap();
+        assert_eq!(offsets, vec![Duration::zero(), Duration::days(4)]);
+
+        assert!(r.range_offsets(Duration::seconds(1)).is_err());
+        assert_matches!(parse_reference("task/c@2d..1d"), Err(_));
+
+        assert_eq!(
+            range_step(&[Some(86400), Some(86400)]),
+            Ok(Duration::days(1))
+        );
+        assert!(range_step(&[]).is_err());
+        assert!(range_step(&[None]).is_err());
+        assert!(range_step(&[Some(3600), Some(86400)]).is_err());
+    }
+
+    #[test]
+    fn test_check_cyclic() {
+        let check = |r: &str| parse_reference(r).unwrap().check_cyclic();
+
+        assert_eq!(check("task/end@1d"), Ok(()));
+        assert_eq!(check("other/job@1h"), Ok(()));
+        assert!(check("task/end").is_err());
+        assert!(check("task/end@0s").is_err());
+        assert!(check("trigger/daily@1d").is_err());
     }
 
     #[test]
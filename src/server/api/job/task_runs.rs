@@ -25,6 +25,11 @@ struct ListJobAllTaskRuns {
     state: TokenState,
     priority: TaskPriority,
     worker_id: Option<Uuid>,
+    map_index: Option<i32>,
+    map_item: Option<String>,
+    manual: bool,
+    principal: Option<String>,
+    env_overrides: Option<Vec<String>>,
 }
 pub async fn list_job_all_task_runs(req: Request<State>) -> highnoon::Result<impl Responder> {
     let job_id: Uuid = req.param("id")?.parse()?;
@@ -49,7 +54,12 @@ pub async fn list_job_all_task_runs(req: Request<State>) -> highnoon::Result<imp
             finish_datetime,
             state,
             priority,
-            worker_id
+            worker_id,
+            map_index,
+            map_item,
+            manual,
+            principal,
+            tr.env_overrides
         FROM task_run tr
         JOIN task t ON t.id = tr.task_id
         WHERE t.job_id = $1
@@ -76,6 +86,11 @@ struct ListTaskRuns {
     state: TokenState,
     priority: TaskPriority,
     worker_id: Option<Uuid>,
+    map_index: Option<i32>,
+    map_item: Option<String>,
+    manual: bool,
+    principal: Option<String>,
+    env_overrides: Option<Vec<String>>,
 }
 
 pub async fn list_task_runs(req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -97,7 +112,12 @@ pub async fn list_task_runs(req: Request<State>) -> highnoon::Result<impl Respon
             finish_datetime,
             state,
             priority,
-            worker_id
+            worker_id,
+            map_index,
+            map_item,
+            manual,
+            principal,
+            tr.env_overrides
         FROM task_run tr
         JOIN task t ON t.id = tr.task_id
         WHERE tr.task_id = $1
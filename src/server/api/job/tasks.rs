@@ -1,46 +1,74 @@
 use crate::{
+    messages::SensorCondition,
     server::api::{
         State, auth,
-        job::reference::{Reference, ReferenceKind, parse_reference, resolve_reference},
+        job::{
+            defaults::resolve_task,
+            reference::{
+                Reference, ReferenceKind, node_name, parse_reference, range_step, resolve_reference,
+            },
+        },
         request_ext::RequestExt,
-        types::{Job, Task},
+        types::{DEFAULT_BRANCH_STASH_KEY, Job, Task, TaskDefaults},
     },
     util::{is_pg_integrity_error, pg_error},
 };
 use highnoon::{Json, Request, Responder};
 use serde::Serialize;
-use sqlx::{Postgres, Transaction};
-use tracing::debug;
+use sqlx::{PgExecutor, Postgres, Transaction, types::Json as SqlJson};
+use std::collections::HashSet;
+use tracing::{debug, warn};
 use uuid::Uuid;
 
 pub async fn create_task(
     txn: &mut Transaction<'_, Postgres>,
     task: &Task,
     job: &Job,
+    defaults: &TaskDefaults,
 ) -> highnoon::Result<Uuid> {
+    let resolved = resolve_task(task, defaults).map_err(highnoon::Error::bad_request)?;
+    check_sensor(task).map_err(highnoon::Error::bad_request)?;
+    check_branches(task).map_err(highnoon::Error::bad_request)?;
+
     let threshold = task.threshold.unwrap_or({
-        if let Some(dep) = &task.depends {
-            dep.len() as i32
+        if task.depends.is_some() || task.depends_cyclic.is_some() {
+            task.depends
+                .iter()
+                .chain(&task.depends_cyclic)
+                .flatten()
+                .count() as i32
         } else {
             1
         }
     });
 
-    let retry_delay_secs = task
+    let retry_delay_secs = resolved
         .retry
-        .as_ref()
         .and_then(|r| r.delay.as_deref())
         .map(humantime::parse_duration)
         .transpose()?
         .map(|dur| dur.as_secs() as i32);
 
-    let timeout_secs = task
+    let timeout_secs = resolved
         .timeout
-        .as_ref()
-        .map(|s| humantime::parse_duration(s))
+        .map(humantime::parse_duration)
         .transpose()?
         .map(|dur| dur.as_secs() as i32);
 
+    let sensor = task.sensor.as_ref();
+
+    let poke_interval_secs = sensor
+        .and_then(|s| s.poke_interval.as_deref())
+        .map(humantime::parse_duration)
+        .transpose()?
+        .map(|dur| dur.as_secs() as i64);
+
+    let sensor_timeout_secs = sensor
+        .and_then(|s| s.timeout.as_deref())
+        .map(humantime::parse_duration)
+        .transpose()?
+        .map(|dur| dur.as_secs() as i64);
+
     let new_id = Uuid::new_v4();
 
     let (task_id,): (Uuid,) = sqlx::query_as(
@@ -54,9 +82,16 @@ pub async fn create_task(
             timeout_secs,
             image,
             args,
-            env
+            env,
+            branch_names,
+            branch_stash_key,
+            map_stash_key,
+            sensor,
+            sensor_poke_interval_secs,
+            sensor_timeout_secs,
+            branch_exit_codes
          )
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
          ON CONFLICT(name, job_id)
          DO UPDATE
          SET threshold = $4,
@@ -65,29 +100,167 @@ pub async fn create_task(
              timeout_secs = $7,
              image = $8,
              args = $9,
-             env = $10
+             env = $10,
+             branch_names = $11,
+             branch_stash_key = $12,
+             map_stash_key = $13,
+             sensor = $14,
+             sensor_poke_interval_secs = $15,
+             sensor_timeout_secs = $16,
+             branch_exit_codes = $17,
+             archived_datetime = NULL
          RETURNING id",
     )
     .bind(new_id)
     .bind(&task.name)
     .bind(job.uuid)
     .bind(threshold)
-    .bind(task.retry.as_ref().map(|r| r.max_attempts))
+    .bind(resolved.retry.map(|r| r.max_attempts))
     .bind(retry_delay_secs)
     .bind(timeout_secs)
-    .bind(task.docker.as_ref().map(|d| &d.image))
-    .bind(task.docker.as_ref().map(|d| &d.args))
-    .bind(task.docker.as_ref().map(|d| &d.env))
+    .bind(&resolved.image)
+    .bind(resolved.args)
+    .bind(&resolved.env)
+    .bind(task.branches.as_ref().map(|b| &b.names))
+    .bind(
+        task.branches
+            .as_ref()
+            .map(|b| b.stash_key.as_deref().unwrap_or(DEFAULT_BRANCH_STASH_KEY)),
+    )
+    .bind(task.map.as_ref().map(|m| &m.stash_key))
+    .bind(sensor.map(|s| SqlJson(&s.condition)))
+    .bind(poke_interval_secs)
+    .bind(sensor_timeout_secs)
+    .bind(
+        task.branches
+            .as_ref()
+            .and_then(|b| b.exit_codes.as_ref())
+            .map(SqlJson),
+    )
     .fetch_one(txn.as_mut())
     .await?;
 
     Ok(task_id)
 }
 
+/// Check if a reference points into the given job
+pub fn is_in_job(reference: &Reference, job: &Job) -> bool {
+    reference.proj.as_deref() == Some(job.project.as_str())
+        && reference.job.as_deref() == Some(job.name.as_str())
+}
+
+/// Check a dependency of a task in `job` can be linked, before resolving what it points to.
+/// `kind` is "success" or "failure".
+pub fn check_dependency(
+    reference: &Reference,
+    kind: &str,
+    cyclic: bool,
+    job: &Job,
+) -> Result<(), String> {
+    if cyclic {
+        reference.check_cyclic()?;
+    }
+
+    match reference.kind {
+        ReferenceKind::Trigger if kind == "failure" => {
+            Err("depends_failure cannot reference a trigger since triggers can't fail".to_owned())
+        }
+        ReferenceKind::Trigger if reference.branch.is_some() => {
+            Err(format!("triggers don't have branches: {reference}"))
+        }
+        ReferenceKind::Task if kind == "failure" && reference.branch.is_some() => Err(format!(
+            "depends_failure cannot reference a branch since branches are only taken on \
+            success: {reference}"
+        )),
+        ReferenceKind::Job if is_in_job(reference, job) => {
+            Err(format!("a task cannot depend on its own job: {reference}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that the exit codes of a task choose branches it declares
+pub fn check_branches(task: &Task) -> Result<(), String> {
+    let Some(branches) = &task.branches else {
+        return Ok(());
+    };
+
+    for (exit_code, branch) in branches.exit_codes.iter().flatten() {
+        if !branches.names.contains(branch) {
+            return Err(format!(
+                "exit code {exit_code} chooses branch '{branch}' which task '{}' doesn't declare",
+                task.name
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a sensor task's condition and timing are valid, and that it doesn't also run an image
+pub fn check_sensor(task: &Task) -> Result<(), String> {
+    let Some(sensor) = &task.sensor else {
+        return Ok(());
+    };
+
+    if task.docker.is_some() {
+        return Err(format!(
+            "task '{}' is a sensor and cannot also have a docker section",
+            task.name
+        ));
+    }
+
+    if let Some(poke_interval) = &sensor.poke_interval {
+        let dur = humantime::parse_duration(poke_interval)
+            .map_err(|err| format!("sensor poke_interval is not valid: {err}"))?;
+        if dur.as_secs() == 0 {
+            return Err("sensor poke_interval must be at least 1s".to_owned());
+        }
+    }
+
+    if let Some(timeout) = &sensor.timeout {
+        humantime::parse_duration(timeout)
+            .map_err(|err| format!("sensor timeout is not valid: {err}"))?;
+    }
+
+    match &sensor.condition {
+        SensorCondition::Http { url, .. } => {
+            let url = reqwest::Url::parse(url)
+                .map_err(|err| format!("sensor url '{url}' is not valid: {err}"))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(format!("sensor url '{url}' must be http or https"));
+            }
+        }
+        SensorCondition::File { path } if !path.starts_with('/') => {
+            return Err(format!("sensor path '{path}' must be absolute"));
+        }
+        SensorCondition::Stash { key, .. } if key.is_empty() => {
+            return Err("sensor stash key cannot be empty".to_owned());
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
 pub async fn create_task_edges(
     txn: &mut Transaction<'_, Postgres>,
     task: &Task,
     job: &Job,
+) -> highnoon::Result<()> {
+    link_task(txn, task, job, true).await
+}
+
+/// Replace the edges into a task from its dependencies.
+///
+/// Glob and job references are expanded into an edge for each matching node. When `strict`
+/// they must match at least one node, otherwise (when re-expanding after the upstream job
+/// changed) they may match nothing.
+async fn link_task(
+    txn: &mut Transaction<'_, Postgres>,
+    task: &Task,
+    job: &Job,
+    strict: bool,
 ) -> highnoon::Result<()> {
     let (task_id,): (Uuid,) = sqlx::query_as(
         "SELECT id
@@ -117,39 +290,464 @@ pub async fn create_task_edges(
     .execute(txn.as_mut())
     .await?;
 
-    if let Some(depends) = &task.depends {
-        for d in depends {
+    sqlx::query(
+        "DELETE FROM dynamic_reference
+        WHERE task_id = $1",
+    )
+    .bind(task_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM pending_edge
+        WHERE task_id = $1",
+    )
+    .bind(task_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    let lists = [
+        ("success", false, &task.depends),
+        ("failure", false, &task.depends_failure),
+        ("success", true, &task.depends_cyclic),
+    ];
+
+    // nodes already linked, so overlapping globs don't create the same edge twice
+    let mut linked = HashSet::new();
+    let mut dynamic = false;
+
+    for (kind, cyclic, list) in lists {
+        for d in list.iter().flatten() {
             let reference = parse_reference(d)?;
             let reference = resolve_reference(reference, job);
+            let own_job = is_in_job(&reference, job);
+
+            check_dependency(&reference, kind, cyclic, job)
+                .map_err(highnoon::Error::bad_request)?;
+
+            // other jobs may be created later, so the edge is linked then
+            if !own_job && !job_exists(txn.as_mut(), &reference).await? {
+                record_pending_edge(&mut *txn, &task_id, &reference, kind).await?;
+                // what the reference expanded to before counts for nothing now
+                dynamic |= reference.is_dynamic() || reference.is_range();
+                continue;
+            }
 
-            match reference.kind {
-                ReferenceKind::Trigger => {
-                    create_trigger_edge(&mut *txn, &task_id, reference).await?
+            let names = if reference.is_dynamic() {
+                let mut names = expand_reference(txn.as_mut(), &reference).await?;
+                if own_job {
+                    names.retain(|name| *name != task.name);
                 }
-                ReferenceKind::Task => {
-                    create_task_edge(&mut *txn, &task_id, reference, "success").await?
+
+                if strict && names.is_empty() {
+                    return Err(highnoon::Error::bad_request(format!(
+                        "reference does not match anything: {reference}"
+                    )));
+                }
+                names
+            } else if !strict
+                && !own_job
+                && expand_reference(txn.as_mut(), &reference).await?.is_empty()
+            {
+                // the upstream job was changed and no longer has the node
+                record_pending_edge(&mut *txn, &task_id, &reference, kind).await?;
+                continue;
+            } else {
+                vec![reference.name.clone()]
+            };
+
+            let expanded = reference.is_dynamic() || reference.is_range();
+            if expanded {
+                dynamic = true;
+                record_dynamic_reference(&mut *txn, &task_id, &reference).await?;
+            }
+
+            for name in names {
+                let matched = Reference {
+                    kind: match reference.kind {
+                        ReferenceKind::Job => ReferenceKind::Task,
+                        kind => kind,
+                    },
+                    name,
+                    ..reference.clone()
+                };
+
+                let offsets = if matched.is_range() {
+                    let Some(periods) = get_upstream_periods(txn.as_mut(), &matched).await? else {
+                        return Err(highnoon::Error::bad_request(format!(
+                            "invalid {} reference (does this {} exist?): {matched}",
+                            matched.kind, matched.kind
+                        )));
+                    };
+
+                    range_step(&periods)
+                        .and_then(|step| matched.range_offsets(step))
+                        .map_err(|err| highnoon::Error::bad_request(format!("{err}: {matched}")))?
+                        .into_iter()
+                        .map(Some)
+                        .collect()
+                } else {
+                    vec![matched.offset]
+                };
+
+                for offset in offsets {
+                    let edge = Reference {
+                        offset,
+                        offset_end: None,
+                        ..matched.clone()
+                    };
+                    let key = (
+                        kind,
+                        node_name(&edge),
+                        offset.map_or(0, |o| o.num_seconds()),
+                    );
+
+                    // explicit duplicates are reported when the edge is inserted
+                    if linked.insert(key) || !expanded {
+                        create_edge(&mut *txn, &task_id, edge, kind, cyclic).await?;
+                    }
                 }
             }
         }
     }
 
-    // TODO refactor this duplicate code
-    if let Some(depends) = &task.depends_failure {
-        for d in depends {
-            let reference = parse_reference(d)?;
-            let reference = resolve_reference(reference, job);
+    // the default threshold is the number of dependencies, which depends on what they expanded to
+    if dynamic && task.threshold.is_none() {
+        sqlx::query(
+            "UPDATE task
+            SET threshold = GREATEST(1, (
+                SELECT COUNT(*)
+                FROM trigger_edge
+                WHERE task_id = $1
+            ) + (
+                SELECT COUNT(*)
+                FROM task_edge
+                WHERE child_task_id = $1
+                AND kind = 'success'
+            ) + (
+                SELECT COUNT(*)
+                FROM pending_edge
+                WHERE task_id = $1
+                AND kind = 'success'
+            ))
+            WHERE id = $1",
+        )
+        .bind(task_id)
+        .execute(txn.as_mut())
+        .await?;
+    }
 
-            match reference.kind {
-                ReferenceKind::Trigger => {
-                    return Err(highnoon::Error::http((
-                        highnoon::StatusCode::BAD_REQUEST,
-                        "depends_failure cannot reference a trigger since triggers can't fail",
-                    )));
-                }
-                ReferenceKind::Task => {
-                    create_task_edge(&mut *txn, &task_id, reference, "failure").await?
-                }
+    Ok(())
+}
+
+async fn create_edge(
+    txn: &mut Transaction<'_, Postgres>,
+    task_id: &Uuid,
+    reference: Reference,
+    kind: &str,
+    cyclic: bool,
+) -> highnoon::Result<()> {
+    match reference.kind {
+        ReferenceKind::Trigger => create_trigger_edge(txn, task_id, reference).await,
+        _ => create_task_edge(txn, task_id, reference, kind, cyclic).await,
+    }
+}
+
+/// Get the periods of the triggers an offset range takes its step from (see `range_step`), or
+/// `None` if the upstream node doesn't exist
+pub async fn get_upstream_periods<'e>(
+    executor: impl PgExecutor<'e>,
+    reference: &Reference,
+) -> highnoon::Result<Option<Vec<Option<i64>>>> {
+    let query = match reference.kind {
+        ReferenceKind::Trigger => {
+            "SELECT g.id, g.period
+            FROM trigger g
+            JOIN job j ON j.id = g.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE p.name = $1
+            AND j.name = $2
+            AND g.name = $3
+            AND j.archived_datetime IS NULL"
+        }
+        _ => {
+            "SELECT g.id, g.period
+            FROM task t
+            JOIN job j ON j.id = t.job_id
+            JOIN project p ON p.id = j.project_id
+            LEFT JOIN trigger g ON g.job_id = j.id
+            WHERE p.name = $1
+            AND j.name = $2
+            AND t.name = $3
+            AND t.archived_datetime IS NULL
+            AND j.archived_datetime IS NULL"
+        }
+    };
+
+    let rows: Vec<(Option<Uuid>, Option<i64>)> = sqlx::query_as(query)
+        .bind(&reference.proj)
+        .bind(&reference.job)
+        .bind(&reference.name)
+        .fetch_all(executor)
+        .await?;
+
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        rows.into_iter()
+            .filter(|(id, _)| id.is_some())
+            .map(|(_, period)| period)
+            .collect(),
+    ))
+}
+
+/// Check if the job a reference points into exists (and isn't archived)
+pub async fn job_exists<'e>(
+    executor: impl PgExecutor<'e>,
+    reference: &Reference,
+) -> highnoon::Result<bool> {
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT j.id
+        FROM job j
+        JOIN project p ON p.id = j.project_id
+        WHERE p.name = $1
+        AND j.name = $2
+        AND j.archived_datetime IS NULL",
+    )
+    .bind(&reference.proj)
+    .bind(&reference.job)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+/// Remember a dependency which can't be linked yet, `relink_dependents` links it once the
+/// upstream job is created
+async fn record_pending_edge(
+    txn: &mut Transaction<'_, Postgres>,
+    task_id: &Uuid,
+    reference: &Reference,
+    kind: &str,
+) -> highnoon::Result<()> {
+    debug!(?task_id, "pending dependency on {}", reference);
+
+    sqlx::query(
+        "INSERT INTO pending_edge(task_id, project_name, job_name, reference, kind)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(task_id)
+    .bind(&reference.proj)
+    .bind(&reference.job)
+    .bind(reference.to_string())
+    .bind(kind)
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+/// Get the task names and references of the job's dependencies which aren't linked yet
+pub async fn get_pending_edges(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<Vec<(String, String)>> {
+    let pending = sqlx::query_as(
+        "SELECT
+            t.name,
+            e.reference
+        FROM pending_edge e
+        JOIN task t ON t.id = e.task_id
+        WHERE t.job_id = $1
+        ORDER BY t.name, e.reference",
+    )
+    .bind(job_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    Ok(pending)
+}
+
+/// Remember which job a glob or job reference points into, so it can be expanded again when
+/// that job changes
+async fn record_dynamic_reference(
+    txn: &mut Transaction<'_, Postgres>,
+    task_id: &Uuid,
+    reference: &Reference,
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "INSERT INTO dynamic_reference(task_id, job_id)
+        SELECT $1, j.id
+        FROM job j
+        JOIN project p ON p.id = j.project_id
+        WHERE p.name = $2
+        AND j.name = $3
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(task_id)
+    .bind(&reference.proj)
+    .bind(&reference.job)
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+/// Get the names of the triggers or tasks a glob or job reference matches
+pub async fn expand_reference<'e>(
+    executor: impl PgExecutor<'e>,
+    reference: &Reference,
+) -> highnoon::Result<Vec<String>> {
+    let query = match reference.kind {
+        ReferenceKind::Trigger => {
+            "SELECT g.name
+            FROM trigger g
+            JOIN job j ON j.id = g.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE p.name = $1
+            AND j.name = $2
+            AND g.name LIKE $3
+            AND j.archived_datetime IS NULL
+            ORDER BY g.name"
+        }
+        ReferenceKind::Task => {
+            "SELECT t.name
+            FROM task t
+            JOIN job j ON j.id = t.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE p.name = $1
+            AND j.name = $2
+            AND t.name LIKE $3
+            AND t.archived_datetime IS NULL
+            AND j.archived_datetime IS NULL
+            ORDER BY t.name"
+        }
+        ReferenceKind::Job => {
+            "SELECT t.name
+            FROM task t
+            JOIN job j ON j.id = t.job_id
+            JOIN project p ON p.id = j.project_id
+            WHERE p.name = $1
+            AND j.name = $2
+            AND t.archived_datetime IS NULL
+            AND j.archived_datetime IS NULL
+            AND NOT EXISTS (
+                SELECT 1
+                FROM task_edge e
+                WHERE e.child_task_id = t.id
+                AND e.kind = 'failure'
+            )
+            ORDER BY t.name"
+        }
+    };
+
+    let names: Vec<(String,)> = sqlx::query_as(query)
+        .bind(&reference.proj)
+        .bind(&reference.job)
+        .bind(reference.like_pattern())
+        .fetch_all(executor)
+        .await?;
+
+    Ok(names.into_iter().map(|(name,)| name).collect())
+}
+
+/// Expand the glob and job references of tasks in other jobs which point into this job again,
+/// since the job's triggers and tasks may have changed. Pending dependencies on the job are
+/// linked now that it exists.
+pub async fn relink_dependents(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<()> {
+    let dependents: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT d.task_id
+        FROM dynamic_reference d
+        JOIN task t ON t.id = d.task_id
+        WHERE d.job_id = $1
+        AND t.job_id <> $1
+        UNION
+        SELECT e.task_id
+        FROM pending_edge e
+        JOIN task t ON t.id = e.task_id
+        JOIN job uj ON uj.name = e.job_name
+        JOIN project up ON up.id = uj.project_id
+        WHERE uj.id = $1
+        AND up.name = e.project_name
+        AND t.job_id <> $1",
+    )
+    .bind(job_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let dependents: Vec<Uuid> = dependents.into_iter().map(|(id,)| id).collect();
+    relink_tasks(txn, &dependents).await
+}
+
+/// Get the tasks in other jobs which have an edge from any of these tasks or triggers
+pub async fn get_dependents(
+    txn: &mut Transaction<'_, Postgres>,
+    task_ids: &[Uuid],
+    trigger_ids: &[Uuid],
+) -> highnoon::Result<Vec<Uuid>> {
+    let dependents: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT e.child_task_id
+        FROM task_edge e
+        JOIN task pt ON pt.id = e.parent_task_id
+        JOIN task ct ON ct.id = e.child_task_id
+        WHERE e.parent_task_id = ANY($1)
+        AND pt.job_id <> ct.job_id
+        UNION
+        SELECT e.task_id
+        FROM trigger_edge e
+        JOIN trigger g ON g.id = e.trigger_id
+        JOIN task t ON t.id = e.task_id
+        WHERE e.trigger_id = ANY($2)
+        AND g.job_id <> t.job_id",
+    )
+    .bind(task_ids)
+    .bind(trigger_ids)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    Ok(dependents.into_iter().map(|(id,)| id).collect())
+}
+
+/// Link the tasks again from their job definitions. Dependencies on nodes that no longer
+/// exist become pending edges, which are linked again if the nodes come back, and default
+/// thresholds are recalculated.
+pub async fn relink_tasks(
+    txn: &mut Transaction<'_, Postgres>,
+    task_ids: &[Uuid],
+) -> highnoon::Result<()> {
+    let tasks: Vec<(Uuid, String, String)> = sqlx::query_as(
+        "SELECT
+            j.id,
+            j.raw_definition,
+            t.name
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        WHERE t.id = ANY($1)
+        AND t.archived_datetime IS NULL
+        AND j.archived_datetime IS NULL",
+    )
+    .bind(task_ids)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    for (job_id, raw_definition, task_name) in tasks {
+        let job: Job = match serde_json::from_str(&raw_definition) {
+            Ok(job) => job,
+            Err(err) => {
+                warn!("cannot parse definition of job {}: {}", job_id, err);
+                continue;
             }
+        };
+
+        if let Some(task) = job.tasks.iter().find(|t| t.name == task_name) {
+            debug!("relinking {}/{}/task/{}", job.project, job.name, task.name);
+            link_task(txn, task, &job, false).await?;
         }
     }
 
@@ -172,6 +770,7 @@ async fn create_trigger_edge(
                 WHERE p.name = $1
                 AND j.name = $2
                 AND t.name = $3
+                AND j.archived_datetime IS NULL
             ),
             $4,
             $5
@@ -205,9 +804,14 @@ async fn create_task_edge(
     task: &Uuid,
     reference: Reference,
     kind: &str,
+    cyclic: bool,
 ) -> highnoon::Result<()> {
+    if let Some(branch) = &reference.branch {
+        check_branch(&mut *txn, &reference, branch).await?;
+    }
+
     let res = sqlx::query(
-        "INSERT INTO task_edge(parent_task_id, child_task_id, kind, edge_offset)
+        "INSERT INTO task_edge(parent_task_id, child_task_id, kind, edge_offset, branch, cyclic)
         VALUES(
             (
                 SELECT t.id
@@ -217,10 +821,14 @@ async fn create_task_edge(
                 WHERE p.name = $1
                 AND j.name = $2
                 AND t.name = $3
+                AND t.archived_datetime IS NULL
+                AND j.archived_datetime IS NULL
             ),
             $4,
             $5,
-            $6
+            $6,
+            $7,
+            $8
         )",
     )
     .bind(&reference.proj)
@@ -229,6 +837,8 @@ async fn create_task_edge(
     .bind(task)
     .bind(kind)
     .bind(reference.offset.map(|offset| offset.num_seconds()))
+    .bind(&reference.branch)
+    .bind(cyclic)
     .execute(txn.as_mut())
     .await;
 
@@ -247,6 +857,39 @@ async fn create_task_edge(
     }
 }
 
+/// Check that the upstream task of a reference declares the branch being depended on
+async fn check_branch(
+    txn: &mut Transaction<'_, Postgres>,
+    reference: &Reference,
+    branch: &str,
+) -> highnoon::Result<()> {
+    let declared: Option<(bool,)> = sqlx::query_as(
+        "SELECT $4 = ANY(COALESCE(t.branch_names, ARRAY[]::VARCHAR[]))
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE p.name = $1
+        AND j.name = $2
+        AND t.name = $3
+        AND t.archived_datetime IS NULL
+        AND j.archived_datetime IS NULL",
+    )
+    .bind(&reference.proj)
+    .bind(&reference.job)
+    .bind(&reference.name)
+    .bind(branch)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    match declared {
+        // missing tasks are reported when the edge is inserted
+        None | Some((true,)) => Ok(()),
+        Some((false,)) => Err(highnoon::Error::bad_request(format!(
+            "invalid branch reference (task does not declare branch '{branch}'): {reference}"
+        ))),
+    }
+}
+
 #[derive(Serialize, sqlx::FromRow)]
 struct ListTask {
     task_id: Uuid,
@@ -264,6 +907,7 @@ pub async fn list_tasks(req: Request<State>) -> highnoon::Result<impl Responder>
             name
         FROM task
         WHERE job_id = $1
+        AND archived_datetime IS NULL
         ORDER BY name
         LIMIT 200",
     )
@@ -0,0 +1,753 @@
+use crate::server::{
+    api::{
+        State, auth,
+        job::{
+            cycles::check_cycles,
+            upsert::{JobDiff, notify_job_upsert, upsert_job, upsert_job_edges},
+            validate::{Problem, check_jobs, schema_problems},
+        },
+        request_ext::RequestExt,
+        types::{Job, JobTemplate, ParameterType, TemplateInstance},
+    },
+    body_parser::read_from_body,
+};
+use chrono::{DateTime, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeMap;
+use tracing::info;
+use uuid::Uuid;
+
+impl ParameterType {
+    fn name(self) -> &'static str {
+        match self {
+            ParameterType::String => "string",
+            ParameterType::Integer => "integer",
+            ParameterType::Number => "number",
+            ParameterType::Boolean => "boolean",
+        }
+    }
+
+    fn matches(self, value: &JsonValue) -> bool {
+        match self {
+            ParameterType::String => value.is_string(),
+            ParameterType::Integer => value.is_i64() || value.is_u64(),
+            ParameterType::Number => value.is_number(),
+            ParameterType::Boolean => value.is_boolean(),
+        }
+    }
+
+    /// A value of this type, used to check a template renders
+    fn example(self) -> JsonValue {
+        match self {
+            ParameterType::String => JsonValue::from("x"),
+            ParameterType::Integer | ParameterType::Number => JsonValue::from(0),
+            ParameterType::Boolean => JsonValue::from(false),
+        }
+    }
+}
+
+/// The template a job was rendered from, and the declaration it was rendered with
+pub struct TemplateLink {
+    template_id: Uuid,
+    instance: JsonValue,
+}
+
+/// Render a job declared as a template instance into a job definition. Any other job
+/// definition is returned unchanged.
+pub async fn render_declaration(
+    pool: &PgPool,
+    value: JsonValue,
+) -> highnoon::Result<(JsonValue, Option<TemplateLink>)> {
+    if value.get("template").is_none() {
+        return Ok((value, None));
+    }
+
+    let instance: TemplateInstance = serde_json::from_value(value.clone())
+        .map_err(|err| highnoon::Error::bad_request(format!("invalid template instance: {err}")))?;
+
+    let row: Option<(Uuid, String)> = sqlx::query_as(
+        "SELECT
+            t.id,
+            t.raw_definition
+        FROM job_template t
+        JOIN project p ON p.id = t.project_id
+        WHERE p.name = $1
+        AND t.name = $2",
+    )
+    .bind(&instance.project)
+    .bind(&instance.template)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some((template_id, raw_definition)) = row else {
+        return Err(highnoon::Error::bad_request(format!(
+            "no template named '{}' in project '{}'",
+            instance.template, instance.project
+        )));
+    };
+
+    let template: JobTemplate = serde_json::from_str(&raw_definition)?;
+    let job = render(&template, &instance).map_err(highnoon::Error::bad_request)?;
+
+    Ok((
+        job,
+        Some(TemplateLink {
+            template_id,
+            instance: value,
+        }),
+    ))
+}
+
+/// Render a job declaration and parse the resulting job definition
+pub async fn declared_job(
+    pool: &PgPool,
+    value: JsonValue,
+) -> highnoon::Result<(Job, Option<TemplateLink>)> {
+    let (value, link) = render_declaration(pool, value).await?;
+    let job = serde_json::from_value(value)
+        .map_err(|err| highnoon::Error::bad_request(format!("invalid job definition: {err}")))?;
+
+    Ok((job, link))
+}
+
+/// Record which template (if any) a job was rendered from, so it can be rendered again when
+/// the template changes
+pub async fn set_job_template(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    link: Option<&TemplateLink>,
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "UPDATE job
+        SET template_id = $2,
+            template_instance = $3
+        WHERE id = $1",
+    )
+    .bind(job_id)
+    .bind(link.map(|l| l.template_id))
+    .bind(link.map(|l| &l.instance))
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+/// Check the template's parameters, and that it only uses parameters it declares
+fn check_template(template: &JobTemplate) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut values = BTreeMap::new();
+
+    for (idx, param) in template.parameters.iter().enumerate() {
+        if values.contains_key(&param.name) {
+            problems.push(Problem::new(
+                format!("/parameters/{idx}/name"),
+                format!("duplicate parameter '{}'", param.name),
+            ));
+        }
+
+        if let Some(default) = &param.default
+            && !param.kind.matches(default)
+        {
+            problems.push(Problem::new(
+                format!("/parameters/{idx}/default"),
+                format!("default must be a {}", param.kind.name()),
+            ));
+        }
+
+        values.insert(
+            param.name.clone(),
+            param
+                .default
+                .clone()
+                .unwrap_or_else(|| param.kind.example()),
+        );
+    }
+
+    match template.job.as_object() {
+        None => problems.push(Problem::new("/job", "job must be an object")),
+        Some(job) => {
+            for key in ["uuid", "project", "name"] {
+                if job.contains_key(key) {
+                    problems.push(Problem::new(
+                        format!("/job/{key}"),
+                        format!("'{key}' is set by each job using the template"),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Err(err) = substitute(&template.job, &values) {
+        problems.push(Problem::new("/job", err));
+    }
+
+    problems
+}
+
+/// Get the value of every parameter of the template, from the instance or the defaults
+fn resolve_params(
+    template: &JobTemplate,
+    instance: &TemplateInstance,
+) -> Result<BTreeMap<String, JsonValue>, String> {
+    if let Some(name) = instance
+        .params
+        .keys()
+        .find(|name| !template.parameters.iter().any(|p| &p.name == *name))
+    {
+        return Err(format!(
+            "template '{}' has no parameter '{name}'",
+            template.name
+        ));
+    }
+
+    let mut values = BTreeMap::new();
+    for param in &template.parameters {
+        let value = instance
+            .params
+            .get(&param.name)
+            .or(param.default.as_ref())
+            .ok_or_else(|| format!("parameter '{}' is required", param.name))?;
+
+        if !param.kind.matches(value) {
+            return Err(format!(
+                "parameter '{}' must be a {}",
+                param.name,
+                param.kind.name()
+            ));
+        }
+
+        values.insert(param.name.clone(), value.clone());
+    }
+
+    Ok(values)
+}
+
+/// Render a template instance into a job definition
+fn render(template: &JobTemplate, instance: &TemplateInstance) -> Result<JsonValue, String> {
+    let values = resolve_params(template, instance)?;
+    let mut job = substitute(&template.job, &values)?;
+
+    let obj = job
+        .as_object_mut()
+        .ok_or("the template's job must be an object")?;
+
+    obj.insert(
+        "uuid".to_owned(),
+        JsonValue::from(instance.uuid.to_string()),
+    );
+    obj.insert(
+        "project".to_owned(),
+        JsonValue::from(instance.project.clone()),
+    );
+    obj.insert("name".to_owned(), JsonValue::from(instance.name.clone()));
+
+    if let Some(description) = &instance.description {
+        obj.insert(
+            "description".to_owned(),
+            JsonValue::from(description.clone()),
+        );
+    } else if !obj.contains_key("description") {
+        obj.insert(
+            "description".to_owned(),
+            JsonValue::from(template.description.clone()),
+        );
+    }
+
+    if let Some(paused) = instance.paused {
+        obj.insert("paused".to_owned(), JsonValue::from(paused));
+    }
+
+    Ok(job)
+}
+
+/// Replace the placeholders in every string within the value
+fn substitute(
+    value: &JsonValue,
+    values: &BTreeMap<String, JsonValue>,
+) -> Result<JsonValue, String> {
+    Ok(match value {
+        JsonValue::String(s) => substitute_str(s, values)?,
+        JsonValue::Array(items) => JsonValue::Array(
+            items
+                .iter()
+                .map(|item| substitute(item, values))
+                .collect::<Result<_, _>>()?,
+        ),
+        JsonValue::Object(map) => JsonValue::Object(
+            map.iter()
+                .map(|(key, item)| Ok((key.clone(), substitute(item, values)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+/// Replace `{{name}}` placeholders in a string. A string which is a single placeholder is
+/// replaced by the parameter's value, keeping its type.
+fn substitute_str(s: &str, values: &BTreeMap<String, JsonValue>) -> Result<JsonValue, String> {
+    let lookup = |name: &str| {
+        values
+            .get(name.trim())
+            .ok_or_else(|| format!("unknown parameter '{}' in '{s}'", name.trim()))
+    };
+
+    if let Some(name) = s.strip_prefix("{{").and_then(|s| s.strip_suffix("}}"))
+        && !name.contains("{{")
+        && !name.contains("}}")
+    {
+        return lookup(name).cloned();
+    }
+
+    let mut out = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+
+        out.push_str(&rest[..start]);
+        match lookup(&rest[start + 2..start + 2 + len])? {
+            JsonValue::String(value) => out.push_str(value),
+            other => out.push_str(&other.to_string()),
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+
+    Ok(JsonValue::String(out))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ListTemplate {
+    template_id: Uuid,
+    name: String,
+    description: String,
+    updated_datetime: DateTime<Utc>,
+    num_jobs: i64,
+}
+
+pub async fn list_templates(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+
+    auth::list()
+        .project(project_id)
+        .kind("template")
+        .check(&req)
+        .await?;
+
+    let templates: Vec<ListTemplate> = sqlx::query_as(
+        "SELECT
+            t.id AS template_id,
+            t.name,
+            COALESCE(t.description, '') AS description,
+            t.updated_datetime,
+            (
+                SELECT COUNT(1)
+                FROM job j
+                WHERE j.template_id = t.id
+                AND j.archived_datetime IS NULL
+            ) AS num_jobs
+        FROM job_template t
+        WHERE t.project_id = $1
+        ORDER BY t.name",
+    )
+    .bind(project_id)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Ok(Json(templates))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TemplateJob {
+    job_id: Uuid,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct GetTemplate {
+    template_id: Uuid,
+    updated_datetime: DateTime<Utc>,
+    definition: JobTemplate,
+    jobs: Vec<TemplateJob>,
+}
+
+async fn get_template_jobs(pool: &PgPool, template_id: Uuid) -> highnoon::Result<Vec<TemplateJob>> {
+    let jobs = sqlx::query_as(
+        "SELECT
+            id AS job_id,
+            name
+        FROM job
+        WHERE template_id = $1
+        AND archived_datetime IS NULL
+        ORDER BY name",
+    )
+    .bind(template_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(jobs)
+}
+
+pub async fn get_template(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let name = req.param("name")?;
+
+    auth::get()
+        .project(project_id)
+        .kind("template")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid, DateTime<Utc>, String)> = sqlx::query_as(
+        "SELECT
+            id,
+            updated_datetime,
+            raw_definition
+        FROM job_template
+        WHERE project_id = $1
+        AND name = $2",
+    )
+    .bind(project_id)
+    .bind(name)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((template_id, updated_datetime, raw_definition)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    Json(GetTemplate {
+        template_id,
+        updated_datetime,
+        definition: serde_json::from_str(&raw_definition)?,
+        jobs: get_template_jobs(&pool, template_id).await?,
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct JobProblems {
+    job: String,
+    problems: Vec<Problem>,
+}
+
+#[derive(Serialize)]
+struct RenderedJob {
+    job_id: Uuid,
+    name: String,
+    #[serde(flatten)]
+    diff: JobDiff,
+}
+
+#[derive(Serialize)]
+struct UpdatedTemplate {
+    template_id: Uuid,
+    jobs: Vec<RenderedJob>,
+}
+
+/// Create or update a template. Every job using the template is rendered again and applied,
+/// if any of them would be invalid nothing is changed.
+pub async fn create_template(mut req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let template: JobTemplate = read_from_body(&mut req).await?;
+
+    auth::update()
+        .project(project_id)
+        .kind("template")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+
+    let exists: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM project WHERE id = $1")
+        .bind(project_id)
+        .fetch_optional(&pool)
+        .await?;
+
+    if exists.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let problems = check_template(&template);
+    if !problems.is_empty() {
+        return Response::status(StatusCode::BAD_REQUEST).json(problems);
+    }
+
+    let existing: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM job_template
+        WHERE project_id = $1
+        AND name = $2",
+    )
+    .bind(project_id)
+    .bind(&template.name)
+    .fetch_optional(&pool)
+    .await?;
+
+    let template_id = existing.map_or_else(Uuid::new_v4, |(id,)| id);
+
+    let instances: Vec<(JsonValue,)> = sqlx::query_as(
+        "SELECT template_instance
+        FROM job
+        WHERE template_id = $1
+        AND archived_datetime IS NULL
+        ORDER BY name",
+    )
+    .bind(template_id)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut jobs = Vec::new();
+    let mut links = Vec::new();
+    let mut failed = Vec::new();
+
+    for (value,) in instances {
+        let instance: TemplateInstance = serde_json::from_value(value.clone())?;
+
+        let job = match render(&template, &instance) {
+            Ok(job) => job,
+            Err(err) => {
+                failed.push(JobProblems {
+                    job: instance.name,
+                    problems: vec![Problem::new("/params", err)],
+                });
+                continue;
+            }
+        };
+
+        let problems = schema_problems(&job);
+        if !problems.is_empty() {
+            failed.push(JobProblems {
+                job: instance.name,
+                problems,
+            });
+            continue;
+        }
+
+        let job: Job = serde_json::from_value(job)?;
+        auth::update().job(job.uuid, project_id).check(&req).await?;
+
+        jobs.push(job);
+        links.push(TemplateLink {
+            template_id,
+            instance: value,
+        });
+    }
+
+    let checked = check_jobs(&pool, &jobs).await?;
+    for (job, problems) in jobs.iter().zip(checked.problems) {
+        if !problems.is_empty() {
+            failed.push(JobProblems {
+                job: job.name.clone(),
+                problems,
+            });
+        }
+    }
+
+    if !failed.is_empty() {
+        return Response::status(StatusCode::BAD_REQUEST).json(failed);
+    }
+
+    let principal = auth::principal_name(&req);
+
+    let mut txn = pool.begin().await?;
+
+    sqlx::query(
+        "INSERT INTO job_template(id, project_id, name, description,
+            raw_definition, updated_datetime)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT(id)
+        DO UPDATE
+        SET description = $4,
+            raw_definition = $5,
+            updated_datetime = $6",
+    )
+    .bind(template_id)
+    .bind(project_id)
+    .bind(&template.name)
+    .bind(&template.description)
+    .bind(serde_json::to_string(&template)?)
+    .bind(Utc::now())
+    .execute(txn.as_mut())
+    .await?;
+
+    let mut upserts = Vec::new();
+    for (job, link) in jobs.iter().zip(&links) {
+        let upsert = upsert_job(&mut txn, job, project_id, principal.as_deref()).await?;
+        set_job_template(&mut txn, upsert.job_id, Some(link)).await?;
+        upserts.push(upsert);
+    }
+
+    for (job, upsert) in jobs.iter().zip(upserts.iter_mut()) {
+        upsert_job_edges(&mut txn, job, upsert).await?;
+    }
+
+    let job_ids: Vec<Uuid> = jobs.iter().map(|job| job.uuid).collect();
+    check_cycles(&mut txn, &job_ids).await?;
+
+    txn.commit().await?;
+
+    info!(
+        "updated template {} -> {} ({} jobs)",
+        template.name,
+        template_id,
+        jobs.len()
+    );
+
+    let mut rendered = Vec::new();
+    for (job, upsert) in jobs.iter().zip(upserts) {
+        rendered.push(RenderedJob {
+            job_id: job.uuid,
+            name: job.name.clone(),
+            diff: notify_job_upsert(req.get_channel(), upsert).await?,
+        });
+    }
+
+    Response::status(StatusCode::CREATED).json(UpdatedTemplate {
+        template_id,
+        jobs: rendered,
+    })
+}
+
+/// Delete a template. This is refused while any (unarchived) jobs use it.
+pub async fn delete_template(req: Request<State>) -> highnoon::Result<Response> {
+    let project_id: Uuid = req.param("id")?.parse()?;
+    let name = req.param("name")?;
+
+    auth::delete()
+        .project(project_id)
+        .kind("template")
+        .check(&req)
+        .await?;
+
+    let pool = req.get_pool();
+    let mut txn = pool.begin().await?;
+
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM job_template
+        WHERE project_id = $1
+        AND name = $2
+        FOR UPDATE",
+    )
+    .bind(project_id)
+    .bind(name)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    let Some((template_id,)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let jobs = get_template_jobs(&pool, template_id).await?;
+    if !jobs.is_empty() {
+        return Err(highnoon::Error::http((
+            StatusCode::CONFLICT,
+            format!(
+                "template is used by {} jobs: {}",
+                jobs.len(),
+                jobs.iter()
+                    .map(|j| j.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )));
+    }
+
+    // archived jobs keep their rendered definition
+    sqlx::query(
+        "UPDATE job
+        SET template_id = NULL,
+            template_instance = NULL
+        WHERE template_id = $1",
+    )
+    .bind(template_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query("DELETE FROM job_template WHERE id = $1")
+        .bind(template_id)
+        .execute(txn.as_mut())
+        .await?;
+
+    txn.commit().await?;
+
+    info!("deleted template {} -> {}", name, template_id);
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn template() -> JobTemplate {
+        serde_json::from_value(json!({
+            "name": "ingest",
+            "description": "ingest a source",
+            "parameters": [
+                {"name": "source", "type": "string"},
+                {"name": "hour", "type": "integer", "default": 3},
+            ],
+            "job": {
+                "triggers": [{"name": "daily", "start": "2024-01-01T00:00:00Z",
+                              "cron": "0 0 {{ hour }} * * *"}],
+                "tasks": [{"name": "load_{{source}}", "threshold": "{{hour}}",
+                           "docker": {"image": "ingest:1", "args": ["{{source}}"]}}],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn instance(params: JsonValue) -> TemplateInstance {
+        serde_json::from_value(json!({
+            "uuid": "00000000-0000-0000-0000-000000000001",
+            "project": "proj",
+            "name": "ingest_foo",
+            "template": "ingest",
+            "params": params,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let job = render(&template(), &instance(json!({"source": "foo"}))).unwrap();
+
+        assert_eq!(job["name"], "ingest_foo");
+        assert_eq!(job["description"], "ingest a source");
+        assert_eq!(job["triggers"][0]["cron"], "0 0 3 * * *");
+        assert_eq!(job["tasks"][0]["name"], "load_foo");
+        // a placeholder on its own keeps the parameter's type
+        assert_eq!(job["tasks"][0]["threshold"], 3);
+        assert_eq!(job["tasks"][0]["docker"]["args"], json!(["foo"]));
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let t = template();
+
+        assert!(render(&t, &instance(json!({}))).is_err());
+        assert!(render(&t, &instance(json!({"source": 1}))).is_err());
+        assert!(render(&t, &instance(json!({"source": "a", "other": "b"}))).is_err());
+    }
+
+    #[test]
+    fn test_check_template() {
+        assert_eq!(check_template(&template()), vec![]);
+
+        let mut t = template();
+        t.job["tasks"][0]["name"] = json!("load_{{table}}");
+        t.job["name"] = json!("x");
+        assert_eq!(check_template(&t).len(), 2);
+    }
+}
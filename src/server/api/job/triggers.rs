@@ -1,14 +1,28 @@
-use crate::server::api::{
-    State, auth,
-    request_ext::RequestExt,
-    types::{Job, Trigger, duration_from_string},
+use crate::{
+    messages::{ProcessToken, TaskPriority, Token},
+    server::{
+        api::{
+            State, auth,
+            calendar::calendar_exists,
+            request_ext::RequestExt,
+            task::{ActivateOverrides, seed_stash, set_overrides},
+            types::{
+                CatchupSetting, EVENT_STASH_KEY, Job, Trigger, TriggerKind, duration_from_string,
+            },
+            updates,
+        },
+        trigger_time::TriggerTime as ScheduledTriggerTime,
+        triggers::{do_activate_trigger, increment_trigger_tokens},
+    },
 };
-use chrono::{DateTime, Utc};
-use highnoon::{Json, Request, Responder};
+use chrono::{DateTime, SubsecRound, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use sqlx::{Postgres, Transaction};
+use serde_json::Value as JsonValue;
+use sqlx::{PgPool, Postgres, Transaction};
 use std::str::FromStr;
 use thiserror::Error;
+use tracing::info;
 use uuid::Uuid;
 
 #[derive(Error, Debug)]
@@ -21,31 +35,133 @@ pub enum TriggerError {
     InvalidCron(cron::error::Error),
     #[error("Period is not valid: {0}")]
     InvalidPeriod(humantime::DurationError),
+    #[error("No start - time triggers must have a start")]
+    NoStart,
+    #[error("Event triggers fire when an event is received and cannot have a schedule")]
+    EventSchedule,
+    #[error("AMQP triggers must give the exchange to bind to")]
+    NoAmqpSource,
+    #[error("Only AMQP triggers can have an AMQP source")]
+    UnexpectedAmqpSource,
+    #[error("AMQP triggers can take the datetime from a header or the body, but not both")]
+    MultipleAmqpDatetime,
+    #[error("Datetime pointer is not a valid JSON pointer: {0}")]
+    InvalidDatetimePointer(String),
+    #[error("Stash triggers must give the project stash key to watch")]
+    NoStashSource,
+    #[error("Only stash triggers can have a stash source")]
+    UnexpectedStashSource,
+    #[error("Stash key cannot be empty")]
+    EmptyStashKey,
+    #[error("Catchup max_runs must be at least 1")]
+    InvalidMaxRuns,
+    #[error("Catchup max_age is not valid: {0}")]
+    InvalidMaxAge(humantime::DurationError),
 }
 
 fn bad_req(err: TriggerError) -> highnoon::Result<()> {
     Err(highnoon::Error::bad_request(err.to_string()))
 }
 
+/// Check a time trigger has a start and exactly one valid schedule, and other kinds of trigger
+/// have no schedule
+pub fn check_schedule(trigger: &Trigger) -> Result<(), TriggerError> {
+    if trigger.kind != TriggerKind::Time {
+        return match (&trigger.period, &trigger.cron) {
+            (None, None) => Ok(()),
+            _ => Err(TriggerError::EventSchedule),
+        };
+    }
+    if trigger.start.is_none() {
+        return Err(TriggerError::NoStart);
+    }
+
+    match (&trigger.period, &trigger.cron) {
+        (Some(_), Some(_)) => Err(TriggerError::MultipleSchedule),
+        (Some(p), None) => humantime::parse_duration(p)
+            .map(|_| ())
+            .map_err(TriggerError::InvalidPeriod),
+        (None, Some(c)) => cron::Schedule::from_str(c)
+            .map(|_| ())
+            .map_err(TriggerError::InvalidCron),
+        (None, None) => Err(TriggerError::NoSchedule),
+    }
+}
+
+/// Check an AMQP trigger has a valid source, and other kinds of trigger don't have one
+pub fn check_amqp(trigger: &Trigger) -> Result<(), TriggerError> {
+    match (&trigger.kind, &trigger.amqp) {
+        (TriggerKind::Amqp, None) => Err(TriggerError::NoAmqpSource),
+        (TriggerKind::Amqp, Some(amqp)) => {
+            if amqp.datetime_header.is_some() && amqp.datetime_pointer.is_some() {
+                return Err(TriggerError::MultipleAmqpDatetime);
+            }
+            if let Some(pointer) = &amqp.datetime_pointer
+                && !pointer.is_empty()
+                && !pointer.starts_with('/')
+            {
+                return Err(TriggerError::InvalidDatetimePointer(pointer.clone()));
+            }
+            Ok(())
+        }
+        (_, Some(_)) => Err(TriggerError::UnexpectedAmqpSource),
+        (_, None) => Ok(()),
+    }
+}
+
+/// Check a stash trigger has a stash key to watch, and other kinds of trigger don't have one
+pub fn check_stash(trigger: &Trigger) -> Result<(), TriggerError> {
+    match (&trigger.kind, &trigger.stash) {
+        (TriggerKind::Stash, None) => Err(TriggerError::NoStashSource),
+        (TriggerKind::Stash, Some(stash)) if stash.key.is_empty() => {
+            Err(TriggerError::EmptyStashKey)
+        }
+        (TriggerKind::Stash, Some(_)) => Ok(()),
+        (_, Some(_)) => Err(TriggerError::UnexpectedStashSource),
+        (_, None) => Ok(()),
+    }
+}
+
+/// Check the limits on a bounded catchup are valid
+pub fn check_catchup(trigger: &Trigger) -> Result<(), TriggerError> {
+    let Some(catchup) = &trigger.catchup else {
+        return Ok(());
+    };
+    if catchup.max_runs().is_some_and(|max_runs| max_runs < 1) {
+        return Err(TriggerError::InvalidMaxRuns);
+    }
+    if let Some(max_age) = catchup.max_age() {
+        humantime::parse_duration(max_age).map_err(TriggerError::InvalidMaxAge)?;
+    }
+    Ok(())
+}
+
 pub async fn create_trigger(
     txn: &mut Transaction<'_, Postgres>,
     job: &Job,
     trigger: &Trigger,
 ) -> highnoon::Result<Uuid> {
-    match (&trigger.period, &trigger.cron) {
-        (Some(_), Some(_)) => bad_req(TriggerError::MultipleSchedule)?,
-        (Some(p), None) => {
-            if let Err(e) = humantime::parse_duration(p) {
-                bad_req(TriggerError::InvalidPeriod(e))?
-            }
-        }
-        (None, Some(c)) => {
-            if let Err(e) = cron::Schedule::from_str(c) {
-                bad_req(TriggerError::InvalidCron(e))?
-            }
+    if let Err(err) = check_schedule(trigger) {
+        bad_req(err)?
+    }
+    if let Err(err) = check_catchup(trigger) {
+        bad_req(err)?
+    }
+    if let Err(err) = check_amqp(trigger) {
+        bad_req(err)?
+    }
+    if let Err(err) = check_stash(trigger) {
+        bad_req(err)?
+    }
+
+    for name in trigger.calendars.iter().flatten() {
+        if !calendar_exists(txn.as_mut(), &job.project, name).await? {
+            return Err(highnoon::Error::bad_request(format!(
+                "calendar '{name}' does not exist in project '{}'",
+                job.project
+            )));
         }
-        (None, None) => bad_req(TriggerError::NoSchedule)?,
-    };
+    }
 
     let new_id = Uuid::new_v4();
 
@@ -53,11 +169,17 @@ pub async fn create_trigger(
         "INSERT INTO trigger(id, name, job_id,
             start_datetime, end_datetime,
             earliest_trigger_datetime, latest_trigger_datetime,
-            period, cron, trigger_offset, catchup)
+            period, cron, trigger_offset, catchup, calendars,
+            catchup_max_runs, catchup_max_age, kind,
+            amqp_exchange, amqp_routing_key, amqp_datetime_header, amqp_datetime_pointer,
+            stash_project, stash_key)
         VALUES ($1, $2, $3,
             $4, $5,
             NULL, NULL,
-            $6, $7, $8, $9)
+            $6, $7, $8, $9, $10,
+            $11, $12, $13,
+            $14, $15, $16, $17,
+            $18, $19)
         ON CONFLICT(name, job_id)
         DO UPDATE
         SET start_datetime = $4,
@@ -65,7 +187,17 @@ pub async fn create_trigger(
             period = $6,
             cron = $7,
             trigger_offset = $8,
-            catchup = $9
+            catchup = $9,
+            calendars = $10,
+            catchup_max_runs = $11,
+            catchup_max_age = $12,
+            kind = $13,
+            amqp_exchange = $14,
+            amqp_routing_key = $15,
+            amqp_datetime_header = $16,
+            amqp_datetime_pointer = $17,
+            stash_project = $18,
+            stash_key = $19
         RETURNING id",
     )
     .bind(new_id)
@@ -76,12 +208,48 @@ pub async fn create_trigger(
     .bind(duration_from_string(trigger.period.as_deref())?)
     .bind(&trigger.cron)
     .bind(duration_from_string(trigger.offset.as_deref())?)
-    .bind(trigger.catchup.unwrap_or_default())
+    .bind(
+        trigger
+            .catchup
+            .as_ref()
+            .map(CatchupSetting::mode)
+            .unwrap_or_default(),
+    )
+    .bind(&trigger.calendars)
+    .bind(trigger.catchup.as_ref().and_then(CatchupSetting::max_runs))
+    .bind(duration_from_string(
+        trigger.catchup.as_ref().and_then(CatchupSetting::max_age),
+    )?)
+    .bind(trigger.kind)
+    .bind(trigger.amqp.as_ref().map(|amqp| &amqp.exchange))
+    .bind(
+        trigger
+            .amqp
+            .as_ref()
+            .and_then(|amqp| amqp.routing_key.as_deref()),
+    )
+    .bind(
+        trigger
+            .amqp
+            .as_ref()
+            .and_then(|amqp| amqp.datetime_header.as_deref()),
+    )
+    .bind(
+        trigger
+            .amqp
+            .as_ref()
+            .and_then(|amqp| amqp.datetime_pointer.as_deref()),
+    )
+    .bind(
+        trigger
+            .stash
+            .as_ref()
+            .map(|stash| stash.project.as_deref().unwrap_or(&job.project)),
+    )
+    .bind(trigger.stash.as_ref().map(|stash| &stash.key))
     .fetch_one(txn.as_mut())
     .await?;
 
-    // TODO - delete removed triggers
-
     Ok(id)
 }
 
@@ -89,7 +257,8 @@ pub async fn create_trigger(
 pub struct GetTriggerByJob {
     pub trigger_id: Uuid,
     pub trigger_name: String,
-    pub start_datetime: DateTime<Utc>,
+    pub kind: String,
+    pub start_datetime: Option<DateTime<Utc>>,
     pub end_datetime: Option<DateTime<Utc>>,
     pub earliest_trigger_datetime: Option<DateTime<Utc>>,
     pub latest_trigger_datetime: Option<DateTime<Utc>>,
@@ -97,6 +266,12 @@ pub struct GetTriggerByJob {
     pub cron: Option<String>,
     pub trigger_offset: Option<i64>,
     pub catchup: Option<String>,
+    pub catchup_max_runs: Option<i32>,
+    pub catchup_max_age: Option<i64>, // seconds
+    pub amqp_exchange: Option<String>,
+    pub amqp_routing_key: Option<String>,
+    pub stash_project: Option<String>,
+    pub stash_key: Option<String>,
 }
 
 pub async fn get_triggers_by_job(req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -108,6 +283,7 @@ pub async fn get_triggers_by_job(req: Request<State>) -> highnoon::Result<impl R
         "SELECT
             id AS trigger_id,
             name AS trigger_name,
+            kind,
             start_datetime,
             end_datetime,
             earliest_trigger_datetime,
@@ -115,7 +291,13 @@ pub async fn get_triggers_by_job(req: Request<State>) -> highnoon::Result<impl R
             period,
             cron,
             trigger_offset,
-            catchup
+            catchup,
+            catchup_max_runs,
+            catchup_max_age,
+            amqp_exchange,
+            amqp_routing_key,
+            stash_project,
+            stash_key
         FROM trigger
         WHERE job_id = $1
         ORDER BY latest_trigger_datetime DESC",
@@ -233,3 +415,455 @@ pub async fn get_trigger(req: Request<State>) -> highnoon::Result<impl Responder
 
     Ok(Json(GetTrigger { info, times }))
 }
+
+#[derive(Deserialize)]
+struct FireTriggerParams {
+    priority: Option<TaskPriority>,
+    #[serde(flatten)]
+    overrides: ActivateOverrides,
+}
+
+#[derive(Serialize)]
+struct FireTriggerReply {
+    activated: u64,
+}
+
+/// Fire a trigger manually for a trigger datetime, activating every task it triggers exactly
+/// as if the scheduler had fired it.
+pub async fn fire_trigger(mut req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let params: FireTriggerParams = req.body_json().await?;
+
+    if let Err(msg) = params.overrides.check() {
+        return Err(highnoon::Error::bad_request(msg));
+    }
+
+    // the run would start before the period it's for has happened
+    if trigger_datetime > Utc::now() {
+        return Err(highnoon::Error::bad_request(
+            "cannot fire a trigger for a datetime in the future",
+        ));
+    }
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT job_id
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id,)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    // the datetime may be off the trigger's schedule, so it isn't recorded as a trigger time
+    let tokens = increment_trigger_tokens(
+        &pool,
+        &mut txn,
+        ScheduledTriggerTime {
+            scheduled_datetime: trigger_datetime,
+            trigger_id,
+            trigger_datetime,
+        },
+    )
+    .await?;
+
+    for token in &tokens {
+        set_overrides(&mut txn, token, &params.overrides).await?;
+    }
+
+    txn.commit().await?;
+
+    let priority = params.priority.unwrap_or(TaskPriority::High);
+    let activated = tokens.len() as u64;
+
+    for token in tokens {
+        updates::send_token_update(req.get_channel(), ProcessToken::Increment(token, priority))
+            .await?;
+    }
+
+    info!(?trigger_id, trigger_datetime=%trigger_datetime.to_rfc3339(), "trigger fired manually");
+
+    Response::ok().json(FireTriggerReply { activated })
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct TriggerSkip {
+    trigger_datetime: DateTime<Utc>,
+    skipped: bool,
+    reason: String,
+    updated_datetime: DateTime<Utc>,
+}
+
+/// List the trigger datetimes which have been skipped, or un-skipped after being skipped
+pub async fn list_trigger_skips(req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT job_id
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id,)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::get()
+        .job(job_id, None)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let skips: Vec<TriggerSkip> = sqlx::query_as(
+        "SELECT
+            trigger_datetime,
+            skipped,
+            reason,
+            updated_datetime
+        FROM trigger_skip
+        WHERE trigger_id = $1
+        ORDER BY trigger_datetime DESC",
+    )
+    .bind(trigger_id)
+    .fetch_all(&pool)
+    .await?;
+
+    Json(skips).into_response()
+}
+
+#[derive(Deserialize)]
+struct SetTriggerSkip {
+    skipped: bool,
+}
+
+/// Skip a future trigger datetime, or un-skip one. Un-skipping a datetime which has already
+/// passed activates it, the same as firing the trigger manually.
+pub async fn set_trigger_skip(mut req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let body: SetTriggerSkip = req.body_json().await?;
+
+    let now = Utc::now();
+
+    if body.skipped && trigger_datetime <= now {
+        return Err(highnoon::Error::bad_request(
+            "only future trigger datetimes can be skipped",
+        ));
+    }
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT job_id
+        FROM trigger
+        WHERE id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id,)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    let previous: Option<(bool,)> = sqlx::query_as(
+        "SELECT skipped
+        FROM trigger_skip
+        WHERE trigger_id = $1
+        AND trigger_datetime = $2
+        FOR UPDATE",
+    )
+    .bind(trigger_id)
+    .bind(trigger_datetime)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO trigger_skip(trigger_id, trigger_datetime, skipped, reason, updated_datetime)
+        VALUES ($1, $2, $3, 'manual', $4)
+        ON CONFLICT(trigger_id, trigger_datetime)
+        DO UPDATE
+        SET skipped = $3,
+            reason = 'manual',
+            updated_datetime = $4",
+    )
+    .bind(trigger_id)
+    .bind(trigger_datetime)
+    .bind(body.skipped)
+    .bind(now)
+    .execute(txn.as_mut())
+    .await?;
+
+    // a past datetime was passed over by the scheduler when it was skipped, so it has to be
+    // activated here instead
+    let was_skipped = matches!(previous, Some((true,)));
+    let tokens = if !body.skipped && was_skipped && trigger_datetime <= now {
+        do_activate_trigger(
+            &pool,
+            &mut txn,
+            ScheduledTriggerTime {
+                scheduled_datetime: trigger_datetime,
+                trigger_id,
+                trigger_datetime,
+            },
+        )
+        .await?
+    } else {
+        vec![]
+    };
+
+    txn.commit().await?;
+
+    let activated = tokens.len() as u64;
+
+    for token in tokens {
+        updates::send_token_update(
+            req.get_channel(),
+            ProcessToken::Increment(token, TaskPriority::High),
+        )
+        .await?;
+    }
+
+    info!(?trigger_id, trigger_datetime=%trigger_datetime.to_rfc3339(), skipped=body.skipped, "trigger skip updated");
+
+    Response::ok().json(FireTriggerReply { activated })
+}
+
+#[derive(Deserialize)]
+struct TriggerEvent {
+    /// defaults to when the event is received
+    trigger_datetime: Option<DateTime<Utc>>,
+    /// events with the same key as an earlier event are ignored
+    idempotency_key: Option<String>,
+    /// written into the job stash for the trigger datetime
+    payload: Option<JsonValue>,
+    priority: Option<TaskPriority>,
+}
+
+#[derive(Serialize)]
+struct TriggerEventReply {
+    trigger_datetime: DateTime<Utc>,
+    activated: u64,
+    duplicate: bool,
+}
+
+/// Fire an event trigger. Any idempotency key is recorded with the trigger datetime, so a
+/// repeated event gets the original trigger datetime back without activating anything.
+pub async fn post_trigger_event(mut req: Request<State>) -> highnoon::Result<Response> {
+    let trigger_id = req.param("id")?.parse::<Uuid>()?;
+    let event: TriggerEvent = req.body_json().await?;
+
+    let pool = req.get_pool();
+
+    let row: Option<(Uuid, TriggerKind, bool)> = sqlx::query_as(
+        "SELECT
+            g.job_id,
+            g.kind,
+            j.paused
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        WHERE g.id = $1",
+    )
+    .bind(trigger_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id, kind, paused)) = row else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("trigger")
+        .check(&req)
+        .await?;
+
+    if kind != TriggerKind::Event {
+        return Err(highnoon::Error::bad_request("not an event trigger"));
+    }
+    if paused {
+        return Err(highnoon::Error::http((
+            StatusCode::CONFLICT,
+            "the job is paused",
+        )));
+    }
+
+    let trigger_datetime = event
+        .trigger_datetime
+        .unwrap_or_else(|| Utc::now().trunc_subsecs(0));
+
+    let mut txn = pool.begin().await?;
+
+    if let Some(key) = &event.idempotency_key {
+        let inserted: Option<(DateTime<Utc>,)> = sqlx::query_as(
+            "INSERT INTO trigger_event(trigger_id, idempotency_key, trigger_datetime,
+                received_datetime)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(trigger_id, idempotency_key)
+            DO NOTHING
+            RETURNING trigger_datetime",
+        )
+        .bind(trigger_id)
+        .bind(key)
+        .bind(trigger_datetime)
+        .bind(Utc::now())
+        .fetch_optional(txn.as_mut())
+        .await?;
+
+        if inserted.is_none() {
+            let (trigger_datetime,): (DateTime<Utc>,) = sqlx::query_as(
+                "SELECT trigger_datetime
+                FROM trigger_event
+                WHERE trigger_id = $1
+                AND idempotency_key = $2",
+            )
+            .bind(trigger_id)
+            .bind(key)
+            .fetch_one(txn.as_mut())
+            .await?;
+
+            info!(?trigger_id, idempotency_key=%key, "ignoring duplicate event");
+
+            return Response::ok().json(TriggerEventReply {
+                trigger_datetime,
+                activated: 0,
+                duplicate: true,
+            });
+        }
+    }
+
+    let tokens = do_activate_trigger(
+        &pool,
+        &mut txn,
+        ScheduledTriggerTime {
+            scheduled_datetime: trigger_datetime,
+            trigger_id,
+            trigger_datetime,
+        },
+    )
+    .await?;
+
+    if let Some(payload) = &event.payload {
+        // only the stash is seeded, so overrides from an earlier manual activation are kept
+        let payload = payload.to_string();
+        for token in &tokens {
+            seed_stash(&mut txn, token, EVENT_STASH_KEY, &payload).await?;
+        }
+    }
+
+    txn.commit().await?;
+
+    let priority = event.priority.unwrap_or(TaskPriority::Normal);
+    let activated = tokens.len() as u64;
+
+    for token in tokens {
+        updates::send_token_update(req.get_channel(), ProcessToken::Increment(token, priority))
+            .await?;
+    }
+
+    info!(?trigger_id, trigger_datetime=%trigger_datetime.to_rfc3339(), "trigger fired by event");
+
+    Response::ok().json(TriggerEventReply {
+        trigger_datetime,
+        activated,
+        duplicate: false,
+    })
+}
+
+/// Fire the stash triggers watching a project stash key which was just written, with the write
+/// time as the trigger datetime. Returns the tokens to send to the token processor once the
+/// transaction is committed.
+pub async fn fire_stash_triggers(
+    pool: &PgPool,
+    txn: &mut Transaction<'_, Postgres>,
+    project_id: Uuid,
+    key: &str,
+    written_datetime: DateTime<Utc>,
+) -> highnoon::Result<Vec<Token>> {
+    let trigger_ids: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT g.id
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        JOIN project p ON p.name = g.stash_project
+        WHERE g.kind = 'stash'
+        AND p.id = $1
+        AND g.stash_key = $2
+        AND NOT j.paused
+        AND j.archived_datetime IS NULL",
+    )
+    .bind(project_id)
+    .bind(key)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let mut tokens = Vec::new();
+
+    for (trigger_id,) in trigger_ids {
+        // writes within the same second share a trigger datetime, which only fires once
+        let inserted: Option<(Uuid,)> = sqlx::query_as(
+            "INSERT INTO trigger_event(trigger_id, idempotency_key, trigger_datetime,
+                received_datetime)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(trigger_id, idempotency_key)
+            DO NOTHING
+            RETURNING trigger_id",
+        )
+        .bind(trigger_id)
+        .bind(format!("stash:{}", written_datetime.to_rfc3339()))
+        .bind(written_datetime)
+        .bind(Utc::now())
+        .fetch_optional(txn.as_mut())
+        .await?;
+
+        if inserted.is_none() {
+            info!(?trigger_id, trigger_datetime=%written_datetime.to_rfc3339(), %key,
+                "trigger already fired for this stash write time");
+            continue;
+        }
+
+        tokens.extend(
+            do_activate_trigger(
+                pool,
+                txn,
+                ScheduledTriggerTime {
+                    scheduled_datetime: written_datetime,
+                    trigger_id,
+                    trigger_datetime: written_datetime,
+                },
+            )
+            .await?,
+        );
+
+        info!(?trigger_id, trigger_datetime=%written_datetime.to_rfc3339(), %key,
+            "trigger fired by stash write");
+    }
+
+    Ok(tokens)
+}
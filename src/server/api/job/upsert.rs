@@ -0,0 +1,495 @@
+use crate::{
+    messages::{ConfigUpdate, TriggerUpdate},
+    server::api::{
+        config_cache,
+        job::{defaults, tasks, triggers},
+        types::Job,
+        updates,
+    },
+    util::{is_pg_integrity_error, pg_error},
+};
+use highnoon::StatusCode;
+use lapin::Channel;
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Names added, updated in place, and removed by a job upsert
+#[derive(Serialize, Default, Debug)]
+pub struct NameDiff {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl NameDiff {
+    fn new(before: &BTreeSet<String>, after: &BTreeSet<String>) -> Self {
+        NameDiff {
+            added: after.difference(before).cloned().collect(),
+            updated: after.intersection(before).cloned().collect(),
+            removed: before.difference(after).cloned().collect(),
+        }
+    }
+}
+
+/// An edge into or out of a job, with both ends written as references
+/// (eg. `project/job/task/name`)
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, sqlx::FromRow)]
+pub struct EdgeDesc {
+    pub from: String,
+    pub to: String,
+    pub kind: String,
+    pub edge_offset: Option<i64>,
+    pub branch: Option<String>,
+    pub cyclic: bool,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct EdgeDiff {
+    pub added: Vec<EdgeDesc>,
+    pub removed: Vec<EdgeDesc>,
+}
+
+/// Changes made to the database by a job upsert
+#[derive(Serialize, Default, Debug)]
+pub struct JobDiff {
+    pub created: bool,
+    pub version: i32,
+    pub triggers: NameDiff,
+    pub tasks: NameDiff,
+    pub edges: EdgeDiff,
+    /// dependencies which can't be linked yet because their upstream doesn't exist
+    pub warnings: Vec<String>,
+}
+
+/// State carried between the phases of a job upsert
+pub struct JobUpsert {
+    pub job_id: Uuid,
+    pub diff: JobDiff,
+    edges_before: BTreeSet<EdgeDesc>,
+    triggers_to_tx: Vec<Uuid>,
+    tasks_to_tx: Vec<Uuid>,
+}
+
+/// Insert or update a job along with its triggers and tasks.
+///
+/// Triggers that are no longer in the definition are deleted, and tasks are archived (their
+/// history is kept). Edges are not created here - call `upsert_job_edges` once every job being
+/// changed in the transaction exists, then `notify_job_upsert` after committing.
+pub async fn upsert_job(
+    txn: &mut Transaction<'_, Postgres>,
+    job: &Job,
+    project_id: Uuid,
+    principal: Option<&str>,
+) -> highnoon::Result<JobUpsert> {
+    let raw_definition = serde_json::to_string(job)?;
+
+    let edges_before = get_edges(txn, job.uuid).await?;
+    let triggers_before = get_names(txn, job.uuid, "trigger").await?;
+    let tasks_before = get_names(txn, job.uuid, "task").await?;
+
+    let res: Result<(bool,), _> = sqlx::query_as(
+        "INSERT INTO job(
+            id, name, project_id, description, paused, raw_definition
+        ) VALUES (
+            $1, $2, $3, $4,
+            COALESCE($5, FALSE),
+            $6
+        )
+        ON CONFLICT(id)
+        DO UPDATE
+        SET name = $2,
+            project_id = $3,
+            description = $4,
+            paused = COALESCE($5, job.paused),
+            raw_definition = $6,
+            archived_datetime = NULL
+        RETURNING (xmax = 0) AS created",
+    )
+    .bind(job.uuid)
+    .bind(&job.name)
+    .bind(project_id)
+    .bind(&job.description)
+    .bind(job.paused)
+    .bind(&raw_definition)
+    .fetch_one(txn.as_mut())
+    .await;
+
+    let created = match pg_error(res)? {
+        Ok((created,)) => {
+            info!("created job {} -> {}", job.name, job.uuid);
+            created
+        }
+        Err(err) => {
+            warn!("error creating job: {}", err);
+            return if is_pg_integrity_error(&err) {
+                Err(highnoon::Error::http(StatusCode::CONFLICT))
+            } else {
+                Err(err.into())
+            };
+        }
+    };
+
+    let version = record_version(txn, job.uuid, &raw_definition, principal).await?;
+
+    let mut triggers_to_tx = Vec::new();
+    let mut tasks_to_tx = Vec::new();
+
+    for trigger in &job.triggers {
+        let id = triggers::create_trigger(txn, job, trigger).await?;
+        triggers_to_tx.push(id);
+    }
+
+    let defaults = defaults::get_job_defaults(txn.as_mut(), job, project_id).await?;
+
+    for task in &job.tasks {
+        let id = tasks::create_task(txn, task, job, &defaults).await?;
+        tasks_to_tx.push(id);
+    }
+
+    triggers_to_tx.extend(remove_triggers(txn, job.uuid, &triggers_to_tx).await?);
+    archive_tasks(txn, job.uuid, &tasks_to_tx).await?;
+
+    let triggers_after = job.triggers.iter().map(|t| t.name.clone()).collect();
+    let tasks_after = job.tasks.iter().map(|t| t.name.clone()).collect();
+
+    Ok(JobUpsert {
+        job_id: job.uuid,
+        diff: JobDiff {
+            created,
+            version,
+            triggers: NameDiff::new(&triggers_before, &triggers_after),
+            tasks: NameDiff::new(&tasks_before, &tasks_after),
+            edges: EdgeDiff::default(),
+            warnings: Vec::new(),
+        },
+        edges_before,
+        triggers_to_tx,
+        tasks_to_tx,
+    })
+}
+
+/// Replace the edges into the job's tasks, and work out which edges changed.
+pub async fn upsert_job_edges(
+    txn: &mut Transaction<'_, Postgres>,
+    job: &Job,
+    upsert: &mut JobUpsert,
+) -> highnoon::Result<()> {
+    for task in &job.tasks {
+        tasks::create_task_edges(txn, task, job).await?;
+    }
+    tasks::relink_dependents(txn, upsert.job_id).await?;
+
+    let edges_after = get_edges(txn, upsert.job_id).await?;
+
+    upsert.diff.edges = EdgeDiff {
+        added: edges_after
+            .difference(&upsert.edges_before)
+            .cloned()
+            .collect(),
+        removed: upsert
+            .edges_before
+            .difference(&edges_after)
+            .cloned()
+            .collect(),
+    };
+
+    upsert.diff.warnings = tasks::get_pending_edges(txn, upsert.job_id)
+        .await?
+        .into_iter()
+        .map(|(task, reference)| {
+            format!(
+                "task '{task}' depends on {reference} which doesn't exist yet, \
+                it will be linked once it is created"
+            )
+        })
+        .collect();
+
+    Ok(())
+}
+
+/// Tell the scheduler and workers about the changes, once they have been committed.
+pub async fn notify_job_upsert(chan: &Channel, upsert: JobUpsert) -> anyhow::Result<JobDiff> {
+    updates::send_trigger_update(chan, TriggerUpdate(upsert.triggers_to_tx)).await?;
+
+    for id in upsert.tasks_to_tx {
+        config_cache::send(chan, ConfigUpdate::TaskDef(id)).await?;
+    }
+
+    Ok(upsert.diff)
+}
+
+/// Store the definition as a new version of the job. A definition identical to the latest
+/// version isn't stored again, the version refers to the earlier copy instead. Returns the
+/// version number of the definition.
+async fn record_version(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    raw_definition: &str,
+    principal: Option<&str>,
+) -> highnoon::Result<i32> {
+    let hash = definition_hash(raw_definition);
+
+    let latest: Option<(i32, String, i32)> = sqlx::query_as(
+        "SELECT version, hash, COALESCE(definition_version, version)
+        FROM job_version
+        WHERE job_id = $1
+        ORDER BY version DESC
+        LIMIT 1",
+    )
+    .bind(job_id)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    let (version, definition_version) = match latest {
+        Some((version, latest_hash, definition_version)) if latest_hash == hash => {
+            (version + 1, Some(definition_version))
+        }
+        Some((version, _, _)) => (version + 1, None),
+        None => (1, None),
+    };
+
+    sqlx::query(
+        "INSERT INTO job_version(
+            job_id, version, created_datetime, principal, hash, raw_definition,
+            definition_version
+        ) VALUES (
+            $1, $2, CURRENT_TIMESTAMP, $3, $4, $5,
+            $6
+        )",
+    )
+    .bind(job_id)
+    .bind(version)
+    .bind(principal)
+    .bind(&hash)
+    .bind(definition_version.is_none().then_some(raw_definition))
+    .bind(definition_version)
+    .execute(txn.as_mut())
+    .await?;
+
+    info!(
+        ?job_id,
+        ?definition_version,
+        "recorded job version {}",
+        version
+    );
+
+    Ok(version)
+}
+
+fn definition_hash(raw_definition: &str) -> String {
+    format!(
+        "{:016x}",
+        xxhash_rust::xxh3::xxh3_64(raw_definition.as_bytes())
+    )
+}
+
+async fn get_names(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    kind: &str,
+) -> highnoon::Result<BTreeSet<String>> {
+    let names: Vec<(String,)> = sqlx::query_as(
+        "SELECT name
+        FROM trigger
+        WHERE job_id = $1
+        AND $2 = 'trigger'
+        UNION ALL
+        SELECT name
+        FROM task
+        WHERE job_id = $1
+        AND archived_datetime IS NULL
+        AND $2 = 'task'",
+    )
+    .bind(job_id)
+    .bind(kind)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    Ok(names.into_iter().map(|(name,)| name).collect())
+}
+
+/// Get every edge into or out of the job's tasks and triggers
+async fn get_edges(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+) -> highnoon::Result<BTreeSet<EdgeDesc>> {
+    let edges: Vec<EdgeDesc> = sqlx::query_as(
+        "SELECT
+            pp.name || '/' || pj.name || '/task/' || pt.name AS \"from\",
+            cp.name || '/' || cj.name || '/task/' || ct.name AS \"to\",
+            e.kind,
+            e.edge_offset,
+            e.branch,
+            e.cyclic
+        FROM task_edge e
+        JOIN task pt ON pt.id = e.parent_task_id
+        JOIN job pj ON pj.id = pt.job_id
+        JOIN project pp ON pp.id = pj.project_id
+        JOIN task ct ON ct.id = e.child_task_id
+        JOIN job cj ON cj.id = ct.job_id
+        JOIN project cp ON cp.id = cj.project_id
+        WHERE pj.id = $1
+        OR cj.id = $1
+        UNION ALL
+        SELECT
+            gp.name || '/' || gj.name || '/trigger/' || g.name AS \"from\",
+            cp.name || '/' || cj.name || '/task/' || ct.name AS \"to\",
+            'trigger' AS kind,
+            e.edge_offset,
+            NULL AS branch,
+            FALSE AS cyclic
+        FROM trigger_edge e
+        JOIN trigger g ON g.id = e.trigger_id
+        JOIN job gj ON gj.id = g.job_id
+        JOIN project gp ON gp.id = gj.project_id
+        JOIN task ct ON ct.id = e.task_id
+        JOIN job cj ON cj.id = ct.job_id
+        JOIN project cp ON cp.id = cj.project_id
+        WHERE gj.id = $1
+        OR cj.id = $1",
+    )
+    .bind(job_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    Ok(edges.into_iter().collect())
+}
+
+/// Delete the job's triggers which aren't in `keep`, along with any edges from them.
+/// Returns the IDs of the deleted triggers.
+async fn remove_triggers(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    keep: &[Uuid],
+) -> highnoon::Result<Vec<Uuid>> {
+    let removed: Vec<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM trigger
+        WHERE job_id = $1
+        AND NOT id = ANY($2)",
+    )
+    .bind(job_id)
+    .bind(keep)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let removed: Vec<Uuid> = removed.into_iter().map(|(id,)| id).collect();
+
+    if removed.is_empty() {
+        return Ok(removed);
+    }
+
+    let dependents = tasks::get_dependents(txn, &[], &removed).await?;
+
+    sqlx::query(
+        "DELETE FROM trigger_edge
+        WHERE trigger_id = ANY($1)",
+    )
+    .bind(&removed)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM trigger_skip
+        WHERE trigger_id = ANY($1)",
+    )
+    .bind(&removed)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM trigger_event
+        WHERE trigger_id = ANY($1)",
+    )
+    .bind(&removed)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "UPDATE scheduler
+        SET waiting_for_trigger_id = NULL
+        WHERE waiting_for_trigger_id = ANY($1)",
+    )
+    .bind(&removed)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM trigger
+        WHERE id = ANY($1)",
+    )
+    .bind(&removed)
+    .execute(txn.as_mut())
+    .await?;
+
+    // dependents in other jobs are left with pending edges to the removed triggers
+    tasks::relink_tasks(txn, &dependents).await?;
+
+    info!(?job_id, "removed {} triggers", removed.len());
+
+    Ok(removed)
+}
+
+/// Archive the job's tasks which aren't in `keep` and remove all edges into or out of them.
+/// Archived tasks keep their tokens and runs, and are restored if a task with the same name is
+/// added back to the job. Tasks in other jobs depending on them are relinked, leaving pending
+/// edges in place of the removed ones.
+async fn archive_tasks(
+    txn: &mut Transaction<'_, Postgres>,
+    job_id: Uuid,
+    keep: &[Uuid],
+) -> highnoon::Result<()> {
+    let archived: Vec<(Uuid,)> = sqlx::query_as(
+        "UPDATE task
+        SET archived_datetime = CURRENT_TIMESTAMP
+        WHERE job_id = $1
+        AND archived_datetime IS NULL
+        AND NOT id = ANY($2)
+        RETURNING id",
+    )
+    .bind(job_id)
+    .bind(keep)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let archived: Vec<Uuid> = archived.into_iter().map(|(id,)| id).collect();
+
+    if archived.is_empty() {
+        return Ok(());
+    }
+
+    let dependents = tasks::get_dependents(txn, &archived, &[]).await?;
+    tasks::relink_tasks(txn, &dependents).await?;
+
+    sqlx::query(
+        "DELETE FROM trigger_edge
+        WHERE task_id = ANY($1)",
+    )
+    .bind(&archived)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM task_edge
+        WHERE parent_task_id = ANY($1)
+        OR child_task_id = ANY($1)",
+    )
+    .bind(&archived)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM pending_edge
+        WHERE task_id = ANY($1)",
+    )
+    .bind(&archived)
+    .execute(txn.as_mut())
+    .await?;
+
+    info!(?job_id, "archived {} tasks", archived.len());
+
+    Ok(())
+}
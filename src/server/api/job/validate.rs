@@ -0,0 +1,883 @@
+use crate::server::{
+    api::{
+        State, auth,
+        calendar::calendar_exists,
+        job::{
+            cycles::find_cycle,
+            defaults::{
+                PROJECT_DEFAULTS_KEY, get_project_config, layer, parse_project_defaults,
+                resolve_task,
+            },
+            reference::{
+                Reference, ReferenceKind, node_name, parse_reference, range_step, resolve_reference,
+            },
+            tasks::{
+                check_branches, check_dependency, check_sensor, expand_reference,
+                get_upstream_periods, is_in_job, job_exists,
+            },
+            template::render_declaration,
+            triggers::{check_amqp, check_catchup, check_schedule, check_stash},
+            upsert::EdgeDesc,
+        },
+        request_ext::RequestExt,
+        types::{Job, Retry, duration_from_string},
+    },
+    body_parser::read_from_body,
+};
+use highnoon::{Json, Request, Responder};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+static JOB_SCHEMA: Lazy<jsonschema::Validator> = Lazy::new(|| {
+    let schema = serde_json::from_str(include_str!("../../../../docs/job-schema.json"))
+        .expect("job schema is not valid json");
+    jsonschema::validator_for(&schema).expect("job schema is not a valid json schema")
+});
+
+/// A problem with a job definition. The path is a JSON pointer to the offending value.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Problem {
+    path: String,
+    message: String,
+}
+
+impl Problem {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Problem {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ValidateNode {
+    reference: String,
+    kind: String,
+    external: bool,
+}
+
+#[derive(Serialize)]
+struct ValidateGraph {
+    nodes: Vec<ValidateNode>,
+    edges: Vec<EdgeDesc>,
+}
+
+#[derive(Serialize)]
+struct Validation {
+    valid: bool,
+    problems: Vec<Problem>,
+    warnings: Vec<Problem>,
+    graph: Option<ValidateGraph>,
+}
+
+/// Check a job definition against the JSONSchema
+pub fn schema_problems(value: &JsonValue) -> Vec<Problem> {
+    JOB_SCHEMA
+        .iter_errors(value)
+        .map(|err| Problem::new(err.instance_path.to_string(), err.to_string()))
+        .collect()
+}
+
+/// A parsed and resolved dependency of one of the job's tasks
+struct Dependency {
+    path: String,
+    task: String,
+    reference: Reference,
+    kind: &'static str,
+    cyclic: bool,
+}
+
+/// Check a job definition without writing anything, and report every problem found
+/// along with the graph the job would create.
+pub async fn validate(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    let value: JsonValue = read_from_body(&mut req).await?;
+
+    let value = render_declaration(&req.get_pool(), value).await?.0;
+
+    let mut problems = schema_problems(&value);
+
+    let job: Job = match serde_json::from_value(value) {
+        Ok(job) => job,
+        Err(err) => {
+            problems.push(Problem::new("", format!("invalid job definition: {err}")));
+            return Ok(Json(Validation {
+                valid: false,
+                problems,
+                warnings: Vec::new(),
+                graph: None,
+            }));
+        }
+    };
+
+    let pool = req.get_pool();
+
+    // check before anything outside the definition is looked at, even if the project doesn't
+    // exist - then there's no project to authorize against, only the action
+    match get_project(&pool, &job.project).await? {
+        Some(project_id) => auth::update().job(job.uuid, project_id).check(&req).await?,
+        None => auth::update().job(None, None).check(&req).await?,
+    }
+
+    let checked = check_jobs(&pool, std::slice::from_ref(&job)).await?;
+    problems.extend(checked.problems.into_iter().flatten());
+    let warnings = checked.warnings.into_iter().flatten().collect();
+
+    let mut nodes: Vec<ValidateNode> = job
+        .triggers
+        .iter()
+        .map(|t| ("trigger", &t.name))
+        .chain(job.tasks.iter().map(|t| ("task", &t.name)))
+        .map(|(kind, name)| ValidateNode {
+            reference: format!("{}/{}/{}/{}", job.project, job.name, kind, name),
+            kind: kind.to_owned(),
+            external: false,
+        })
+        .collect();
+    nodes.extend(
+        checked
+            .external
+            .into_iter()
+            .map(|(reference, kind)| ValidateNode {
+                reference,
+                kind,
+                external: true,
+            }),
+    );
+
+    Ok(Json(Validation {
+        valid: problems.is_empty(),
+        problems,
+        warnings,
+        graph: Some(ValidateGraph {
+            nodes,
+            edges: checked.edges,
+        }),
+    }))
+}
+
+/// The result of checking a set of jobs together
+pub struct Checked {
+    /// problems with each job, in the same order as the jobs
+    pub problems: Vec<Vec<Problem>>,
+    /// dependencies which will be linked once their job is created, in the same order as the jobs
+    pub warnings: Vec<Vec<Problem>>,
+    /// edges into the jobs' tasks
+    pub edges: Vec<EdgeDesc>,
+    /// nodes outside of the jobs which the edges refer to, and their kind
+    pub external: BTreeMap<String, String>,
+}
+
+/// Check a set of jobs which are going to be applied together, so they may refer to each other
+/// even if they don't exist yet. Nothing is written to the database.
+pub async fn check_jobs(pool: &PgPool, jobs: &[Job]) -> highnoon::Result<Checked> {
+    let mut checked = Checked {
+        problems: Vec::new(),
+        warnings: Vec::new(),
+        edges: Vec::new(),
+        external: BTreeMap::new(),
+    };
+
+    for (i, job) in jobs.iter().enumerate() {
+        let (mut problems, deps) = check_definition(job);
+
+        if let Some(other) = jobs[..i].iter().find(|other| other.uuid == job.uuid) {
+            problems.push(Problem::new(
+                "/uuid",
+                format!("job '{}' has the same uuid", other.name),
+            ));
+        }
+        if jobs[..i]
+            .iter()
+            .any(|other| other.project == job.project && other.name == job.name)
+        {
+            problems.push(Problem::new(
+                "/name",
+                format!("job '{}' is given more than once", job.name),
+            ));
+        }
+
+        match get_project(pool, &job.project).await? {
+            Some(project_id) => {
+                problems.extend(check_name(pool, job, project_id).await?);
+
+                for (i, trigger) in job.triggers.iter().enumerate() {
+                    for (j, name) in trigger.calendars.iter().flatten().enumerate() {
+                        if !calendar_exists(pool, &job.project, name).await? {
+                            problems.push(Problem::new(
+                                format!("/triggers/{i}/calendars/{j}"),
+                                format!("calendar '{name}' does not exist"),
+                            ));
+                        }
+                    }
+                }
+
+                let config = get_project_config(pool, project_id).await?;
+                match parse_project_defaults(config.as_ref()) {
+                    Ok(project_defaults) => {
+                        let defaults = layer(job.defaults.as_ref(), project_defaults);
+                        for (i, task) in job.tasks.iter().enumerate() {
+                            if let Err(message) = resolve_task(task, &defaults) {
+                                problems.push(Problem::new(format!("/tasks/{i}/docker"), message));
+                            }
+                        }
+                    }
+                    Err(err) => problems.push(Problem::new(
+                        "/project",
+                        format!("invalid {PROJECT_DEFAULTS_KEY} in project config: {err}"),
+                    )),
+                }
+            }
+            None => problems.push(Problem::new(
+                "/project",
+                format!("project '{}' does not exist", job.project),
+            )),
+        }
+
+        let mut warnings = Vec::new();
+
+        for dep in deps {
+            let target = jobs.iter().find(|target| is_in_job(&dep.reference, target));
+
+            if target.is_none() && !job_exists(pool, &dep.reference).await? {
+                warnings.push(Problem::new(
+                    &dep.path,
+                    format!(
+                        "job does not exist yet, the dependency will be linked once it is \
+                        created: {}",
+                        dep.reference
+                    ),
+                ));
+                continue;
+            }
+
+            let found = match target {
+                Some(target) => find_in_job(&dep.reference, target),
+                None => find_external(pool, &dep.reference).await?,
+            };
+
+            let mut names = match found {
+                Ok(names) => names,
+                Err(message) => {
+                    problems.push(Problem::new(&dep.path, message));
+                    continue;
+                }
+            };
+
+            // a glob in the task's own job doesn't match the task itself
+            if dep.reference.is_dynamic() && is_in_job(&dep.reference, job) {
+                names.retain(|name| *name != dep.task);
+            }
+
+            for name in names {
+                let reference = Reference {
+                    kind: match dep.reference.kind {
+                        ReferenceKind::Job => ReferenceKind::Task,
+                        kind => kind,
+                    },
+                    name,
+                    ..dep.reference.clone()
+                };
+
+                let offsets = if reference.is_range() {
+                    let step = match target {
+                        Some(target) => range_step(&periods_in_job(&reference, target)),
+                        None => match get_upstream_periods(pool, &reference).await? {
+                            Some(periods) => range_step(&periods),
+                            None => Err("upstream node does not exist".to_owned()),
+                        },
+                    };
+
+                    match step.and_then(|step| reference.range_offsets(step)) {
+                        Ok(offsets) => offsets.into_iter().map(Some).collect(),
+                        Err(message) => {
+                            problems
+                                .push(Problem::new(&dep.path, format!("{message}: {reference}")));
+                            continue;
+                        }
+                    }
+                } else {
+                    vec![reference.offset]
+                };
+
+                if target.is_none() {
+                    checked
+                        .external
+                        .insert(node_name(&reference), reference.kind.to_string());
+                }
+
+                for offset in offsets {
+                    checked.edges.push(EdgeDesc {
+                        from: node_name(&reference),
+                        to: format!("{}/{}/task/{}", job.project, job.name, dep.task),
+                        kind: dep.kind.to_owned(),
+                        edge_offset: offset.map(|offset| offset.num_seconds()),
+                        branch: reference.branch.clone(),
+                        cyclic: dep.cyclic,
+                    });
+                }
+            }
+        }
+
+        checked.problems.push(problems);
+        checked.warnings.push(warnings);
+    }
+
+    // only edges from depends_cyclic may close a cycle, they point at an earlier token
+    let mut cycle_edges: Vec<(String, String)> = checked
+        .edges
+        .iter()
+        .filter(|e| e.kind != "trigger" && !e.cyclic)
+        .map(|e| (e.from.clone(), e.to.clone()))
+        .collect();
+    let job_ids: Vec<Uuid> = jobs.iter().map(|job| job.uuid).collect();
+    cycle_edges.extend(get_downstream_edges(pool, &job_ids).await?);
+
+    if let Some(cycle) = find_cycle(&cycle_edges) {
+        // report the cycle against the first job it passes through
+        let idx = jobs
+            .iter()
+            .position(|job| {
+                let prefix = format!("{}/{}/", job.project, job.name);
+                cycle.iter().any(|node| node.starts_with(&prefix))
+            })
+            .unwrap_or_default();
+
+        if let Some(problems) = checked.problems.get_mut(idx) {
+            problems.push(Problem::new(
+                "/tasks",
+                format!("dependency cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+    }
+
+    Ok(checked)
+}
+
+pub async fn get_project(pool: &PgPool, name: &str) -> highnoon::Result<Option<Uuid>> {
+    let row: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM project WHERE name = $1")
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|(id,)| id))
+}
+
+/// Check no other job in the project has the same name
+async fn check_name(
+    pool: &PgPool,
+    job: &Job,
+    project_id: Uuid,
+) -> highnoon::Result<Option<Problem>> {
+    let other: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT id
+        FROM job
+        WHERE project_id = $1
+        AND name = $2
+        AND id <> $3",
+    )
+    .bind(project_id)
+    .bind(&job.name)
+    .bind(job.uuid)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(other.map(|(id,)| {
+        Problem::new(
+            "/name",
+            format!(
+                "another job ({id}) in this project is already named '{}'",
+                job.name
+            ),
+        )
+    }))
+}
+
+/// Check the timeout and retry settings of a task or the defaults
+fn check_timing(
+    problems: &mut Vec<Problem>,
+    path: &str,
+    timeout: Option<&str>,
+    retry: Option<&Retry>,
+) {
+    if let Some(timeout) = timeout
+        && let Err(err) = humantime::parse_duration(timeout)
+    {
+        problems.push(Problem::new(
+            format!("{path}/timeout"),
+            format!("timeout is not valid: {err}"),
+        ));
+    }
+
+    if let Some(retry) = retry {
+        if retry.max_attempts < 0 {
+            problems.push(Problem::new(
+                format!("{path}/retry/max_attempts"),
+                "max_attempts cannot be negative",
+            ));
+        }
+        if let Some(delay) = &retry.delay
+            && let Err(err) = humantime::parse_duration(delay)
+        {
+            problems.push(Problem::new(
+                format!("{path}/retry/delay"),
+                format!("delay is not valid: {err}"),
+            ));
+        }
+    }
+}
+
+/// Checks which only need the definition itself. Also returns the dependencies which parsed
+/// successfully.
+fn check_definition(job: &Job) -> (Vec<Problem>, Vec<Dependency>) {
+    let mut problems = Vec::new();
+    let mut deps = Vec::new();
+
+    let mut trigger_names = BTreeSet::new();
+    for (i, trigger) in job.triggers.iter().enumerate() {
+        let path = format!("/triggers/{i}");
+
+        if !trigger_names.insert(trigger.name.as_str()) {
+            problems.push(Problem::new(
+                format!("{path}/name"),
+                format!("duplicate trigger name '{}'", trigger.name),
+            ));
+        }
+        if let Err(err) = check_schedule(trigger) {
+            problems.push(Problem::new(&path, err.to_string()));
+        }
+        if let Err(err) = check_catchup(trigger) {
+            problems.push(Problem::new(format!("{path}/catchup"), err.to_string()));
+        }
+        if let Err(err) = check_amqp(trigger) {
+            problems.push(Problem::new(format!("{path}/amqp"), err.to_string()));
+        }
+        if let Err(err) = check_stash(trigger) {
+            problems.push(Problem::new(format!("{path}/stash"), err.to_string()));
+        }
+        if let Err(err) = duration_from_string(trigger.offset.as_deref()) {
+            problems.push(Problem::new(
+                format!("{path}/offset"),
+                format!("offset is not valid: {err}"),
+            ));
+        }
+        if let Some(end) = trigger.end
+            && let Some(start) = trigger.start
+            && end < start
+        {
+            problems.push(Problem::new(format!("{path}/end"), "end is before start"));
+        }
+    }
+
+    if let Some(defaults) = &job.defaults {
+        check_timing(
+            &mut problems,
+            "/defaults",
+            defaults.timeout.as_deref(),
+            defaults.retry.as_ref(),
+        );
+    }
+
+    let mut task_names = BTreeSet::new();
+    for (i, task) in job.tasks.iter().enumerate() {
+        let path = format!("/tasks/{i}");
+
+        if !task_names.insert(task.name.as_str()) {
+            problems.push(Problem::new(
+                format!("{path}/name"),
+                format!("duplicate task name '{}'", task.name),
+            ));
+        }
+
+        check_timing(
+            &mut problems,
+            &path,
+            task.timeout.as_deref(),
+            task.retry.as_ref(),
+        );
+
+        if let Err(message) = check_sensor(task) {
+            problems.push(Problem::new(format!("{path}/sensor"), message));
+        }
+
+        if let Err(message) = check_branches(task) {
+            problems.push(Problem::new(format!("{path}/branches"), message));
+        }
+
+        let lists = [
+            ("depends", "success", &task.depends),
+            ("depends_failure", "failure", &task.depends_failure),
+            ("depends_cyclic", "success", &task.depends_cyclic),
+        ];
+
+        let mut num_deps = 0;
+        // the number of dependencies isn't known until globs, job references and
+        // offset ranges are expanded
+        let mut dynamic = false;
+        for (field, kind, list) in lists {
+            for (j, d) in list.iter().flatten().enumerate() {
+                num_deps += 1;
+                let dep_path = format!("{path}/{field}/{j}");
+
+                let reference = match parse_reference(d) {
+                    Ok(reference) => resolve_reference(reference, job),
+                    Err(err) => {
+                        problems.push(Problem::new(dep_path, err.to_string()));
+                        continue;
+                    }
+                };
+
+                let cyclic = field == "depends_cyclic";
+                if let Err(message) = check_dependency(&reference, kind, cyclic, job) {
+                    problems.push(Problem::new(dep_path, message));
+                    continue;
+                }
+
+                let kind = match reference.kind {
+                    ReferenceKind::Trigger => "trigger",
+                    ReferenceKind::Task | ReferenceKind::Job => kind,
+                };
+
+                dynamic |= reference.is_dynamic() || reference.is_range();
+
+                deps.push(Dependency {
+                    path: dep_path,
+                    task: task.name.clone(),
+                    reference,
+                    kind,
+                    cyclic,
+                });
+            }
+        }
+
+        if let Some(threshold) = task.threshold {
+            if threshold < 1 {
+                problems.push(Problem::new(
+                    format!("{path}/threshold"),
+                    "threshold must be at least 1",
+                ));
+            } else if num_deps > 0 && !dynamic && threshold > num_deps {
+                problems.push(Problem::new(
+                    format!("{path}/threshold"),
+                    format!(
+                        "threshold {threshold} is more than the number of dependencies \
+                        ({num_deps}), the task would never run"
+                    ),
+                ));
+            }
+        }
+    }
+
+    (problems, deps)
+}
+
+/// Get the periods of the triggers in a job whose definition is known that an offset range
+/// takes its step from
+fn periods_in_job(reference: &Reference, target: &Job) -> Vec<Option<i64>> {
+    target
+        .triggers
+        .iter()
+        .filter(|t| reference.kind != ReferenceKind::Trigger || t.name == reference.name)
+        .map(|t| {
+            duration_from_string(t.period.as_deref())
+                .ok()
+                .flatten()
+                .map(i64::from)
+        })
+        .collect()
+}
+
+/// Resolve a reference to a job whose definition is known, returns the names of the nodes it
+/// matches or the problem if it doesn't resolve
+fn find_in_job(reference: &Reference, target: &Job) -> Result<Vec<String>, String> {
+    let names: Vec<String> = match reference.kind {
+        ReferenceKind::Trigger => target
+            .triggers
+            .iter()
+            .filter(|t| reference.matches(&t.name))
+            .map(|t| t.name.clone())
+            .collect(),
+        ReferenceKind::Task => target
+            .tasks
+            .iter()
+            .filter(|t| reference.matches(&t.name))
+            .map(|t| t.name.clone())
+            .collect(),
+        ReferenceKind::Job => target
+            .tasks
+            .iter()
+            .filter(|t| t.depends_failure.is_none())
+            .map(|t| t.name.clone())
+            .collect(),
+    };
+
+    if names.is_empty() {
+        return Err(if reference.is_dynamic() {
+            format!("reference does not match anything: {reference}")
+        } else {
+            format!(
+                "no {} named '{}' in job '{}'",
+                reference.kind, reference.name, target.name
+            )
+        });
+    }
+
+    if let Some(branch) = &reference.branch {
+        for name in &names {
+            let declared = target
+                .tasks
+                .iter()
+                .find(|t| t.name == *name)
+                .and_then(|t| t.branches.as_ref())
+                .is_some_and(|b| b.names.contains(branch));
+
+            if !declared {
+                return Err(format!("task '{name}' does not declare branch '{branch}'"));
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Resolve a reference to another job the same way creating the edge would, returns the names
+/// of the nodes it matches or the problem if it doesn't resolve
+async fn find_external(
+    pool: &PgPool,
+    reference: &Reference,
+) -> highnoon::Result<Result<Vec<String>, String>> {
+    if reference.is_dynamic() {
+        let names = expand_reference(pool, reference).await?;
+        return Ok(if names.is_empty() {
+            Err(format!("reference does not match anything: {reference}"))
+        } else {
+            Ok(names)
+        });
+    }
+
+    let found: Option<(Vec<String>,)> = sqlx::query_as(
+        "SELECT COALESCE(t.branch_names, ARRAY[]::VARCHAR[])
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE p.name = $1
+        AND j.name = $2
+        AND t.name = $3
+        AND t.archived_datetime IS NULL
+        AND j.archived_datetime IS NULL
+        AND $4 = 'task'
+        UNION ALL
+        SELECT ARRAY[]::VARCHAR[]
+        FROM trigger g
+        JOIN job j ON j.id = g.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE p.name = $1
+        AND j.name = $2
+        AND g.name = $3
+        AND j.archived_datetime IS NULL
+        AND $4 = 'trigger'",
+    )
+    .bind(&reference.proj)
+    .bind(&reference.job)
+    .bind(&reference.name)
+    .bind(reference.kind.to_string())
+    .fetch_optional(pool)
+    .await?;
+
+    let message = match found {
+        None => format!(
+            "invalid {} reference (does this {} exist?): {reference}",
+            reference.kind, reference.kind
+        ),
+        Some((declared,)) => match &reference.branch {
+            Some(branch) if !declared.contains(branch) => format!(
+                "invalid branch reference (task does not declare branch '{branch}'): {reference}"
+            ),
+            _ => return Ok(Ok(vec![reference.name.clone()])),
+        },
+    };
+
+    Ok(Err(message))
+}
+
+/// Get the edges downstream of the jobs' tasks in other jobs, which could lead back into the
+/// jobs and form a cycle
+async fn get_downstream_edges(
+    pool: &PgPool,
+    job_ids: &[Uuid],
+) -> highnoon::Result<Vec<(String, String)>> {
+    let edges = sqlx::query_as(
+        "WITH RECURSIVE downstream(parent_task_id, child_task_id) AS (
+            SELECT e.parent_task_id, e.child_task_id
+            FROM task_edge e
+            JOIN task pt ON pt.id = e.parent_task_id
+            JOIN task ct ON ct.id = e.child_task_id
+            WHERE pt.job_id = ANY($1)
+            AND NOT ct.job_id = ANY($1)
+            AND NOT e.cyclic
+            UNION
+            SELECT e.parent_task_id, e.child_task_id
+            FROM task_edge e
+            JOIN downstream d ON d.child_task_id = e.parent_task_id
+            JOIN task ct ON ct.id = e.child_task_id
+            WHERE NOT ct.job_id = ANY($1)
+            AND NOT e.cyclic
+        )
+        SELECT
+            pp.name || '/' || pj.name || '/task/' || pt.name,
+            cp.name || '/' || cj.name || '/task/' || ct.name
+        FROM downstream d
+        JOIN task pt ON pt.id = d.parent_task_id
+        JOIN job pj ON pj.id = pt.job_id
+        JOIN project pp ON pp.id = pj.project_id
+        JOIN task ct ON ct.id = d.child_task_id
+        JOIN job cj ON cj.id = ct.job_id
+        JOIN project cp ON cp.id = cj.project_id",
+    )
+    .bind(job_ids)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(edges)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn job(value: JsonValue) -> Job {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn paths(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|p| p.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_schema() {
+        let value = serde_json::json!({
+            "uuid": "bd8f7e7e-6d14-4d64-a1c5-9b0d2a30c1a5",
+            "project": "proj",
+            "name": "job",
+            "description": "",
+            "triggers": [{"name": "daily", "start": "2021-01-01T00:00:00Z", "period": "1d"}],
+            "tasks": [{"name": "a", "docker": {"image": "bash", "args": []}}]
+        });
+        assert_eq!(JOB_SCHEMA.iter_errors(&value).count(), 0);
+
+        let value = serde_json::json!({
+            "uuid": "bd8f7e7e-6d14-4d64-a1c5-9b0d2a30c1a5",
+            "name": "job",
+            "description": "",
+            "triggers": [],
+            "tasks": [{"name": 1}]
+        });
+        assert_eq!(JOB_SCHEMA.iter_errors(&value).count(), 2);
+    }
+
+    #[test]
+    fn test_check_definition() {
+        let job = job(serde_json::json!({
+            "uuid": "bd8f7e7e-6d14-4d64-a1c5-9b0d2a30c1a5",
+            "project": "proj",
+            "name": "job",
+            "description": "",
+            "triggers": [
+                {"name": "daily", "start": "2021-01-01T00:00:00Z", "period": "1d"},
+                {"name": "both", "start": "2021-01-01T00:00:00Z", "period": "1d", "cron": "* * *"},
+                {"name": "hook", "kind": "event"},
+                {"name": "scheduled-hook", "kind": "event", "period": "1d"},
+                {"name": "no-start", "period": "1d"},
+                {"name": "orders", "kind": "stash", "stash": {"project": "sales", "key": "orders"}},
+                {"name": "no-key", "kind": "stash"},
+            ],
+            "tasks": [
+                {"name": "a", "depends": ["trigger/daily"]},
+                {"name": "b", "depends": ["task/a#load", "task/missing", "other/task/x"]},
+                {"name": "c", "depends": ["task/a"], "threshold": 2, "timeout": "soon"},
+                {"name": "c", "depends_failure": ["trigger/daily"]},
+                {"name": "ready", "depends": ["trigger/daily"],
+                    "sensor": {"kind": "file", "path": "/data/ready", "poke_interval": "5m"}},
+                {"name": "fetch", "depends": ["trigger/daily"],
+                    "sensor": {"kind": "http", "url": "http://example.com"},
+                    "docker": {"image": "bash", "args": []}},
+                {"name": "check", "branches": {"names": ["load"], "exit_codes": {"3": "skip"}}},
+            ]
+        }));
+
+        let (problems, deps) = check_definition(&job);
+
+        assert_eq!(
+            paths(&problems),
+            vec![
+                "/triggers/1",
+                "/triggers/3",
+                "/triggers/4",
+                "/triggers/6/stash",
+                "/tasks/2/timeout",
+                "/tasks/2/threshold",
+                "/tasks/3/name",
+                "/tasks/3/depends_failure/0",
+                "/tasks/5/sensor",
+                "/tasks/6/branches",
+            ]
+        );
+
+        let found: Vec<_> = deps
+            .iter()
+            .map(|d| find_in_job(&d.reference, &job))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                Ok(vec!["daily".to_owned()]),
+                Err("task 'a' does not declare branch 'load'".to_owned()),
+                Err("no task named 'missing' in job 'job'".to_owned()),
+                Err("no task named 'x' in job 'job'".to_owned()),
+                Ok(vec!["a".to_owned()]),
+                Ok(vec!["daily".to_owned()]),
+                Ok(vec!["daily".to_owned()]),
+            ]
+        );
+        assert!(!is_in_job(&deps[3].reference, &job));
+    }
+
+    #[test]
+    fn test_find_dynamic() {
+        let job = job(serde_json::json!({
+            "uuid": "bd8f7e7e-6d14-4d64-a1c5-9b0d2a30c1a5",
+            "project": "proj",
+            "name": "job",
+            "description": "",
+            "triggers": [],
+            "tasks": [
+                {"name": "load_a"},
+                {"name": "load_b"},
+                {"name": "report", "depends": ["task/load_*"]},
+                {"name": "alert", "depends_failure": ["task/report"]},
+            ]
+        }));
+
+        let find =
+            |r: &str| find_in_job(&resolve_reference(parse_reference(r).unwrap(), &job), &job);
+
+        assert_eq!(
+            find("task/load_*"),
+            Ok(vec!["load_a".to_owned(), "load_b".to_owned()])
+        );
+        assert_eq!(
+            find("proj/job/job"),
+            Ok(vec![
+                "load_a".to_owned(),
+                "load_b".to_owned(),
+                "report".to_owned()
+            ])
+        );
+        assert!(find("task/unload_*").is_err());
+    }
+}
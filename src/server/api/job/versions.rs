@@ -0,0 +1,175 @@
+use crate::server::api::{State, auth, request_ext::RequestExt, types::Job};
+use chrono::{DateTime, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ListVersion {
+    version: i32,
+    created_datetime: DateTime<Utc>,
+    principal: Option<String>,
+    hash: String,
+    /// the earlier version with the same definition, if it was unchanged
+    definition_version: Option<i32>,
+}
+
+pub async fn list_versions(req: Request<State>) -> highnoon::Result<impl Responder> {
+    let job_id: Uuid = req.param("id")?.parse()?;
+
+    auth::list().job(job_id, None).check(&req).await?;
+
+    let versions: Vec<ListVersion> = sqlx::query_as(
+        "SELECT
+            version,
+            created_datetime,
+            principal,
+            hash,
+            definition_version
+        FROM job_version
+        WHERE job_id = $1
+        ORDER BY version DESC",
+    )
+    .bind(job_id)
+    .fetch_all(&req.get_pool())
+    .await?;
+
+    Ok(Json(versions))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct GetVersion {
+    version: i32,
+    created_datetime: DateTime<Utc>,
+    principal: Option<String>,
+    hash: String,
+    definition_version: Option<i32>,
+    raw_definition: String,
+}
+
+async fn fetch_version(
+    pool: &PgPool,
+    job_id: Uuid,
+    version: i32,
+) -> highnoon::Result<Option<GetVersion>> {
+    let row = sqlx::query_as(
+        "SELECT
+            v.version,
+            v.created_datetime,
+            v.principal,
+            v.hash,
+            v.definition_version,
+            COALESCE(v.raw_definition, d.raw_definition) AS raw_definition
+        FROM job_version v
+        LEFT JOIN job_version d
+            ON d.job_id = v.job_id
+            AND d.version = v.definition_version
+        WHERE v.job_id = $1
+        AND v.version = $2",
+    )
+    .bind(job_id)
+    .bind(version)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row)
+}
+
+pub async fn get_version(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id: Uuid = req.param("id")?.parse()?;
+    let version: i32 = req.param("version")?.parse()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    match fetch_version(&req.get_pool(), job_id, version).await? {
+        Some(version) => Json(version).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    against: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct VersionDiff {
+    from: Option<i32>,
+    to: i32,
+    patch: json_patch::Patch,
+}
+
+/// Diff a version against another version (by default the one before it) as a JSON patch
+pub async fn diff_version(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id: Uuid = req.param("id")?.parse()?;
+    let version: i32 = req.param("version")?.parse()?;
+    let DiffQuery { against } = req.query()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let pool = req.get_pool();
+
+    let Some(to) = fetch_version(&pool, job_id, version).await? else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let against = match against {
+        Some(against) => Some(against),
+        None => {
+            let row: Option<(i32,)> = sqlx::query_as(
+                "SELECT MAX(version)
+                FROM job_version
+                WHERE job_id = $1
+                AND version < $2
+                HAVING MAX(version) IS NOT NULL",
+            )
+            .bind(job_id)
+            .bind(version)
+            .fetch_optional(&pool)
+            .await?;
+
+            row.map(|(v,)| v)
+        }
+    };
+
+    let from = match against {
+        Some(against) => match fetch_version(&pool, job_id, against).await? {
+            Some(from) => Some(from),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        None => None,
+    };
+
+    let from_json = match &from {
+        Some(from) => serde_json::from_str(&from.raw_definition)?,
+        None => JsonValue::Null,
+    };
+    let to_json: JsonValue = serde_json::from_str(&to.raw_definition)?;
+
+    Json(VersionDiff {
+        from: from.map(|from| from.version),
+        to: to.version,
+        patch: json_patch::diff(&from_json, &to_json),
+    })
+    .into_response()
+}
+
+/// Re-apply a previous version of the job. This creates a new version with the old definition.
+pub async fn rollback(req: Request<State>) -> highnoon::Result<Response> {
+    let job_id: Uuid = req.param("id")?.parse()?;
+    let version: i32 = req.param("version")?.parse()?;
+
+    auth::get().job(job_id, None).check(&req).await?;
+
+    let Some(old) = fetch_version(&req.get_pool(), job_id, version).await? else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let job: Job = serde_json::from_str(&old.raw_definition)?;
+
+    // the old version is applied as it was rendered, so a job made from a template is detached
+    // from it
+    super::apply_job(&req, &job, None).await
+}
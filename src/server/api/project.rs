@@ -1,9 +1,13 @@
 use super::{State, auth, config_cache, request_ext::RequestExt};
 use crate::{
     messages::ConfigUpdate,
-    server::api::jwt,
+    server::api::{
+        job::defaults::{PROJECT_DEFAULTS_KEY, parse_project_defaults},
+        jwt,
+    },
     util::{is_pg_integrity_error, pg_error},
 };
+use chrono::{DateTime, Utc};
 use highnoon::{Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
@@ -25,6 +29,12 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<Response> {
 
     auth::update().project(id).check(&req).await?;
 
+    if let Err(err) = parse_project_defaults(proj.config.as_ref()) {
+        return Err(highnoon::Error::bad_request(format!(
+            "invalid {PROJECT_DEFAULTS_KEY} in project config: {err}"
+        )));
+    }
+
     let res = sqlx::query(
         "INSERT INTO project(id, name, description, config)
         VALUES($1, $2, $3, $4)
@@ -158,6 +168,7 @@ pub async fn get_by_id(req: Request<State>) -> highnoon::Result<Response> {
                 SELECT count(1)
                 FROM job j
                 WHERE j.project_id = $1
+                AND j.archived_datetime IS NULL
             ) AS num_jobs,
             (
                 SELECT COUNT(1)
@@ -262,6 +273,8 @@ struct ListJobQuery {
     limit: Option<i32>,
     after: Option<String>,
     name: Option<String>,
+    #[serde(default)]
+    archived: bool,
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -270,6 +283,7 @@ struct ListJob {
     name: String,
     description: String,
     paused: bool,
+    archived_datetime: Option<DateTime<Utc>>,
     success: i64,
     running: i64,
     failure: i64,
@@ -318,6 +332,7 @@ pub async fn list_jobs(req: Request<State>) -> highnoon::Result<impl Responder>
             name,
             description,
             paused,
+            archived_datetime,
             coalesce(success, 0) AS success,
             coalesce(running, 0) AS running,
             coalesce(failure, 0) AS failure,
@@ -328,6 +343,7 @@ pub async fn list_jobs(req: Request<State>) -> highnoon::Result<impl Responder>
         WHERE project_id = $1
         AND ($2 IS NULL OR name > $2)
         AND ($3 IS NULL OR name = $3)
+        AND ($5 OR archived_datetime IS NULL)
         ORDER BY name
         LIMIT $4",
     )
@@ -335,6 +351,7 @@ pub async fn list_jobs(req: Request<State>) -> highnoon::Result<impl Responder>
     .bind(query.after.as_ref())
     .bind(query.name.as_ref())
     .bind(query.limit.unwrap_or(50))
+    .bind(query.archived)
     .fetch_all(&req.get_pool())
     .await?;
 
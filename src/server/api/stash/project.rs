@@ -1,4 +1,8 @@
-use crate::server::api::{State, auth, request_ext::RequestExt};
+use crate::{
+    messages::{ProcessToken, TaskPriority},
+    server::api::{State, auth, job::fire_stash_triggers, request_ext::RequestExt, updates},
+};
+use chrono::{SubsecRound, Utc};
 use highnoon::{Json, Request, Responder, StatusCode};
 use tracing::info;
 use uuid::Uuid;
@@ -19,6 +23,9 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder>
         .await?;
 
     let db = req.get_pool();
+    let written_datetime = Utc::now().trunc_subsecs(0);
+
+    let mut txn = db.begin().await?;
 
     sqlx::query(
         "INSERT INTO project_stash(project_id, name, data)
@@ -30,11 +37,23 @@ pub async fn create(mut req: Request<State>) -> highnoon::Result<impl Responder>
     .bind(proj_id)
     .bind(key)
     .bind(&data)
-    .execute(&db)
+    .execute(txn.as_mut())
     .await?;
 
+    let tokens = fire_stash_triggers(&db, &mut txn, proj_id, key, written_datetime).await?;
+
+    txn.commit().await?;
+
     info!(project_id=?proj_id, key, "created project stash item");
 
+    for token in tokens {
+        updates::send_token_update(
+            req.get_channel(),
+            ProcessToken::Increment(token, TaskPriority::Normal),
+        )
+        .await?;
+    }
+
     Ok(StatusCode::CREATED)
 }
 
@@ -1,17 +1,102 @@
 use crate::{
-    messages::{ProcessToken, TaskDef, TaskPriority, Token},
-    server::api::{State, auth, jwt, request_ext::RequestExt, updates},
+    messages::{ProcessToken, SensorCondition, TaskDef, TaskPriority, Token, TokenState},
+    server::{
+        api::{State, auth, jwt, request_ext::RequestExt, updates},
+        progress::advance_tokens,
+    },
 };
 use chrono::{DateTime, Utc};
 use futures::TryStreamExt;
 use highnoon::{Json, Request, Responder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use sqlx::{Postgres, Transaction, types::Json as SqlJson};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tracing::info;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
 struct ActivateTokenParams {
     priority: Option<TaskPriority>,
+    #[serde(flatten)]
+    overrides: ActivateOverrides,
+}
+
+/// Overrides for a manual activation. These are recorded on the token, so they also apply to
+/// retries, and replace any overrides given to a previous activation.
+#[derive(Deserialize, Default)]
+pub struct ActivateOverrides {
+    /// `NAME=value` environment variables replacing those from the task definition
+    env: Option<Vec<String>>,
+    /// values to put in the job stash for the trigger datetime before the task runs
+    stash: Option<HashMap<String, String>>,
+}
+
+impl ActivateOverrides {
+    pub fn check(&self) -> Result<(), String> {
+        for var in self.env.iter().flatten() {
+            if !var.contains('=') {
+                return Err(format!(
+                    "invalid environment variable '{var}' (only KEY=VALUE syntax is supported)"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Record the overrides on a token and seed its job stash
+pub async fn set_overrides(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+    overrides: &ActivateOverrides,
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "UPDATE token
+        SET env_overrides = $3
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(&overrides.env)
+    .execute(txn.as_mut())
+    .await?;
+
+    for (name, data) in overrides.stash.iter().flatten() {
+        seed_stash(txn, token, name, data).await?;
+    }
+
+    Ok(())
+}
+
+/// Put a value in the job stash for the token's trigger datetime, leaving any environment
+/// overrides on the token as they are
+pub async fn seed_stash(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+    name: &str,
+    data: &str,
+) -> highnoon::Result<()> {
+    sqlx::query(
+        "INSERT INTO job_stash(job_id, trigger_datetime, name, data)
+        SELECT job_id, $2, $3, $4
+        FROM task
+        WHERE id = $1
+        ON CONFLICT (job_id, trigger_datetime, name)
+        DO UPDATE
+        SET data = $4",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(name)
+    .bind(data.as_bytes())
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
 }
 
 pub async fn activate_token(mut req: Request<State>) -> highnoon::Result<impl Responder> {
@@ -19,6 +104,10 @@ pub async fn activate_token(mut req: Request<State>) -> highnoon::Result<impl Re
     let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
     let params: ActivateTokenParams = req.body_json().await?;
 
+    if let Err(msg) = params.overrides.check() {
+        return Err(highnoon::Error::bad_request(msg));
+    }
+
     // TODO auth check
 
     let token = Token {
@@ -42,12 +131,15 @@ pub async fn activate_token(mut req: Request<State>) -> highnoon::Result<impl Re
     .execute(txn.as_mut())
     .await?;
 
+    set_overrides(&mut txn, &token, &params.overrides).await?;
+
+    // the executor reads the overrides, so they must be committed before it's told
+    txn.commit().await?;
+
     let priority = params.priority.unwrap_or(TaskPriority::High);
 
     updates::send_token_update(req.get_channel(), ProcessToken::Activate(token, priority)).await?;
 
-    txn.commit().await?;
-
     Ok(StatusCode::CREATED)
 }
 
@@ -57,6 +149,8 @@ struct ActivateMultipleTokensParams {
     first: Option<DateTime<Utc>>,
     last: Option<DateTime<Utc>>,
     only_failed: Option<bool>,
+    #[serde(flatten)]
+    overrides: ActivateOverrides,
 }
 
 #[derive(Serialize)]
@@ -76,12 +170,16 @@ pub async fn activate_multiple_tokens(mut req: Request<State>) -> highnoon::Resu
             .into_response();
     }
 
+    if let Err(msg) = params.overrides.check() {
+        return Err(highnoon::Error::bad_request(msg));
+    }
+
     // TODO auth check
 
     let pool = req.get_pool();
     let mut txn = pool.begin().await?;
 
-    let mut cursor = sqlx::query_as(
+    let trigger_datetimes: Vec<(DateTime<Utc>,)> = sqlx::query_as(
         "UPDATE token
          SET count = (SELECT threshold FROM task WHERE id = $1),
              state = 'waiting'
@@ -95,14 +193,201 @@ pub async fn activate_multiple_tokens(mut req: Request<State>) -> highnoon::Resu
     .bind(params.first)
     .bind(params.last)
     .bind(params.only_failed.unwrap_or(false))
-    .fetch(txn.as_mut());
+    .fetch_all(txn.as_mut())
+    .await?;
 
     let priority = params.priority.unwrap_or(TaskPriority::BackFill);
 
-    let mut count = 0u64;
-    while let Some((trigger_datetime,)) = cursor.try_next().await? {
-        count += 1;
+    let mut tokens = Vec::new();
+    for (trigger_datetime,) in trigger_datetimes {
+        let token = Token {
+            task_id,
+            trigger_datetime,
+        };
+
+        set_overrides(&mut txn, &token, &params.overrides).await?;
+        tokens.push(token);
+    }
+
+    // the executor reads the overrides, so they must be committed before it's told
+    txn.commit().await?;
+
+    let count = tokens.len() as u64;
+    for token in tokens {
+        updates::send_token_update(req.get_channel(), ProcessToken::Activate(token, priority))
+            .await?;
+    }
+
+    Json(ActivateTokenReply { cleared: count }).into_response()
+}
+
+#[derive(Deserialize)]
+struct ClearDownstreamParams {
+    priority: Option<TaskPriority>,
+    trigger_datetime: Option<DateTime<Utc>>,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ClearDownstreamReply {
+    activated: u64,
+    cleared: u64,
+}
+
+/// Clear a task and everything downstream of it (following task edges into other jobs too) and
+/// then activate the task again. The descendants will run again as the task completes.
+///
+/// Either a single `trigger_datetime` or an inclusive range of `first` and/or `last` must be given.
+pub async fn clear_downstream(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let params: ClearDownstreamParams = req.body_json().await?;
+
+    if params.trigger_datetime.is_some() == (params.first.is_some() || params.last.is_some()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "either 'trigger_datetime' or one or both of 'first' and 'last' must be specified",
+        )
+            .into_response();
+    }
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT job_id
+        FROM task
+        WHERE id = $1",
+    )
+    .bind(task_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id,)) = maybe_job else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let mut txn = pool.begin().await?;
+
+    let roots: Vec<DateTime<Utc>> = if let Some(trigger_datetime) = params.trigger_datetime {
+        vec![trigger_datetime]
+    } else {
+        sqlx::query_as(
+            "SELECT trigger_datetime
+            FROM token
+            WHERE task_id = $1
+            AND ($2 IS NULL OR trigger_datetime >= $2)
+            AND ($3 IS NULL OR trigger_datetime <= $3)
+            ORDER BY trigger_datetime",
+        )
+        .bind(task_id)
+        .bind(params.first)
+        .bind(params.last)
+        .fetch_all(txn.as_mut())
+        .await?
+        .into_iter()
+        .map(|(trigger_datetime,)| trigger_datetime)
+        .collect()
+    };
 
+    let mut cleared = Vec::new();
+    let mut cleared_jobs = HashSet::new();
+
+    for &trigger_datetime in &roots {
+        // walk the existing tokens downstream of the root and reset them to waiting.
+        // Each token keeps the count contributed by edges from outside the cleared set, so it
+        // activates again once its cleared parents have all re-run.
+        let mut cursor = sqlx::query_as::<_, (Uuid, DateTime<Utc>, Uuid)>(
+            "WITH RECURSIVE downstream(task_id, trigger_datetime) AS (
+                SELECT $1::UUID, $2::TIMESTAMP WITH TIME ZONE
+                UNION
+                SELECT e.child_task_id, k.trigger_datetime
+                FROM downstream d
+                JOIN task_edge e ON e.parent_task_id = d.task_id
+                JOIN token k ON k.task_id = e.child_task_id
+                    AND k.trigger_datetime = d.trigger_datetime
+                        + (INTERVAL '1 second' * COALESCE(e.edge_offset, 0))
+            ),
+            cleared AS (
+                SELECT
+                    d.task_id,
+                    d.trigger_datetime,
+                    (
+                        SELECT COUNT(*)
+                        FROM task_edge e
+                        JOIN downstream p ON p.task_id = e.parent_task_id
+                            AND p.trigger_datetime
+                                + (INTERVAL '1 second' * COALESCE(e.edge_offset, 0))
+                                = d.trigger_datetime
+                        WHERE e.child_task_id = d.task_id
+                    ) AS cleared_edges
+                FROM downstream d
+                WHERE NOT (d.task_id = $1 AND d.trigger_datetime = $2)
+            )
+            UPDATE token k
+            SET count = GREATEST(t.threshold - c.cleared_edges, 0),
+                state = 'waiting',
+                env_overrides = NULL
+            FROM cleared c
+            JOIN task t ON t.id = c.task_id
+            WHERE k.task_id = c.task_id
+            AND k.trigger_datetime = c.trigger_datetime
+            RETURNING k.task_id, k.trigger_datetime, t.job_id",
+        )
+        .bind(task_id)
+        .bind(trigger_datetime)
+        .fetch(txn.as_mut());
+
+        while let Some((task_id, trigger_datetime, job_id)) = cursor.try_next().await? {
+            cleared.push(Token {
+                task_id,
+                trigger_datetime,
+            });
+            cleared_jobs.insert(job_id);
+        }
+
+        drop(cursor);
+
+        // overrides from an earlier manual activation don't apply to the rerun
+        sqlx::query(
+            "INSERT INTO token(task_id, trigger_datetime, count, state)
+                VALUES ($1, $2, (SELECT threshold FROM task WHERE id = $1), 'waiting')
+                ON CONFLICT(task_id, trigger_datetime)
+                DO UPDATE
+                SET count = (SELECT threshold FROM task WHERE id = $1),
+                    state = 'waiting',
+                    env_overrides = NULL",
+        )
+        .bind(task_id)
+        .bind(trigger_datetime)
+        .execute(txn.as_mut())
+        .await?;
+    }
+
+    // clearing tokens in other jobs needs permission on those jobs too
+    cleared_jobs.remove(&job_id);
+    for other_job_id in cleared_jobs {
+        auth::update()
+            .job(other_job_id, None)
+            .kind("task")
+            .check(&req)
+            .await?;
+    }
+
+    txn.commit().await?;
+
+    for token in &cleared {
+        updates::send_token_update(req.get_channel(), ProcessToken::Clear(token.clone())).await?;
+    }
+
+    let priority = params.priority.unwrap_or(TaskPriority::High);
+
+    for &trigger_datetime in &roots {
         let token = Token {
             task_id,
             trigger_datetime,
@@ -112,11 +397,154 @@ pub async fn activate_multiple_tokens(mut req: Request<State>) -> highnoon::Resu
             .await?;
     }
 
-    drop(cursor);
+    Json(ClearDownstreamReply {
+        activated: roots.len() as u64,
+        cleared: cleared.len() as u64,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct MarkTokenParams {
+    state: TokenState,
+    priority: Option<TaskPriority>,
+}
+
+/// Mark a task as succeeded or failed without running it.
+///
+/// A manual task run is recorded and downstream tasks are advanced exactly as if the task had
+/// run. Any run still in progress is abandoned and pending retries are cancelled.
+pub async fn mark_token(mut req: Request<State>) -> highnoon::Result<impl Responder> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+    let params: MarkTokenParams = req.body_json().await?;
+
+    if !matches!(params.state, TokenState::Success | TokenState::Failure) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "a task can only be marked as 'success' or 'failure'",
+        )
+            .into_response();
+    }
+
+    let pool = req.get_pool();
+
+    let maybe_job: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT job_id
+        FROM task
+        WHERE id = $1",
+    )
+    .bind(task_id)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some((job_id,)) = maybe_job else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::update()
+        .job(job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    let principal = auth::principal_name(&req);
+    let priority = params.priority.unwrap_or_default();
+
+    let token = Token {
+        task_id,
+        trigger_datetime,
+    };
+
+    let mut txn = pool.begin().await?;
+
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+            VALUES ($1, $2, 0, $3)
+            ON CONFLICT(task_id, trigger_datetime)
+            DO UPDATE
+            SET count = 0,
+                state = $3",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(params.state)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM retry
+        WHERE task_run_id IN (
+            SELECT id
+            FROM task_run
+            WHERE task_id = $1
+            AND trigger_datetime = $2
+        )",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "UPDATE task_run
+        SET state = $3,
+            finish_datetime = CURRENT_TIMESTAMP
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        AND state IN ($4, $5)",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(TokenState::Error)
+    .bind(TokenState::Active)
+    .bind(TokenState::Running)
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO task_run(id, task_id, trigger_datetime,
+            queued_datetime, started_datetime, finish_datetime,
+            updated_datetime,
+            worker_id, state, priority, attempt,
+            manual, principal)
+        VALUES ($1, $2, $3,
+            $4, $4, $4,
+            $4,
+            NULL, $5, $6, (
+                SELECT COALESCE(MAX(attempt), 0) + 1
+                FROM task_run
+                WHERE task_id = $2
+                AND trigger_datetime = $3
+            ),
+            TRUE, $7)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(Utc::now())
+    .bind(params.state)
+    .bind(priority)
+    .bind(&principal)
+    .execute(txn.as_mut())
+    .await?;
+
+    let tokens_to_tx = advance_tokens(&pool, &mut txn, &token, params.state).await?;
 
     txn.commit().await?;
 
-    Json(ActivateTokenReply { cleared: count }).into_response()
+    info!(task_id=?token.task_id,
+        trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+        state=params.state.as_ref(),
+        ?principal,
+        "task marked manually");
+
+    for token in tokens_to_tx {
+        updates::send_token_update(req.get_channel(), ProcessToken::Increment(token, priority))
+            .await?;
+    }
+
+    StatusCode::NO_CONTENT.into_response()
 }
 
 pub async fn get_task_def(req: Request<State>) -> highnoon::Result<Response> {
@@ -156,6 +584,7 @@ struct DbTaskDef {
     pub env: Option<Vec<String>>,
     pub paused: bool,
     pub timeout_secs: Option<i64>,
+    pub sensor: Option<SqlJson<SensorCondition>>,
 }
 
 impl From<DbTaskDef> for TaskDef {
@@ -174,6 +603,7 @@ impl From<DbTaskDef> for TaskDef {
             timeout: other
                 .timeout_secs
                 .map(|secs| Duration::from_secs(secs as u64)),
+            sensor: other.sensor.map(|sensor| sensor.0),
         }
     }
 }
@@ -193,7 +623,8 @@ async fn get_task_def_common(req: &Request<State>) -> highnoon::Result<Option<Ta
                 COALESCE(args, ARRAY[]::VARCHAR[]) AS args,
                 env,
                 j.paused,
-                t.timeout_secs
+                t.timeout_secs,
+                t.sensor
             FROM task t
             JOIN job j on t.job_id = j.id
             JOIN project p ON j.project_id = p.id
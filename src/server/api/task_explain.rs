@@ -0,0 +1,604 @@
+use crate::{
+    messages::{TaskPriority, TokenState},
+    server::api::{State, auth, request_ext::RequestExt},
+};
+use chrono::{DateTime, Duration, Utc};
+use highnoon::{Json, Request, Responder, Response, StatusCode};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExplainTask {
+    task_id: Uuid,
+    task_name: String,
+    job_id: Uuid,
+    job_name: String,
+    project_name: String,
+    job_paused: bool,
+    threshold: i32,
+    token_count: Option<i32>,
+    token_state: Option<TokenState>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExplainUpstream {
+    kind: String,
+    id: Uuid,
+    name: String,
+    job_name: String,
+    project_name: String,
+    edge_kind: String,
+    edge_offset: Option<i64>,
+    branch: Option<String>,
+    upstream_datetime: DateTime<Utc>,
+    upstream_state: Option<TokenState>,
+    contributed: bool,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExplainRetry {
+    task_run_id: Uuid,
+    retry_at_datetime: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ExplainTaskRun {
+    task_run_id: Uuid,
+    attempt: i64,
+    state: Option<TokenState>,
+    priority: TaskPriority,
+    queued_datetime: DateTime<Utc>,
+    started_datetime: Option<DateTime<Utc>>,
+    finish_datetime: Option<DateTime<Utc>>,
+    updated_datetime: Option<DateTime<Utc>>,
+    worker_id: Option<Uuid>,
+}
+
+/// Task slots on the workers that are up. Runs wait in the queue while every slot is busy.
+#[derive(Serialize, sqlx::FromRow)]
+struct ExplainCapacity {
+    live_workers: i64,
+    running_tasks: i64,
+    /// unknown if any worker doesn't report its limit
+    max_tasks: Option<i64>,
+    queued_runs: i64,
+}
+
+#[derive(Serialize)]
+struct Explain {
+    trigger_datetime: DateTime<Utc>,
+    #[serde(flatten)]
+    task: ExplainTask,
+    upstream: Vec<ExplainUpstream>,
+    pending_retries: Vec<ExplainRetry>,
+    latest_run: Option<ExplainTaskRun>,
+    capacity: ExplainCapacity,
+    reasons: Vec<String>,
+}
+
+/// Explain the state of a task's token, ie. why it has (or hasn't) run
+pub async fn explain(req: Request<State>) -> highnoon::Result<Response> {
+    let task_id = req.param("id")?.parse::<Uuid>()?;
+    let trigger_datetime = req.param("trigger_datetime")?.parse::<DateTime<Utc>>()?;
+
+    let pool = req.get_pool();
+
+    let maybe_task: Option<ExplainTask> = sqlx::query_as(
+        "SELECT
+            t.id AS task_id,
+            t.name AS task_name,
+            j.id AS job_id,
+            j.name AS job_name,
+            p.name AS project_name,
+            COALESCE(j.paused, FALSE) AS job_paused,
+            COALESCE(t.threshold, 0) AS threshold,
+            k.count AS token_count,
+            k.state AS token_state
+        FROM task t
+        JOIN job j ON j.id = t.job_id
+        JOIN project p ON p.id = j.project_id
+        LEFT JOIN token k ON k.task_id = t.id
+            AND k.trigger_datetime = $2
+        WHERE t.id = $1",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_optional(&pool)
+    .await?;
+
+    let Some(task) = maybe_task else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    auth::get()
+        .job(task.job_id, None)
+        .kind("task")
+        .check(&req)
+        .await?;
+
+    // an upstream task contributes a token when it finishes in the state matching the edge kind,
+    // a trigger contributes when it has fired for the upstream datetime
+    let upstream: Vec<ExplainUpstream> = sqlx::query_as(
+        "SELECT
+            'task' AS kind,
+            pt.id,
+            pt.name,
+            j.name AS job_name,
+            p.name AS project_name,
+            e.kind AS edge_kind,
+            e.edge_offset,
+            e.branch,
+            $2 - (INTERVAL '1 second' * COALESCE(e.edge_offset, 0)) AS upstream_datetime,
+            k.state AS upstream_state,
+            COALESCE(k.state = e.kind, FALSE) AS contributed
+        FROM task_edge e
+        JOIN task pt ON pt.id = e.parent_task_id
+        JOIN job j ON j.id = pt.job_id
+        JOIN project p ON p.id = j.project_id
+        LEFT JOIN token k ON k.task_id = pt.id
+            AND k.trigger_datetime = $2 - (INTERVAL '1 second' * COALESCE(e.edge_offset, 0))
+        WHERE e.child_task_id = $1
+        UNION ALL
+        SELECT
+            'trigger' AS kind,
+            tr.id,
+            tr.name,
+            j.name AS job_name,
+            p.name AS project_name,
+            'success' AS edge_kind,
+            e.edge_offset,
+            NULL AS branch,
+            $2 - (INTERVAL '1 second' * COALESCE(e.edge_offset, 0)) AS upstream_datetime,
+            NULL AS upstream_state,
+            COALESCE(
+                $2 - (INTERVAL '1 second' * COALESCE(e.edge_offset, 0))
+                    BETWEEN tr.earliest_trigger_datetime AND tr.latest_trigger_datetime,
+                FALSE
+            ) AS contributed
+        FROM trigger_edge e
+        JOIN trigger tr ON tr.id = e.trigger_id
+        JOIN job j ON j.id = tr.job_id
+        JOIN project p ON p.id = j.project_id
+        WHERE e.task_id = $1
+        ORDER BY kind, project_name, job_name, name",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_all(&pool)
+    .await?;
+
+    let pending_retries: Vec<ExplainRetry> = sqlx::query_as(
+        "SELECT
+            r.task_run_id,
+            r.retry_at_datetime
+        FROM retry r
+        JOIN task_run tr ON tr.id = r.task_run_id
+        WHERE tr.task_id = $1
+        AND tr.trigger_datetime = $2
+        ORDER BY r.retry_at_datetime",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_all(&pool)
+    .await?;
+
+    let latest_run: Option<ExplainTaskRun> = sqlx::query_as(
+        "SELECT
+            id AS task_run_id,
+            attempt,
+            state,
+            priority,
+            queued_datetime,
+            started_datetime,
+            finish_datetime,
+            updated_datetime,
+            worker_id
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        ORDER BY queued_datetime DESC
+        LIMIT 1",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_optional(&pool)
+    .await?;
+
+    // workers are considered up on the same terms as the workers API
+    let capacity: ExplainCapacity = sqlx::query_as(
+        "SELECT
+            COUNT(*) AS live_workers,
+            COALESCE(SUM(running_tasks), 0) AS running_tasks,
+            CASE
+                WHEN COUNT(max_tasks) = COUNT(*) THEN SUM(max_tasks)
+            END AS max_tasks,
+            (
+                SELECT COUNT(*)
+                FROM task_run
+                WHERE state = $1
+            ) AS queued_runs
+        FROM worker
+        WHERE CURRENT_TIMESTAMP - last_seen_datetime <= INTERVAL '15 minutes'",
+    )
+    .bind(TokenState::Active)
+    .fetch_one(&pool)
+    .await?;
+
+    let reasons = explain_reasons(
+        &task,
+        &upstream,
+        &pending_retries,
+        latest_run.as_ref(),
+        &capacity,
+    );
+
+    Json(Explain {
+        trigger_datetime,
+        task,
+        upstream,
+        pending_retries,
+        latest_run,
+        capacity,
+        reasons,
+    })
+    .into_response()
+}
+
+/// Summarise the state of the token in plain words
+fn explain_reasons(
+    task: &ExplainTask,
+    upstream: &[ExplainUpstream],
+    pending_retries: &[ExplainRetry],
+    latest_run: Option<&ExplainTaskRun>,
+    capacity: &ExplainCapacity,
+) -> Vec<String> {
+    let mut reasons = Vec::new();
+
+    if task.job_paused {
+        reasons.push("the job is paused, no tasks will be started until it is unpaused".to_owned());
+    }
+
+    match task.token_state {
+        None => reasons.push(format!(
+            "no upstream task or trigger has contributed a token yet (threshold is {})",
+            task.threshold
+        )),
+        Some(TokenState::Waiting) => {
+            let count = task.token_count.unwrap_or(0);
+            if count >= task.threshold {
+                reasons.push(format!(
+                    "token count {count} has reached the threshold {} and the task is waiting \
+                    to be scheduled",
+                    task.threshold
+                ));
+            } else {
+                reasons.push(format!(
+                    "token count {count} has not reached the threshold {}",
+                    task.threshold
+                ));
+            }
+        }
+        Some(TokenState::Skipped) => {
+            reasons.push(
+                "the task was skipped because it is on a branch that wasn't chosen".to_owned(),
+            );
+        }
+        Some(TokenState::Sensing) => {
+            reasons.push("the task is a sensor and its condition has not held yet".to_owned());
+        }
+        Some(state) => reasons.push(format!("the task is {}", state.as_ref())),
+    }
+
+    for up in upstream.iter().filter(|up| !up.contributed) {
+        let waiting_for = match (up.kind.as_str(), up.upstream_state) {
+            ("trigger", _) => "has not fired".to_owned(),
+            (_, None) => "has no token".to_owned(),
+            (_, Some(state)) => format!("is {}", state.as_ref()),
+        };
+
+        reasons.push(format!(
+            "{} {}/{}/{} at {} {} (needs {}{})",
+            up.kind,
+            up.project_name,
+            up.job_name,
+            up.name,
+            up.upstream_datetime.to_rfc3339(),
+            waiting_for,
+            up.edge_kind,
+            offset_suffix(up.edge_offset),
+        ));
+    }
+
+    // sensors are poked again through the retry queue
+    let pending = if task.token_state == Some(TokenState::Sensing) {
+        "the next poke"
+    } else {
+        "a retry"
+    };
+    for retry in pending_retries {
+        reasons.push(format!(
+            "{pending} is pending at {}",
+            retry.retry_at_datetime.to_rfc3339()
+        ));
+    }
+
+    if let Some(run) = latest_run
+        && matches!(run.state, Some(TokenState::Active))
+    {
+        let queued = run.queued_datetime.to_rfc3339();
+        match capacity.max_tasks {
+            _ if capacity.live_workers == 0 => reasons.push(format!(
+                "the latest run was queued at {queued} and no workers are up to run it"
+            )),
+            Some(max_tasks) if capacity.running_tasks >= max_tasks => reasons.push(format!(
+                "the latest run was queued at {queued} and every worker is busy \
+                ({} of {max_tasks} task slots in use, {} runs queued)",
+                capacity.running_tasks, capacity.queued_runs
+            )),
+            _ => reasons.push(format!(
+                "the latest run was queued at {queued} and no worker has picked it up yet"
+            )),
+        }
+    }
+
+    reasons
+}
+
+fn offset_suffix(edge_offset: Option<i64>) -> String {
+    match edge_offset {
+        None | Some(0) => String::new(),
+        Some(secs) => {
+            let sign = if secs < 0 { "-" } else { "" };
+            let duration = Duration::seconds(secs).abs().to_std().unwrap_or_default();
+            format!(", offset {sign}{}", humantime::format_duration(duration))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn task(state: Option<TokenState>, count: Option<i32>) -> ExplainTask {
+        ExplainTask {
+            task_id: Uuid::nil(),
+            task_name: "task".to_owned(),
+            job_id: Uuid::nil(),
+            job_name: "job".to_owned(),
+            project_name: "project".to_owned(),
+            job_paused: false,
+            threshold: 2,
+            token_count: count,
+            token_state: state,
+        }
+    }
+
+    fn datetime(s: &str) -> DateTime<Utc> {
+        s.parse().expect("invalid datetime")
+    }
+
+    fn upstream(
+        kind: &str,
+        edge_offset: Option<i64>,
+        upstream_state: Option<TokenState>,
+        contributed: bool,
+    ) -> ExplainUpstream {
+        ExplainUpstream {
+            kind: kind.to_owned(),
+            id: Uuid::nil(),
+            name: "up".to_owned(),
+            job_name: "job".to_owned(),
+            project_name: "project".to_owned(),
+            edge_kind: "success".to_owned(),
+            edge_offset,
+            branch: None,
+            upstream_datetime: datetime("2024-01-01T00:00:00Z"),
+            upstream_state,
+            contributed,
+        }
+    }
+
+    fn queued_run() -> ExplainTaskRun {
+        ExplainTaskRun {
+            task_run_id: Uuid::nil(),
+            attempt: 1,
+            state: Some(TokenState::Active),
+            priority: TaskPriority::Normal,
+            queued_datetime: datetime("2024-01-02T00:00:00Z"),
+            started_datetime: None,
+            finish_datetime: None,
+            updated_datetime: None,
+            worker_id: None,
+        }
+    }
+
+    fn capacity(live_workers: i64, running_tasks: i64, max_tasks: Option<i64>) -> ExplainCapacity {
+        ExplainCapacity {
+            live_workers,
+            running_tasks,
+            max_tasks,
+            queued_runs: 3,
+        }
+    }
+
+    fn reasons_for_task(task: &ExplainTask) -> Vec<String> {
+        explain_reasons(task, &[], &[], None, &capacity(1, 0, Some(8)))
+    }
+
+    #[test]
+    fn test_job_paused() {
+        let mut task = task(Some(TokenState::Success), Some(2));
+        task.job_paused = true;
+
+        assert_eq!(
+            reasons_for_task(&task),
+            vec![
+                "the job is paused, no tasks will be started until it is unpaused",
+                "the task is success",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_states() {
+        assert_eq!(
+            reasons_for_task(&task(None, None)),
+            vec!["no upstream task or trigger has contributed a token yet (threshold is 2)"]
+        );
+        assert_eq!(
+            reasons_for_task(&task(Some(TokenState::Waiting), Some(1))),
+            vec!["token count 1 has not reached the threshold 2"]
+        );
+        assert_eq!(
+            reasons_for_task(&task(Some(TokenState::Waiting), Some(2))),
+            vec![
+                "token count 2 has reached the threshold 2 and the task is waiting to be scheduled"
+            ]
+        );
+        assert_eq!(
+            reasons_for_task(&task(Some(TokenState::Skipped), Some(0))),
+            vec!["the task was skipped because it is on a branch that wasn't chosen"]
+        );
+        assert_eq!(
+            reasons_for_task(&task(Some(TokenState::Sensing), Some(2))),
+            vec!["the task is a sensor and its condition has not held yet"]
+        );
+        assert_eq!(
+            reasons_for_task(&task(Some(TokenState::Failure), Some(2))),
+            vec!["the task is failure"]
+        );
+    }
+
+    #[test]
+    fn test_upstream_not_contributed() {
+        let upstream = [
+            upstream("task", None, Some(TokenState::Success), true),
+            upstream("task", None, None, false),
+            upstream("task", Some(-3600), Some(TokenState::Running), false),
+            upstream("trigger", Some(86400), None, false),
+        ];
+
+        let reasons = explain_reasons(
+            &task(Some(TokenState::Waiting), Some(1)),
+            &upstream,
+            &[],
+            None,
+            &capacity(1, 0, Some(8)),
+        );
+
+        assert_eq!(
+            reasons,
+            vec![
+                "token count 1 has not reached the threshold 2",
+                "task project/job/up at 2024-01-01T00:00:00+00:00 has no token (needs success)",
+                "task project/job/up at 2024-01-01T00:00:00+00:00 is running \
+                (needs success, offset -1h)",
+                "trigger project/job/up at 2024-01-01T00:00:00+00:00 has not fired \
+                (needs success, offset 1day)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_retries() {
+        let retries = [ExplainRetry {
+            task_run_id: Uuid::nil(),
+            retry_at_datetime: datetime("2024-01-03T00:00:00Z"),
+        }];
+
+        let reasons = explain_reasons(
+            &task(Some(TokenState::Retry), Some(2)),
+            &[],
+            &retries,
+            None,
+            &capacity(1, 0, Some(8)),
+        );
+        assert_eq!(
+            reasons,
+            vec![
+                "the task is retry",
+                "a retry is pending at 2024-01-03T00:00:00+00:00",
+            ]
+        );
+
+        let reasons = explain_reasons(
+            &task(Some(TokenState::Sensing), Some(2)),
+            &[],
+            &retries,
+            None,
+            &capacity(1, 0, Some(8)),
+        );
+        assert_eq!(
+            reasons,
+            vec![
+                "the task is a sensor and its condition has not held yet",
+                "the next poke is pending at 2024-01-03T00:00:00+00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queued_run() {
+        let task = task(Some(TokenState::Active), Some(2));
+        let run = queued_run();
+
+        let reasons = explain_reasons(&task, &[], &[], Some(&run), &capacity(2, 3, Some(16)));
+        assert_eq!(
+            reasons,
+            vec![
+                "the task is active",
+                "the latest run was queued at 2024-01-02T00:00:00+00:00 and no worker has \
+                picked it up yet",
+            ]
+        );
+
+        // a finished run isn't waiting on anything
+        let mut finished = queued_run();
+        finished.state = Some(TokenState::Success);
+        let reasons = explain_reasons(&task, &[], &[], Some(&finished), &capacity(0, 0, None));
+        assert_eq!(reasons, vec!["the task is active"]);
+    }
+
+    #[test]
+    fn test_queued_run_without_workers() {
+        let reasons = explain_reasons(
+            &task(Some(TokenState::Active), Some(2)),
+            &[],
+            &[],
+            Some(&queued_run()),
+            &capacity(0, 0, None),
+        );
+        assert_eq!(
+            reasons,
+            vec![
+                "the task is active",
+                "the latest run was queued at 2024-01-02T00:00:00+00:00 and no workers are up \
+                to run it",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queued_run_with_busy_workers() {
+        let task = task(Some(TokenState::Active), Some(2));
+        let run = queued_run();
+
+        let reasons = explain_reasons(&task, &[], &[], Some(&run), &capacity(2, 16, Some(16)));
+        assert_eq!(
+            reasons,
+            vec![
+                "the task is active",
+                "the latest run was queued at 2024-01-02T00:00:00+00:00 and every worker is busy \
+                (16 of 16 task slots in use, 3 runs queued)",
+            ]
+        );
+
+        // without every worker's limit the slots can't be counted
+        let reasons = explain_reasons(&task, &[], &[], Some(&run), &capacity(2, 16, None));
+        assert_eq!(
+            reasons[1],
+            "the latest run was queued at 2024-01-02T00:00:00+00:00 and no worker has picked \
+            it up yet"
+        );
+    }
+}
@@ -1,7 +1,10 @@
-use chrono::{DateTime, Utc};
+use crate::messages::SensorCondition;
+use chrono::{DateTime, NaiveDate, Utc};
 /// API Types - used to parse the YAML file.
 /// These get converted into internal types
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 pub fn duration_from_string(period: Option<&str>) -> anyhow::Result<Option<i32>> {
@@ -29,15 +32,15 @@ pub struct Job {
     pub name: String,
     pub description: String,
     pub paused: Option<bool>,
+    pub defaults: Option<TaskDefaults>,
     pub triggers: Vec<Trigger>,
     pub tasks: Vec<Task>,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize, sqlx::Type)]
+#[derive(Copy, Clone, Debug, PartialEq, Default, Deserialize, Serialize, sqlx::Type)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
 #[sqlx(type_name = "VARCHAR")]
-#[derive(Default)]
 pub enum Catchup {
     None,
     #[default]
@@ -46,39 +49,234 @@ pub enum Catchup {
     Random,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Default, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+#[sqlx(type_name = "VARCHAR")]
+pub enum TriggerKind {
+    /// fires on a schedule of a period or cron expression
+    #[default]
+    Time,
+    /// fires when an event is posted to the API
+    Event,
+    /// fires for each message published to an AMQP exchange
+    Amqp,
+    /// fires when a project stash key is written
+    Stash,
+}
+
+/// Job stash key an event trigger's payload is written into
+pub const EVENT_STASH_KEY: &str = "event";
+
+/// Where an `amqp` trigger's messages come from, and how their trigger datetime is found.
+/// Without a header or pointer the message timestamp is used, or else the time it's received.
+#[derive(Deserialize, Serialize)]
+pub struct AmqpSource {
+    pub exchange: String,
+    pub routing_key: Option<String>,
+    /// message header holding the trigger datetime
+    pub datetime_header: Option<String>,
+    /// JSON pointer to the trigger datetime in the message body
+    pub datetime_pointer: Option<String>,
+}
+
+/// A project stash key which fires a trigger each time it's written, with the write time as the
+/// trigger datetime
+#[derive(Deserialize, Serialize)]
+pub struct StashSource {
+    /// defaults to the job's own project
+    pub project: Option<String>,
+    pub key: String,
+}
+
+/// Either just the catchup mode, or the mode with limits on how many missed periods are run
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum CatchupSetting {
+    Mode(Catchup),
+    Bounded(BoundedCatchup),
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BoundedCatchup {
+    #[serde(default)]
+    pub mode: Catchup,
+    /// only the most recent missed periods are run, older ones are skipped
+    pub max_runs: Option<i32>,
+    /// only missed periods within this duration of now are run, older ones are skipped
+    pub max_age: Option<String>,
+}
+
+impl CatchupSetting {
+    pub fn mode(&self) -> Catchup {
+        match self {
+            CatchupSetting::Mode(mode) => *mode,
+            CatchupSetting::Bounded(bounded) => bounded.mode,
+        }
+    }
+
+    pub fn max_runs(&self) -> Option<i32> {
+        match self {
+            CatchupSetting::Mode(_) => None,
+            CatchupSetting::Bounded(bounded) => bounded.max_runs,
+        }
+    }
+
+    pub fn max_age(&self) -> Option<&str> {
+        match self {
+            CatchupSetting::Mode(_) => None,
+            CatchupSetting::Bounded(bounded) => bounded.max_age.as_deref(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Trigger {
     pub name: String,
-    pub start: DateTime<Utc>,
+    #[serde(default)]
+    pub kind: TriggerKind,
+    /// required for time triggers
+    pub start: Option<DateTime<Utc>>,
     pub end: Option<DateTime<Utc>>,
     pub period: Option<String>,
     pub cron: Option<String>,
     pub offset: Option<String>,
-    pub catchup: Option<Catchup>,
+    pub catchup: Option<CatchupSetting>,
+    /// names of calendars in the project, the trigger doesn't fire on their dates
+    pub calendars: Option<Vec<String>>,
+    /// required for amqp triggers
+    pub amqp: Option<AmqpSource>,
+    /// required for stash triggers
+    pub stash: Option<StashSource>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct Docker {
-    pub image: String,
+    /// may be left out if the job or project defaults provide an image
+    pub image: Option<String>,
     pub args: Vec<String>,
     pub env: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Retry {
     pub max_attempts: i32,
     pub delay: Option<String>,
 }
 
+/// Settings used for any task in the job which doesn't set them itself.
+/// Also read from the `task_defaults` key of the project config.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct TaskDefaults {
+    pub docker: Option<DockerDefaults>,
+    pub retry: Option<Retry>,
+    pub timeout: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct DockerDefaults {
+    pub image: Option<String>,
+    /// merged with the task's env, the task's value wins for a variable set in both
+    pub env: Option<Vec<String>>,
+}
+
+/// Stash key read when a task declares branches but doesn't name its own key
+pub const DEFAULT_BRANCH_STASH_KEY: &str = "branch";
+
+#[derive(Deserialize, Serialize)]
+pub struct Branches {
+    /// the branches downstream tasks may depend on (eg. `task/check#load`)
+    pub names: Vec<String>,
+    /// job stash key the task writes the chosen branch name into
+    pub stash_key: Option<String>,
+    /// exit codes of the container which choose a branch, these count as success
+    pub exit_codes: Option<BTreeMap<i64, String>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Sensor {
+    #[serde(flatten)]
+    pub condition: SensorCondition,
+    /// how long to wait between checks of the condition
+    pub poke_interval: Option<String>,
+    /// how long to keep checking before the task times out
+    pub timeout: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Map {
+    /// job stash key holding the JSON list to run the task over
+    pub stash_key: String,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Task {
     pub name: String,
     pub docker: Option<Docker>,
     pub depends: Option<Vec<String>>,
     pub depends_failure: Option<Vec<String>>, // TODO - better name for this?
+    /// dependencies allowed to close a cycle, each must have an offset
+    pub depends_cyclic: Option<Vec<String>>,
     pub threshold: Option<i32>,
     pub retry: Option<Retry>,
     pub timeout: Option<String>,
+    pub branches: Option<Branches>,
+    pub map: Option<Map>,
+    /// poll for a condition instead of running a docker image
+    pub sensor: Option<Sensor>,
+}
+
+/// A list of dates (in UTC) when triggers using the calendar don't fire
+#[derive(Deserialize, Serialize)]
+pub struct Calendar {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub dates: Vec<NaiveDate>,
+}
+
+/// A reusable job definition, instantiated by jobs declared with `template` and `params`
+#[derive(Deserialize, Serialize)]
+pub struct JobTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    /// the job definition, without `uuid`, `project` and `name`. Strings may contain
+    /// `{{param}}` placeholders
+    pub job: JsonValue,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Parameter {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ParameterType,
+    pub default: Option<JsonValue>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+/// A job declared as an instance of a template in the same project
+#[derive(Deserialize, Serialize)]
+pub struct TemplateInstance {
+    pub uuid: Uuid,
+    pub project: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub paused: Option<bool>,
+    pub template: String,
+    #[serde(default)]
+    pub params: serde_json::Map<String, JsonValue>,
 }
 
 #[cfg(test)]
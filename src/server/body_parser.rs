@@ -29,7 +29,37 @@ pub async fn read_from_body<T: DeserializeOwned>(
         });
     }
 
-    Err(highnoon::Error::http((
+    Err(unsupported_media_type())
+}
+
+/// Read a list of items from the body - either a JSON array or a YAML stream with one
+/// item per document
+pub async fn read_list_from_body<T: DeserializeOwned>(
+    req: &mut highnoon::Request<State>,
+) -> highnoon::Result<Vec<T>> {
+    let content_type = req
+        .header::<ContentType>()
+        .unwrap_or_else(ContentType::json);
+    let reader = req.reader().await?;
+
+    if content_type == ContentType::json() {
+        return serde_json::from_reader(reader).map_err(|err| {
+            highnoon::Error::bad_request(format!("error parsing request body as json: {err}"))
+        });
+    } else if content_type_is_yaml(&content_type) {
+        return serde_yaml::Deserializer::from_reader(reader)
+            .map(T::deserialize)
+            .collect::<Result<_, _>>()
+            .map_err(|err| {
+                highnoon::Error::bad_request(format!("error parsing request body as yaml: {err}"))
+            });
+    }
+
+    Err(unsupported_media_type())
+}
+
+fn unsupported_media_type() -> highnoon::Error {
+    highnoon::Error::http((
         highnoon::StatusCode::UNSUPPORTED_MEDIA_TYPE,
         format!(
             "Unsupported media type.\n\
@@ -38,5 +68,5 @@ pub async fn read_from_body<T: DeserializeOwned>(
             SUPPORTED_MIMES.join("\n"),
             YAML_MIMES.join("\n"),
         ),
-    )))
+    ))
 }
@@ -1,6 +1,6 @@
 use crate::{
-    messages::{TaskPriority, TaskRequest, Token},
-    server::Server,
+    messages::{MapInstance, ProcessToken, TaskPriority, TaskRequest, Token, TokenState},
+    server::{Server, progress::advance_tokens},
 };
 use anyhow::Result;
 use cadence::CountedExt;
@@ -11,9 +11,9 @@ use lapin::{
     types::FieldTable,
 };
 use postage::prelude::*;
-use sqlx::Connection;
+use sqlx::{Connection, PgPool, Postgres, Transaction};
 use std::sync::Arc;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 const TASK_EXCHANGE: &str = "waterwheel.tasks";
@@ -26,6 +26,36 @@ pub struct ExecuteToken {
     pub token: Token,
     pub priority: TaskPriority,
     pub attempt: u32,
+    /// set when re-executing a single instance of a mapped task
+    pub map: Option<MapInstance>,
+    /// set when poking a sensor again, which reuses the sensor's task run
+    pub task_run_id: Option<Uuid>,
+}
+
+/// Columns of a `task_run` recording which instance of a mapped task it ran
+#[derive(sqlx::FromRow, Debug)]
+pub struct MapColumns {
+    pub map_batch_id: Option<Uuid>,
+    pub map_index: Option<i32>,
+    pub map_count: Option<i32>,
+    pub map_item: Option<String>,
+}
+
+impl MapColumns {
+    pub fn into_instance(self) -> Option<MapInstance> {
+        Some(MapInstance {
+            batch_id: self.map_batch_id?,
+            index: self.map_index?,
+            count: self.map_count?,
+            item: self.map_item?,
+        })
+    }
+}
+
+enum MapItems {
+    NotMapped,
+    Items(Vec<String>),
+    Invalid(String),
 }
 
 pub async fn process_executions(server: Arc<Server>) -> Result<!> {
@@ -79,6 +109,8 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
             token,
             priority,
             attempt,
+            map,
+            task_run_id,
         } = msg;
 
         debug!(task_id=?token.task_id,
@@ -87,56 +119,150 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
             ?attempt,
             "enqueueing");
 
+        // a mapped task is expanded into one instance per item when it first executes,
+        // retries and requeues re-execute just the one instance
+        let instances = match map {
+            Some(instance) => vec![Some(instance)],
+            None => match get_map_items(&pool, &token).await? {
+                MapItems::NotMapped => vec![None],
+                MapItems::Items(items) => {
+                    let batch_id = Uuid::new_v4();
+                    let count = items.len() as i32;
+
+                    items
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, item)| {
+                            Some(MapInstance {
+                                batch_id,
+                                index: index as i32,
+                                count,
+                                item,
+                            })
+                        })
+                        .collect()
+                }
+                MapItems::Invalid(reason) => {
+                    warn!(task_id=?token.task_id,
+                        trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+                        "cannot run mapped task: {}", reason);
+
+                    finish_without_running(
+                        &pool,
+                        &token,
+                        priority,
+                        attempt,
+                        TokenState::Error,
+                        None,
+                    )
+                    .await?;
+                    continue;
+                }
+            },
+        };
+
+        if instances.is_empty() {
+            info!(task_id=?token.task_id,
+                trigger_datetime=%token.trigger_datetime.to_rfc3339(),
+                "mapped task has no items, nothing to run");
+
+            let tokens_to_tx = finish_without_running(
+                &pool,
+                &token,
+                priority,
+                attempt,
+                TokenState::Success,
+                Some(Uuid::new_v4()),
+            )
+            .await?;
+
+            let mut token_tx = server.post_office.post_mail::<ProcessToken>().await?;
+            for token in tokens_to_tx {
+                token_tx
+                    .send(ProcessToken::Increment(token, priority))
+                    .await?;
+            }
+            continue;
+        }
+
+        let env_overrides = get_env_overrides(&pool, &token).await?;
+
         let mut conn = pool.acquire().await?;
         let mut txn = conn.begin().await?;
 
-        let task_req = TaskRequest {
-            task_run_id: Uuid::new_v4(),
-            task_id: token.task_id,
-            trigger_datetime: token.trigger_datetime,
-        };
+        for map in instances {
+            let task_req = TaskRequest {
+                task_run_id: task_run_id.unwrap_or_else(Uuid::new_v4),
+                task_id: token.task_id,
+                trigger_datetime: token.trigger_datetime,
+                map,
+                env_overrides: env_overrides.clone(),
+            };
 
-        let props = BasicProperties::default()
-            .with_delivery_mode(PERSISTENT)
-            .with_priority(priority as u8);
+            let props = BasicProperties::default()
+                .with_delivery_mode(PERSISTENT)
+                .with_priority(priority as u8);
 
-        chan.basic_publish(
-            TASK_EXCHANGE,
-            "",
-            BasicPublishOptions::default(),
-            &serde_json::to_vec(&task_req)?,
-            props,
-        )
-        .await?;
+            chan.basic_publish(
+                TASK_EXCHANGE,
+                "",
+                BasicPublishOptions::default(),
+                &serde_json::to_vec(&task_req)?,
+                props,
+            )
+            .await?;
 
+            // a sensor poked again keeps its task run, and the run's started_datetime stays
+            // at the first poke
+            sqlx::query(
+                "INSERT INTO task_run(id, task_id, trigger_datetime,
+                    queued_datetime, started_datetime, finish_datetime,
+                    updated_datetime,
+                    worker_id, state, priority, attempt,
+                    map_batch_id, map_index, map_count, map_item,
+                    env_overrides)
+                VALUES ($1, $2, $3,
+                    $4, NULL, NULL,
+                    NULL,
+                    NULL, 'active', $5, $6,
+                    $7, $8, $9, $10,
+                    $11)
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    queued_datetime = EXCLUDED.queued_datetime,
+                    finish_datetime = NULL,
+                    updated_datetime = NULL,
+                    worker_id = NULL,
+                    state = EXCLUDED.state,
+                    priority = EXCLUDED.priority,
+                    attempt = EXCLUDED.attempt",
+            )
+            .bind(task_req.task_run_id)
+            .bind(token.task_id)
+            .bind(token.trigger_datetime)
+            .bind(Utc::now())
+            .bind(priority)
+            .bind(attempt as i64)
+            .bind(task_req.map.as_ref().map(|m| m.batch_id))
+            .bind(task_req.map.as_ref().map(|m| m.index))
+            .bind(task_req.map.as_ref().map(|m| m.count))
+            .bind(task_req.map.as_ref().map(|m| &m.item))
+            .bind(&task_req.env_overrides)
+            .execute(txn.as_mut())
+            .await?;
+        }
+
+        // a sensor being poked again already took its share of the count when it first ran
         sqlx::query(
             "UPDATE token
             SET state = 'active',
-                count = count - (SELECT threshold FROM task WHERE id = $1)
+                count = count - CASE WHEN $3 THEN 0 ELSE (SELECT threshold FROM task WHERE id = $1) END
             WHERE task_id = $1
             AND trigger_datetime = $2",
         )
         .bind(token.task_id)
         .bind(token.trigger_datetime)
-        .execute(txn.as_mut())
-        .await?;
-
-        sqlx::query(
-            "INSERT INTO task_run(id, task_id, trigger_datetime,
-                queued_datetime, started_datetime, finish_datetime,
-                updated_datetime,
-                worker_id, state, priority, attempt)
-            VALUES ($1, $2, $3,
-                $4, NULL, NULL,
-                NULL,
-                NULL, 'active', $5, $6)",
-        )
-        .bind(task_req.task_run_id)
-        .bind(token.task_id)
-        .bind(token.trigger_datetime)
-        .bind(Utc::now())
-        .bind(priority)
-        .bind(attempt as i64)
+        .bind(task_run_id.is_some())
         .execute(txn.as_mut())
         .await?;
 
@@ -156,3 +282,144 @@ pub async fn process_executions(server: Arc<Server>) -> Result<!> {
 
     unreachable!("ExecuteToken channel was closed!")
 }
+
+/// Get the environment overrides given when the token was last activated manually, or
+/// inherited from its upstream task
+async fn get_env_overrides(pool: &PgPool, token: &Token) -> Result<Option<Vec<String>>> {
+    let row: Option<(Option<Vec<String>>,)> = sqlx::query_as(
+        "SELECT env_overrides
+        FROM token
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|(env_overrides,)| env_overrides))
+}
+
+/// Load the items for a mapped task from the job stash.
+async fn get_map_items(pool: &PgPool, token: &Token) -> Result<MapItems> {
+    let row: Option<(Option<String>, Option<Vec<u8>>)> = sqlx::query_as(
+        "SELECT
+            t.map_stash_key,
+            (
+                SELECT js.data
+                FROM job_stash js
+                WHERE js.job_id = t.job_id
+                AND js.trigger_datetime = $2
+                AND js.name = t.map_stash_key
+            ) AS items
+        FROM task t
+        WHERE t.id = $1",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match row {
+        None | Some((None, _)) => MapItems::NotMapped,
+        Some((Some(key), None)) => MapItems::Invalid(format!("job stash key '{key}' is not set")),
+        Some((Some(key), Some(data))) => match parse_map_items(&data) {
+            Ok(items) => MapItems::Items(items),
+            Err(err) => {
+                MapItems::Invalid(format!("job stash key '{key}' is not a JSON list: {err}"))
+            }
+        },
+    })
+}
+
+/// Parse a JSON list into the items passed to each instance of a mapped task.
+/// Strings are passed as is, any other value is passed as JSON.
+fn parse_map_items(data: &[u8]) -> serde_json::Result<Vec<String>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(data)?;
+
+    Ok(values
+        .into_iter()
+        .map(|value| match value {
+            serde_json::Value::String(s) => s,
+            other => other.to_string(),
+        })
+        .collect())
+}
+
+/// Record a task run that finished without being sent to a worker.
+///
+/// This happens when a mapped task can't be expanded (the run is an error), or when it expands to
+/// nothing (the run succeeds immediately and downstream tasks are advanced).
+async fn finish_without_running(
+    pool: &PgPool,
+    token: &Token,
+    priority: TaskPriority,
+    attempt: u32,
+    result: TokenState,
+    map_batch_id: Option<Uuid>,
+) -> Result<Vec<Token>> {
+    let mut conn = pool.acquire().await?;
+    let mut txn: Transaction<'_, Postgres> = conn.begin().await?;
+
+    let now = Utc::now();
+
+    sqlx::query(
+        "INSERT INTO task_run(id, task_id, trigger_datetime,
+            queued_datetime, started_datetime, finish_datetime,
+            updated_datetime,
+            worker_id, state, priority, attempt,
+            map_batch_id, map_count)
+        VALUES ($1, $2, $3,
+            $4, $4, $4,
+            $4,
+            NULL, $5, $6, $7,
+            $8, $9)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(now)
+    .bind(result)
+    .bind(priority)
+    .bind(attempt as i64)
+    .bind(map_batch_id)
+    .bind(map_batch_id.map(|_| 0))
+    .execute(txn.as_mut())
+    .await?;
+
+    sqlx::query(
+        "UPDATE token
+        SET state = $3,
+            count = count - (SELECT threshold FROM task WHERE id = $1)
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(result)
+    .execute(txn.as_mut())
+    .await?;
+
+    let tokens_to_tx = advance_tokens(pool, &mut txn, token, result).await?;
+
+    txn.commit().await?;
+
+    Ok(tokens_to_tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_map_items;
+
+    #[test]
+    fn test_parse_map_items() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_map_items(br#"["a", 1, {"b": true}]"#)?,
+            vec!["a", "1", r#"{"b":true}"#]
+        );
+        assert_eq!(parse_map_items(b"[]")?, Vec::<String>::new());
+        assert!(parse_map_items(br#"{"a": 1}"#).is_err());
+        assert!(parse_map_items(b"a,b,c").is_err());
+        Ok(())
+    }
+}
@@ -18,11 +18,16 @@ use lapin::{
 use postage::prelude::*;
 use sqlx::{Connection, PgPool, Postgres, Transaction};
 use std::sync::Arc;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 
 const RESULT_QUEUE: &str = "waterwheel.results";
 
+/// sensors are poked every minute unless they set a poke interval
+const DEFAULT_POKE_INTERVAL_SECS: i64 = 60;
+/// sensors give up after a day unless they set a timeout
+const DEFAULT_SENSOR_TIMEOUT_SECS: i64 = 24 * 60 * 60;
+
 pub async fn process_progress(server: Arc<Server>) -> Result<!> {
     let pool = server.db_pool.clone();
     let chan = server.amqp_conn.create_channel().await?;
@@ -53,7 +58,7 @@ pub async fn process_progress(server: Arc<Server>) -> Result<!> {
         .await?;
 
     while let Some(delivery) = consumer.try_next().await? {
-        let task_progress: TaskProgress = serde_json::from_slice(&delivery.data)?;
+        let mut task_progress: TaskProgress = serde_json::from_slice(&delivery.data)?;
 
         debug!(result=task_progress.result.as_ref(),
             task_id=?task_progress.task_id,
@@ -63,17 +68,45 @@ pub async fn process_progress(server: Arc<Server>) -> Result<!> {
         let mut conn = pool.acquire().await?;
         let mut txn = conn.begin().await?;
 
-        let priority = update_task_progress(&server, &mut txn, &task_progress).await?;
+        if task_progress.result == TokenState::Failure
+            && exit_code_chooses_branch(&mut txn, &task_progress).await?
+        {
+            // the exit code picked a branch, so the task didn't fail
+            task_progress.result = TokenState::Success;
+        }
+
+        let Some(run) = update_task_progress(&server, &mut txn, &task_progress).await? else {
+            // the run was abandoned while in flight, so whatever it reports is ignored
+            txn.commit().await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+            continue;
+        };
+        let priority = run.priority;
 
         let mut tokens_to_tx = Vec::new();
+        let mut result = task_progress.result;
+
+        if result == TokenState::Sensing && !submit_poke(&server, &mut txn, &task_progress).await? {
+            result = TokenState::Timeout;
+            record_sensor_timeout(&mut txn, &task_progress, run.map_batch_id.is_some()).await?;
+        }
+
+        if result.is_final() {
+            let finished = Token {
+                task_id: task_progress.task_id,
+                trigger_datetime: task_progress.trigger_datetime,
+            };
 
-        if task_progress.result.is_final() {
-            if task_progress.result.is_retryable()
-                && has_retries(&pool, task_progress.task_run_id).await?
-            {
+            if result.is_retryable() && has_retries(&pool, task_progress.task_run_id).await? {
                 submit_retry(&server, &mut txn, &server.post_office, &task_progress).await?;
+            } else if let (Some(batch_id), Some(count)) = (run.map_batch_id, run.map_count) {
+                if let Some(result) =
+                    complete_map_batch(&mut txn, &finished, batch_id, count).await?
+                {
+                    tokens_to_tx = advance_tokens(&pool, &mut txn, &finished, result).await?;
+                }
             } else {
-                tokens_to_tx = advance_tokens(&pool, &mut txn, &task_progress).await?;
+                tokens_to_tx = advance_tokens(&pool, &mut txn, &finished, result).await?;
             }
         }
 
@@ -98,27 +131,131 @@ pub async fn process_progress(server: Arc<Server>) -> Result<!> {
 struct TaskEdge {
     child_task_id: Uuid,
     edge_offset: Option<i64>,
+    branch: Option<String>,
+}
+
+/// Check if a task's exit code is one that chooses a branch
+async fn exit_code_chooses_branch(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<bool> {
+    let Some(exit_code) = task_progress.exit_code else {
+        return Ok(false);
+    };
+
+    let chooses: Option<(bool,)> = sqlx::query_as(
+        "SELECT t.branch_exit_codes ? $2
+        FROM task t
+        WHERE t.id = $1
+        AND t.branch_exit_codes IS NOT NULL",
+    )
+    .bind(task_progress.task_id)
+    .bind(exit_code.to_string())
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    Ok(chooses.is_some_and(|(chooses,)| chooses))
+}
+
+#[derive(sqlx::FromRow)]
+struct TaskBranches {
+    branch_names: Option<Vec<String>>,
+    exit_branch: Option<String>,
+    chosen: Option<Vec<u8>>,
+}
+
+/// Find the branch chosen by a task that declares branches.
+///
+/// The task selects a branch either by exiting with a code mapped to the branch, or by
+/// writing its name to the job stash under its branch stash key. The exit code wins if both
+/// choose a branch.
+/// Returns `None` if the task doesn't declare branches, and `Some(None)` if it declares branches
+/// but didn't choose a valid one (in which case all branches are skipped).
+async fn get_chosen_branch(pool: &PgPool, token: &Token) -> Result<Option<Option<String>>> {
+    let info: Option<TaskBranches> = sqlx::query_as(
+        "SELECT
+            t.branch_names,
+            t.branch_exit_codes ->> (
+                SELECT r.exit_code::text
+                FROM task_run r
+                WHERE r.task_id = t.id
+                AND r.trigger_datetime = $2
+                ORDER BY r.queued_datetime DESC
+                LIMIT 1
+            ) AS exit_branch,
+            (
+                SELECT js.data
+                FROM job_stash js
+                WHERE js.job_id = t.job_id
+                AND js.trigger_datetime = $2
+                AND js.name = t.branch_stash_key
+            ) AS chosen
+        FROM task t
+        WHERE t.id = $1",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(TaskBranches {
+        branch_names: Some(branch_names),
+        exit_branch,
+        chosen,
+    }) = info
+    else {
+        return Ok(None);
+    };
+
+    let chosen = exit_branch.or_else(|| {
+        chosen
+            .and_then(|data| String::from_utf8(data).ok())
+            .map(|name| name.trim().to_owned())
+    });
+
+    match chosen {
+        Some(name) if branch_names.contains(&name) => Ok(Some(Some(name))),
+        other => {
+            warn!(task_id=?token.task_id,
+                trigger_datetime=?token.trigger_datetime.to_rfc3339(),
+                chosen=?other,
+                "task did not choose a valid branch, skipping all branches");
+            Ok(Some(None))
+        }
+    }
 }
 
+/// Increment the tokens of the tasks downstream of a finished task.
+///
+/// Returns the tokens that were incremented, which must be sent to the token processor after
+/// the transaction commits.
 pub async fn advance_tokens(
     pool: &PgPool,
     txn: &mut Transaction<'_, Postgres>,
-    task_progress: &TaskProgress,
+    finished: &Token,
+    result: TokenState,
 ) -> Result<Vec<Token>> {
-    trace!(task_id=?task_progress.task_id,
-        task_run_id=?task_progress.task_run_id,
+    trace!(task_id=?finished.task_id,
+        trigger_datetime=?finished.trigger_datetime.to_rfc3339(),
         "advancing tokens");
 
+    let chosen_branch = if result == TokenState::Success {
+        get_chosen_branch(pool, finished).await?
+    } else {
+        None
+    };
+
     let mut cursor = sqlx::query_as(
         "SELECT
             child_task_id,
-            edge_offset
+            edge_offset,
+            branch
         FROM task_edge
         WHERE parent_task_id = $1
         AND kind = $2",
     )
-    .bind(task_progress.task_id)
-    .bind(task_progress.result)
+    .bind(finished.task_id)
+    .bind(result)
     .fetch(pool);
 
     let mut tokens_to_tx = Vec::new();
@@ -126,70 +263,358 @@ pub async fn advance_tokens(
     while let Some(TaskEdge {
         child_task_id,
         edge_offset,
+        branch,
     }) = cursor.try_next().await?
     {
         let token = Token {
             task_id: child_task_id,
-            trigger_datetime: task_progress.trigger_datetime
+            trigger_datetime: finished.trigger_datetime
                 + Duration::seconds(edge_offset.unwrap_or(0)),
         };
 
-        increment_token(&mut *txn, &token).await?;
-        tokens_to_tx.push(token);
+        let taken = match (&branch, &chosen_branch) {
+            (None, _) => true,
+            (Some(branch), Some(chosen)) => chosen.as_ref() == Some(branch),
+            // the task no longer declares branches, so treat the edge as unconditional
+            (Some(_), None) => true,
+        };
+
+        if taken {
+            increment_token(&mut *txn, &token, Some(finished)).await?;
+            if token.trigger_datetime == finished.trigger_datetime {
+                inherit_env_overrides(&mut *txn, finished, &token).await?;
+            }
+            tokens_to_tx.push(token);
+        } else {
+            skip_token(&mut *txn, &token).await?;
+        }
     }
 
     Ok(tokens_to_tx)
 }
 
-async fn update_task_progress(
-    _server: &Server,
+/// Pass the environment overrides of a manually activated token on to a child token in the
+/// same job, so the whole job runs with the overrides. A parent without overrides clears the
+/// child's, so one-off values from an earlier activation aren't reused when the token is
+/// activated again without them.
+async fn inherit_env_overrides(
     txn: &mut Transaction<'_, Postgres>,
-    task_progress: &TaskProgress,
-) -> Result<TaskPriority> {
-    trace!(task_id=?task_progress.task_id,
-        task_run_id=?task_progress.task_run_id,
-        "updating token state");
-
+    parent: &Token,
+    child: &Token,
+) -> Result<()> {
     sqlx::query(
-        "UPDATE token
-            SET state = $1
-        WHERE task_id = $2
-        AND trigger_datetime = $3",
+        "UPDATE token c
+        SET env_overrides = p.env_overrides
+        FROM token p
+        WHERE p.task_id = $1
+        AND p.trigger_datetime = $2
+        AND c.task_id = $3
+        AND c.trigger_datetime = $2
+        AND (SELECT job_id FROM task WHERE id = $1) = (SELECT job_id FROM task WHERE id = $3)",
     )
-    .bind(task_progress.result)
-    .bind(task_progress.task_id)
-    .bind(task_progress.trigger_datetime)
+    .bind(parent.task_id)
+    .bind(parent.trigger_datetime)
+    .bind(child.task_id)
     .execute(txn.as_mut())
     .await?;
 
+    Ok(())
+}
+
+/// Mark a token as skipped because it is on a branch that wasn't chosen.
+///
+/// The skip carries on down the graph: any downstream task whose upstreams have all been
+/// skipped is skipped as well. Tokens that have already started running are left alone.
+async fn skip_token(txn: &mut Transaction<'_, Postgres>, token: &Token) -> Result<()> {
+    let mut to_skip = vec![token.clone()];
+
+    while let Some(token) = to_skip.pop() {
+        trace!(task_id=?token.task_id,
+            trigger_datetime=?token.trigger_datetime.to_rfc3339(),
+            "skipping token");
+
+        let done = sqlx::query(
+            "INSERT INTO token(task_id, trigger_datetime, count, state)
+                VALUES ($1, $2, 0, $3)
+                ON CONFLICT(task_id, trigger_datetime)
+                DO UPDATE SET state = $3
+                WHERE token.state = $4",
+        )
+        .bind(token.task_id)
+        .bind(token.trigger_datetime)
+        .bind(TokenState::Skipped)
+        .bind(TokenState::Waiting)
+        .execute(txn.as_mut())
+        .await?;
+
+        if done.rows_affected() == 0 {
+            continue;
+        }
+
+        // cyclic edges lead into a later period, which isn't skipped along with this one
+        let children: Vec<(Uuid, Option<i64>)> = sqlx::query_as(
+            "SELECT DISTINCT
+                child_task_id,
+                edge_offset
+            FROM task_edge
+            WHERE parent_task_id = $1
+            AND NOT cyclic",
+        )
+        .bind(token.task_id)
+        .fetch_all(txn.as_mut())
+        .await?;
+
+        for (child_task_id, edge_offset) in children {
+            let child = Token {
+                task_id: child_task_id,
+                trigger_datetime: token.trigger_datetime
+                    + Duration::seconds(edge_offset.unwrap_or(0)),
+            };
+
+            if all_upstreams_skipped(txn, &child).await? {
+                to_skip.push(child);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if every upstream of a token was skipped. A task with a trigger, or with an upstream
+/// in a job that doesn't exist yet, always has an upstream that could still run.
+async fn all_upstreams_skipped(txn: &mut Transaction<'_, Postgres>, token: &Token) -> Result<bool> {
+    let (skipped,): (bool,) = sqlx::query_as(
+        "SELECT
+            NOT EXISTS (
+                SELECT 1
+                FROM trigger_edge
+                WHERE task_id = $1
+            )
+            AND NOT EXISTS (
+                SELECT 1
+                FROM pending_edge
+                WHERE task_id = $1
+            )
+            AND NOT EXISTS (
+                SELECT 1
+                FROM task_edge e
+                LEFT JOIN token k ON k.task_id = e.parent_task_id
+                    AND k.trigger_datetime + (INTERVAL '1 second' * COALESCE(e.edge_offset, 0)) = $2
+                WHERE e.child_task_id = $1
+                AND k.state IS DISTINCT FROM $3
+            )",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .bind(TokenState::Skipped)
+    .fetch_one(txn.as_mut())
+    .await?;
+
+    Ok(skipped)
+}
+
+#[derive(sqlx::FromRow, Default)]
+struct RunInfo {
+    priority: TaskPriority,
+    map_batch_id: Option<Uuid>,
+    map_count: Option<i32>,
+}
+
+async fn update_task_progress(
+    _server: &Server,
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<Option<RunInfo>> {
     trace!(task_id=?task_progress.task_id,
         task_run_id=?task_progress.task_run_id,
         "updating task_run state");
 
-    let maybe_priority: Option<(TaskPriority,)> = sqlx::query_as(
+    let maybe_run: Option<RunInfo> = sqlx::query_as(
         "UPDATE task_run
             SET state = $1,
-                started_datetime = $2,
+                started_datetime = COALESCE(started_datetime, $2),
                 finish_datetime = $3,
                 updated_datetime = CURRENT_TIMESTAMP,
-                worker_id = $4
+                worker_id = $4,
+                exit_code = $8
         WHERE id = $5
-        RETURNING priority",
+        AND state IN ($6, $7)
+        RETURNING priority, map_batch_id, map_count",
     )
     .bind(task_progress.result)
     .bind(task_progress.started_datetime)
     .bind(task_progress.finished_datetime)
     .bind(task_progress.worker_id)
     .bind(task_progress.task_run_id)
+    .bind(TokenState::Active)
+    .bind(TokenState::Running)
+    .bind(task_progress.exit_code)
     .fetch_optional(txn.as_mut())
     .await?;
 
-    // there are cases when the database doesn't record a task run for this UUID
-    // (the message is sent to AMQP before the DB commits so we don't lose any events)
-    // in that case we just keep going
-    let priority = maybe_priority.map(first).unwrap_or_default();
+    let run = match maybe_run {
+        Some(run) => run,
+        None => {
+            let exists: Option<(Uuid,)> = sqlx::query_as(
+                "SELECT id
+                FROM task_run
+                WHERE id = $1",
+            )
+            .bind(task_progress.task_run_id)
+            .fetch_optional(txn.as_mut())
+            .await?;
+
+            if exists.is_some() {
+                // the run already finished, or was abandoned when the task was marked
+                // manually or requeued - the token has moved on without it
+                warn!(task_id=?task_progress.task_id,
+                    task_run_id=?task_progress.task_run_id,
+                    result=task_progress.result.as_ref(),
+                    "ignoring progress from a task run that is no longer in progress");
+                return Ok(None);
+            }
+
+            // there are cases when the database doesn't record a task run for this UUID
+            // (the message is sent to AMQP before the DB commits so we don't lose any events)
+            // in that case we just keep going
+            RunInfo::default()
+        }
+    };
+
+    trace!(task_id=?task_progress.task_id,
+        task_run_id=?task_progress.task_run_id,
+        "updating token state");
 
-    Ok(priority)
+    if run.map_batch_id.is_some() {
+        // the token of a mapped task only takes a final state once every instance is done
+        sqlx::query(
+            "UPDATE token
+                SET state = $1
+            WHERE task_id = $2
+            AND trigger_datetime = $3
+            AND state IN ($4, $5)",
+        )
+        .bind(TokenState::Running)
+        .bind(task_progress.task_id)
+        .bind(task_progress.trigger_datetime)
+        .bind(TokenState::Active)
+        .bind(TokenState::Retry)
+        .execute(txn.as_mut())
+        .await?;
+    } else {
+        sqlx::query(
+            "UPDATE token
+                SET state = $1
+            WHERE task_id = $2
+            AND trigger_datetime = $3",
+        )
+        .bind(task_progress.result)
+        .bind(task_progress.task_id)
+        .bind(task_progress.trigger_datetime)
+        .execute(txn.as_mut())
+        .await?;
+    }
+
+    Ok(Some(run))
+}
+
+#[derive(sqlx::FromRow)]
+struct MapInstanceState {
+    state: Option<TokenState>,
+    has_retries: bool,
+}
+
+/// Check if every instance of a mapped task has finished, and if so set the token's final state.
+///
+/// Returns the combined result of the batch if this call completed it: success if every instance
+/// succeeded, otherwise error if any instance errored, otherwise failure.
+async fn complete_map_batch(
+    txn: &mut Transaction<'_, Postgres>,
+    finished: &Token,
+    batch_id: Uuid,
+    count: i32,
+) -> Result<Option<TokenState>> {
+    // lock the token so that instances finishing concurrently can't both miss (or both see)
+    // the batch completing
+    sqlx::query(
+        "SELECT 1
+        FROM token
+        WHERE task_id = $1
+        AND trigger_datetime = $2
+        FOR UPDATE",
+    )
+    .bind(finished.task_id)
+    .bind(finished.trigger_datetime)
+    .execute(txn.as_mut())
+    .await?;
+
+    // only the latest run of each instance counts, earlier runs were retried or requeued
+    let instances: Vec<MapInstanceState> = sqlx::query_as(
+        "SELECT DISTINCT ON (r.map_index)
+            r.state,
+            COALESCE(r.attempt < t.retry_max_attempts, FALSE) AS has_retries
+        FROM task_run r
+        JOIN task t ON t.id = r.task_id
+        WHERE r.map_batch_id = $1
+        AND r.map_index IS NOT NULL
+        ORDER BY r.map_index, r.queued_datetime DESC",
+    )
+    .bind(batch_id)
+    .fetch_all(txn.as_mut())
+    .await?;
+
+    let done = instances.len() >= count as usize
+        && instances.iter().all(|i| match i.state {
+            // failed instances with attempts remaining are about to be retried
+            Some(state) => state.is_final() && !(state.is_retryable() && i.has_retries),
+            None => false,
+        });
+
+    if !done {
+        trace!(task_id=?finished.task_id,
+            trigger_datetime=?finished.trigger_datetime.to_rfc3339(),
+            ?batch_id,
+            "mapped task still has instances running");
+        return Ok(None);
+    }
+
+    let states: Vec<TokenState> = instances.iter().filter_map(|i| i.state).collect();
+    let result = if states.iter().all(|s| *s == TokenState::Success) {
+        TokenState::Success
+    } else if states.contains(&TokenState::Error) {
+        TokenState::Error
+    } else {
+        TokenState::Failure
+    };
+
+    // guard against redelivered results completing the batch twice
+    let updated = sqlx::query(
+        "UPDATE token
+            SET state = $1
+        WHERE task_id = $2
+        AND trigger_datetime = $3
+        AND state IN ($4, $5, $6)",
+    )
+    .bind(result)
+    .bind(finished.task_id)
+    .bind(finished.trigger_datetime)
+    .bind(TokenState::Active)
+    .bind(TokenState::Running)
+    .bind(TokenState::Retry)
+    .execute(txn.as_mut())
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    info!(task_id=?finished.task_id,
+        trigger_datetime=?finished.trigger_datetime.to_rfc3339(),
+        ?batch_id,
+        result=result.as_ref(),
+        "all instances of mapped task finished");
+
+    Ok(Some(result))
 }
 
 async fn has_retries(pool: &PgPool, task_run_id: Uuid) -> Result<bool> {
@@ -234,16 +659,12 @@ async fn submit_retry(
         task_run_id=?task_progress.task_run_id,
         "task will retry at {}", retry_at_datetime);
 
-    sqlx::query(
-        "INSERT INTO retry(task_run_id, retry_at_datetime)
-        VALUES(
-            $1,
-            $2
-        )",
+    queue_retry(
+        txn,
+        post_office,
+        task_progress.task_run_id,
+        retry_at_datetime,
     )
-    .bind(task_progress.task_run_id)
-    .bind(retry_at_datetime)
-    .execute(txn.as_mut())
     .await?;
 
     sqlx::query(
@@ -258,13 +679,122 @@ async fn submit_retry(
     .execute(txn.as_mut())
     .await?;
 
+    Ok(())
+}
+
+/// Run the task again at a later time, through the retry processor
+async fn queue_retry(
+    txn: &mut Transaction<'_, Postgres>,
+    post_office: &PostOffice,
+    task_run_id: Uuid,
+    retry_at_datetime: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO retry(task_run_id, retry_at_datetime)
+        VALUES(
+            $1,
+            $2
+        )",
+    )
+    .bind(task_run_id)
+    .bind(retry_at_datetime)
+    .execute(txn.as_mut())
+    .await?;
+
     let mut retry_tx = post_office.post_mail::<SubmitRetry>().await?;
     retry_tx
         .send(SubmitRetry::Add(Retry {
-            task_run_id: task_progress.task_run_id,
+            task_run_id,
             retry_at_datetime,
         }))
         .await?;
 
     Ok(())
 }
+
+/// Queue the next poke of a sensor whose condition didn't hold. Returns false without queueing
+/// anything if the sensor has been poking for longer than its timeout.
+///
+/// Every poke reuses the same task run, so the timeout counts from when the run started. A
+/// retry or a rerun of the token records a new run, which gets the full timeout again.
+async fn submit_poke(
+    server: &Server,
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+) -> Result<bool> {
+    let finished_datetime = task_progress.finished_datetime.unwrap_or_else(Utc::now);
+
+    let row: Option<(DateTime<Utc>, Option<DateTime<Utc>>)> = sqlx::query_as(
+        "SELECT
+            $2 + (INTERVAL '1s' * COALESCE(t.sensor_poke_interval_secs, $3)),
+            r.started_datetime + (INTERVAL '1s' * COALESCE(t.sensor_timeout_secs, $4))
+        FROM task_run r
+        JOIN task t ON t.id = r.task_id
+        WHERE r.id = $1",
+    )
+    .bind(task_progress.task_run_id)
+    .bind(finished_datetime)
+    .bind(DEFAULT_POKE_INTERVAL_SECS)
+    .bind(DEFAULT_SENSOR_TIMEOUT_SECS)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    let Some((poke_at_datetime, Some(timeout_datetime))) = row else {
+        warn!(task_run_id=?task_progress.task_run_id, "no task run recorded for sensor");
+        return Ok(false);
+    };
+
+    if finished_datetime >= timeout_datetime {
+        info!(task_id=?task_progress.task_id,
+            task_run_id=?task_progress.task_run_id,
+            "sensor timed out at {}", timeout_datetime);
+        return Ok(false);
+    }
+
+    debug!(task_id=?task_progress.task_id,
+        task_run_id=?task_progress.task_run_id,
+        "sensor will be poked again at {}", poke_at_datetime);
+
+    queue_retry(
+        txn,
+        &server.post_office,
+        task_progress.task_run_id,
+        poke_at_datetime,
+    )
+    .await?;
+
+    Ok(true)
+}
+
+async fn record_sensor_timeout(
+    txn: &mut Transaction<'_, Postgres>,
+    task_progress: &TaskProgress,
+    mapped: bool,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE task_run
+            SET state = $1
+        WHERE id = $2",
+    )
+    .bind(TokenState::Timeout)
+    .bind(task_progress.task_run_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    // the token of a mapped task is set once every instance is done
+    if !mapped {
+        sqlx::query(
+            "UPDATE token
+                SET state = $1
+            WHERE task_id = $2
+            AND trigger_datetime = $3",
+        )
+        .bind(TokenState::Timeout)
+        .bind(task_progress.task_id)
+        .bind(task_progress.trigger_datetime)
+        .execute(txn.as_mut())
+        .await?;
+    }
+
+    Ok(())
+}
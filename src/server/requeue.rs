@@ -1,6 +1,9 @@
 use crate::{
     messages::{TaskPriority, Token, TokenState},
-    server::{Server, execute::ExecuteToken},
+    server::{
+        Server,
+        execute::{ExecuteToken, MapColumns},
+    },
 };
 use anyhow::{Result, format_err};
 use chrono::{DateTime, Utc};
@@ -18,6 +21,8 @@ struct Requeue {
     priority: TaskPriority,
     attempt: i64,
     paused: bool,
+    #[sqlx(flatten)]
+    map: MapColumns,
 }
 
 pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
@@ -45,7 +50,11 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
                 r.trigger_datetime,
                 r.priority,
                 r.attempt,
-                j.paused
+                j.paused,
+                r.map_batch_id,
+                r.map_index,
+                r.map_count,
+                r.map_item
             FROM task_run r
             JOIN task t ON r.task_id = t.id
             JOIN job j ON t.job_id = j.id
@@ -64,6 +73,9 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
         .await?;
 
         for requeue in requeues {
+            let map = requeue.map.into_instance();
+            let mapped = map.is_some();
+
             if requeue.paused {
                 warn!(task_run_id=?requeue.task_run_id,
                     task_id=?requeue.task_id,
@@ -83,6 +95,8 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
                         },
                         priority: requeue.priority,
                         attempt: u32::try_from(requeue.attempt)? + 1,
+                        map,
+                        task_run_id: None,
                     })
                     .await?;
             }
@@ -98,6 +112,11 @@ pub async fn process_requeue(server: Arc<Server>) -> Result<!> {
             .execute(txn.as_mut())
             .await?;
 
+            if mapped {
+                // the token of a mapped task only takes a final state once every instance is done
+                continue;
+            }
+
             sqlx::query(
                 "UPDATE token
                    SET state = $1
@@ -1,6 +1,9 @@
 use crate::{
-    messages::{TaskPriority, Token},
-    server::{Server, execute::ExecuteToken},
+    messages::{TaskPriority, Token, TokenState},
+    server::{
+        Server,
+        execute::{ExecuteToken, MapColumns},
+    },
     util::format_duration_approx,
 };
 use anyhow::{Result, format_err};
@@ -93,31 +96,61 @@ struct RetryInfo {
     pub trigger_datetime: DateTime<Utc>,
     pub priority: TaskPriority,
     pub attempt: i64,
+    pub state: Option<TokenState>,
+    #[sqlx(flatten)]
+    pub map: MapColumns,
 }
 
 async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
     let mut execute_tx = server.post_office.post_mail::<ExecuteToken>().await?;
 
+    let mut txn = server.db_pool.begin().await?;
+
+    // the retry may have been cancelled since it was queued (eg. the task was marked manually)
+    let deleted = sqlx::query(
+        "DELETE FROM retry
+        WHERE task_run_id = $1",
+    )
+    .bind(retry.task_run_id)
+    .execute(txn.as_mut())
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        info!(task_run_id=?retry.task_run_id, "retry was cancelled");
+        return Ok(());
+    }
+
     let info: RetryInfo = sqlx::query_as(
         "SELECT
             task_id,
             trigger_datetime,
             priority,
-            attempt
+            attempt,
+            state,
+            map_batch_id,
+            map_index,
+            map_count,
+            map_item
         FROM task_run
         WHERE id = $1",
     )
     .bind(retry.task_run_id)
-    .fetch_one(&server.db_pool)
+    .fetch_one(txn.as_mut())
     .await?;
 
+    // poking a sensor again isn't another attempt
+    let poke = info.state == Some(TokenState::Sensing);
+
     info!(task_run_id=?retry.task_run_id,
         task_id=?info.task_id,
         trigger_datetime=?info.trigger_datetime,
         priority=?info.priority,
         attempt=?info.attempt,
+        poke,
         "retrying");
 
+    let attempt = u32::try_from(info.attempt)?;
+
     execute_tx
         .send(ExecuteToken {
             token: Token {
@@ -125,17 +158,13 @@ async fn do_retry(server: &Server, retry: Retry) -> Result<()> {
                 trigger_datetime: info.trigger_datetime,
             },
             priority: info.priority,
-            attempt: u32::try_from(info.attempt)? + 1,
+            attempt: if poke { attempt } else { attempt + 1 },
+            map: info.map.into_instance(),
+            task_run_id: poke.then_some(retry.task_run_id),
         })
         .await?;
 
-    sqlx::query(
-        "DELETE FROM retry
-        WHERE task_run_id = $1",
-    )
-    .bind(retry.task_run_id)
-    .execute(&server.db_pool)
-    .await?;
+    txn.commit().await?;
 
     Ok(())
 }
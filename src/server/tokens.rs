@@ -71,6 +71,8 @@ pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
                             token,
                             priority,
                             attempt: 1,
+                            map: None,
+                            task_run_id: None,
                         })
                         .await?;
                 }
@@ -81,6 +83,8 @@ pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
                         token,
                         priority,
                         attempt: 1,
+                        map: None,
+                        task_run_id: None,
                     })
                     .await?;
             }
@@ -103,19 +107,86 @@ pub async fn process_tokens(server: Arc<Server>) -> Result<!> {
 /// update the state of the upstream task to be 'done')
 /// After adding the token you have to send the token over to the process_tokens future to actually
 /// check if the node has activated
-pub async fn increment_token(txn: &mut Transaction<'_, Postgres>, token: &Token) -> Result<()> {
+///
+/// `parent` is the finished upstream task adding the token, if it wasn't added by a trigger.
+pub async fn increment_token(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+    parent: Option<&Token>,
+) -> Result<()> {
     trace!(task_id=?token.task_id,
         trigger_datetime=?token.trigger_datetime.to_rfc3339(),
         "incrementing token");
 
-    sqlx::query(
+    let inserted: Option<(Uuid,)> = sqlx::query_as(
         "INSERT INTO token(task_id, trigger_datetime, count, state)
             VALUES ($1, $2, 1, 'waiting')
             ON CONFLICT(task_id, trigger_datetime)
-            DO UPDATE SET count = token.count + 1",
+            DO NOTHING
+            RETURNING task_id",
+    )
+    .bind(token.task_id)
+    .bind(token.trigger_datetime)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    if inserted.is_some() {
+        count_finished_upstream(txn, token, parent).await?;
+    } else {
+        sqlx::query(
+            "UPDATE token
+            SET count = count + 1
+            WHERE task_id = $1
+            AND trigger_datetime = $2",
+        )
+        .bind(token.task_id)
+        .bind(token.trigger_datetime)
+        .execute(txn.as_mut())
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Add the upstream tasks in other jobs which had already finished to a newly created token.
+///
+/// When a job is backfilled the tokens of its downstream tasks in other jobs are created for
+/// datetimes where their other upstream tasks may have finished long ago (eg. before the
+/// dependency was declared). Those never incremented the token, so without counting them here the
+/// token would wait forever.
+async fn count_finished_upstream(
+    txn: &mut Transaction<'_, Postgres>,
+    token: &Token,
+    parent: Option<&Token>,
+) -> Result<()> {
+    let (parent_task_id, parent_datetime) = match parent {
+        Some(parent) => (Some(parent.task_id), Some(parent.trigger_datetime)),
+        None => (None, None),
+    };
+
+    sqlx::query(
+        "UPDATE token
+        SET count = count + (
+            SELECT COUNT(*)
+            FROM task_edge e
+            JOIN task p ON p.id = e.parent_task_id
+            JOIN task c ON c.id = e.child_task_id
+            JOIN token k ON k.task_id = e.parent_task_id
+                AND k.trigger_datetime + (INTERVAL '1 second' * COALESCE(e.edge_offset, 0)) = $2
+            WHERE e.child_task_id = $1
+            AND p.job_id <> c.job_id
+            AND e.branch IS NULL
+            AND k.state = e.kind
+            AND NOT (k.task_id IS NOT DISTINCT FROM $3
+                AND k.trigger_datetime IS NOT DISTINCT FROM $4)
+        )
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
     )
     .bind(token.task_id)
     .bind(token.trigger_datetime)
+    .bind(parent_task_id)
+    .bind(parent_datetime)
     .execute(txn.as_mut())
     .await?;
 
@@ -138,6 +209,7 @@ async fn restore_tokens(server: &Server, job_id: Option<Uuid>) -> Result<()> {
         JOIN task ON task.id = token.task_id
         JOIN job ON job.id = task.job_id
         WHERE token.count >= task.threshold
+        AND task.archived_datetime IS NULL
         AND ($1 IS NULL OR job.id = $1)
         AND NOT job.paused",
     )
@@ -162,6 +234,8 @@ async fn restore_tokens(server: &Server, job_id: Option<Uuid>) -> Result<()> {
                 token: token.clone(),
                 priority: TaskPriority::Normal,
                 attempt: 1,
+                map: None,
+                task_run_id: None,
             })
             .await?;
 
@@ -1,6 +1,12 @@
 use crate::{
     messages::{ProcessToken, TaskPriority, Token, TriggerUpdate},
-    server::{Server, api::types::Catchup, tokens::increment_token, trigger_time::TriggerTime},
+    server::{
+        Server,
+        amqp_triggers::AmqpTriggerChange,
+        api::types::{self, Catchup, CatchupSetting, TriggerKind, duration_from_string},
+        tokens::increment_token,
+        trigger_time::TriggerTime,
+    },
     util::{deref, first, format_duration_approx},
 };
 use anyhow::Result;
@@ -14,7 +20,7 @@ use rand::{rng, seq::SliceRandom};
 use serde::{Deserialize, Serialize};
 use sqlx::{Connection, PgPool, Postgres, Transaction};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     str::FromStr,
     sync::{Arc, atomic::Ordering},
 };
@@ -31,7 +37,7 @@ pub enum TriggerChange {
 }
 
 #[derive(sqlx::FromRow, Debug)]
-struct Trigger {
+pub struct Trigger {
     id: Uuid,
     start_datetime: DateTime<Utc>,
     end_datetime: Option<DateTime<Utc>>,
@@ -41,6 +47,8 @@ struct Trigger {
     cron: Option<String>,
     trigger_offset: Option<i64>,
     catchup: Catchup,
+    catchup_max_runs: Option<i32>,
+    catchup_max_age: Option<i64>, // in seconds
 }
 
 enum Period {
@@ -83,6 +91,96 @@ impl Trigger {
             trigger_id: self.id,
         }
     }
+
+    /// How many of the missed trigger datetimes (oldest first) are beyond the catchup limits
+    fn too_old_to_catchup(&self, missed: &[DateTime<Utc>], now: DateTime<Utc>) -> usize {
+        let mut too_old = 0;
+        if let Some(max_runs) = self.catchup_max_runs {
+            too_old = missed.len().saturating_sub(max_runs as usize);
+        }
+        if let Some(max_age) = self.catchup_max_age {
+            let cutoff = now - Duration::seconds(max_age);
+            too_old = too_old.max(missed.partition_point(|datetime| *datetime < cutoff));
+        }
+        too_old
+    }
+
+    /// Build a trigger from a definition which may not have been saved yet
+    pub fn from_definition(id: Uuid, def: &types::Trigger) -> Result<Trigger> {
+        Ok(Trigger {
+            id,
+            start_datetime: def
+                .start
+                .ok_or_else(|| anyhow::anyhow!("trigger has no start"))?,
+            end_datetime: def.end,
+            earliest_trigger_datetime: None,
+            latest_trigger_datetime: None,
+            period: duration_from_string(def.period.as_deref())?.map(i64::from),
+            cron: def.cron.clone(),
+            trigger_offset: duration_from_string(def.offset.as_deref())?.map(i64::from),
+            catchup: def
+                .catchup
+                .as_ref()
+                .map(CatchupSetting::mode)
+                .unwrap_or_default(),
+            catchup_max_runs: def.catchup.as_ref().and_then(CatchupSetting::max_runs),
+            catchup_max_age: duration_from_string(
+                def.catchup.as_ref().and_then(CatchupSetting::max_age),
+            )?
+            .map(i64::from),
+        })
+    }
+
+    /// The next `limit` times the trigger is scheduled for (the trigger datetime plus offset)
+    /// at or after `after`, stopping before `until` and the trigger's end. This follows the same
+    /// steps from the start datetime as the scheduler.
+    pub fn forecast(
+        &self,
+        after: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Result<Vec<TriggerTime>> {
+        let period = self.period()?;
+        let target = after - self.offset_duration();
+
+        let mut next = self.start_datetime;
+        if next < target {
+            // jump close to the target rather than stepping through the whole history
+            next = match &period {
+                Period::Duration(duration) => {
+                    if *duration <= Duration::zero() {
+                        anyhow::bail!("trigger period must be positive");
+                    }
+                    let steps = (target - next).num_seconds() / duration.num_seconds();
+                    next + Duration::seconds(duration.num_seconds() * steps)
+                }
+                Period::Cron(schedule) => {
+                    match schedule.after(&(target - Duration::seconds(1))).next() {
+                        Some(time) => time,
+                        None => return Ok(Vec::new()),
+                    }
+                }
+            };
+            while next < target {
+                next = next + &period;
+            }
+        }
+
+        let mut times = Vec::new();
+        while times.len() < limit {
+            if self.end_datetime.is_some_and(|end| next >= end) {
+                break;
+            }
+            let time = self.at(next);
+            if until.is_some_and(|until| time.scheduled_datetime >= until) {
+                break;
+            }
+            times.push(time);
+            next = next + &period;
+        }
+
+        Ok(times)
+    }
 }
 
 pub async fn process_triggers(server: Arc<Server>) -> Result<!> {
@@ -187,6 +285,11 @@ async fn activate_trigger(
     let mut conn = pool.acquire().await?;
     let mut txn = conn.begin().await?;
 
+    if is_skipped(&mut txn, trigger_time).await? {
+        txn.commit().await?;
+        return Ok(());
+    }
+
     let tokens_to_tx = do_activate_trigger(&pool, &mut txn, trigger_time).await?;
 
     txn.commit().await?;
@@ -204,7 +307,22 @@ struct TriggerEdge {
     edge_offset: Option<i64>,
 }
 
-async fn do_activate_trigger(
+pub async fn do_activate_trigger(
+    pool: &PgPool,
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_time: TriggerTime,
+) -> Result<Vec<Token>> {
+    let tokens_to_tx = increment_trigger_tokens(pool, txn, trigger_time).await?;
+
+    update_trigger_times(txn, trigger_time).await?;
+
+    Ok(tokens_to_tx)
+}
+
+/// Increment the tokens of the tasks a trigger activates, without recording the trigger time.
+/// Used for manual fires, whose datetime may not be on the trigger's schedule and so mustn't
+/// move where the scheduler carries on from.
+pub async fn increment_trigger_tokens(
     pool: &PgPool,
     txn: &mut Transaction<'_, Postgres>,
     trigger_time: TriggerTime,
@@ -236,10 +354,17 @@ async fn do_activate_trigger(
                 + Duration::seconds(edge_offset.unwrap_or(0)),
         };
 
-        increment_token(txn, &token).await?;
+        increment_token(txn, &token, None).await?;
         tokens_to_tx.push(token);
     }
 
+    Ok(tokens_to_tx)
+}
+
+async fn update_trigger_times(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_time: TriggerTime,
+) -> Result<()> {
     trace!("updating trigger times for {}", trigger_time);
     sqlx::query(
         "
@@ -253,7 +378,94 @@ async fn do_activate_trigger(
     .execute(txn.as_mut())
     .await?;
 
-    Ok(tokens_to_tx)
+    Ok(())
+}
+
+/// Check if a trigger time has been skipped, either explicitly through the API or because
+/// its date is in one of the trigger's calendars. Calendar skips are recorded so they can be
+/// un-skipped later. Skipped times still count as triggered so catchup doesn't replay them.
+async fn is_skipped(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_time: TriggerTime,
+) -> Result<bool> {
+    let skipped = match get_skip(txn, trigger_time).await? {
+        Some(skipped) => skipped,
+        None => {
+            let calendar: Option<(String,)> = sqlx::query_as(
+                "SELECT c.name
+                FROM trigger g
+                JOIN job j ON j.id = g.job_id
+                JOIN calendar c ON c.project_id = j.project_id
+                    AND c.name = ANY(g.calendars)
+                WHERE g.id = $1
+                AND ($2 AT TIME ZONE 'UTC')::date = ANY(c.dates)
+                ORDER BY c.name
+                LIMIT 1",
+            )
+            .bind(trigger_time.trigger_id)
+            .bind(trigger_time.trigger_datetime)
+            .fetch_optional(txn.as_mut())
+            .await?;
+
+            match calendar {
+                Some((name,)) => {
+                    record_skip(txn, trigger_time, &format!("calendar {name}")).await?;
+                    true
+                }
+                None => false,
+            }
+        }
+    };
+
+    if skipped {
+        info!(trigger_id=?trigger_time.trigger_id,
+            trigger_datetime=?trigger_time.trigger_datetime.to_rfc3339(),
+            "skipping trigger");
+        update_trigger_times(txn, trigger_time).await?;
+    }
+
+    Ok(skipped)
+}
+
+/// Get whether the trigger time was explicitly skipped (or un-skipped), if it has been either
+async fn get_skip(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_time: TriggerTime,
+) -> Result<Option<bool>> {
+    let skip: Option<(bool,)> = sqlx::query_as(
+        "SELECT skipped
+        FROM trigger_skip
+        WHERE trigger_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(trigger_time.trigger_id)
+    .bind(trigger_time.trigger_datetime)
+    .fetch_optional(txn.as_mut())
+    .await?;
+
+    Ok(skip.map(first))
+}
+
+async fn record_skip(
+    txn: &mut Transaction<'_, Postgres>,
+    trigger_time: TriggerTime,
+    reason: &str,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO trigger_skip(trigger_id, trigger_datetime, skipped,
+            reason, updated_datetime)
+        VALUES ($1, $2, TRUE, $3, $4)
+        ON CONFLICT(trigger_id, trigger_datetime)
+        DO NOTHING",
+    )
+    .bind(trigger_time.trigger_id)
+    .bind(trigger_time.trigger_datetime)
+    .bind(reason)
+    .bind(Utc::now())
+    .execute(txn.as_mut())
+    .await?;
+
+    Ok(())
 }
 
 async fn catchup_trigger(
@@ -284,8 +496,10 @@ async fn catchup_trigger(
 
         let mut next = trigger.start_datetime;
         while next < earliest {
-            let mut tokens = do_activate_trigger(&pool, &mut txn, trigger.at(next)).await?;
-            tokens_to_tx.append(&mut tokens);
+            if !is_skipped(&mut txn, trigger.at(next)).await? {
+                let mut tokens = do_activate_trigger(&pool, &mut txn, trigger.at(next)).await?;
+                tokens_to_tx.append(&mut tokens);
+            }
             next = next + &period;
         }
     }
@@ -305,14 +519,41 @@ async fn catchup_trigger(
         now
     };
 
+    let mut missed = Vec::new();
     while next < last {
         if trigger.catchup != Catchup::None {
-            let mut tokens = do_activate_trigger(&pool, &mut txn, trigger.at(next)).await?;
-            tokens_to_tx.append(&mut tokens);
+            missed.push(next);
         }
         next = next + &period;
     }
 
+    // periods missed longer ago than the catchup limits are skipped (unless explicitly
+    // un-skipped) so they can still be run later through the API
+    let too_old = trigger.too_old_to_catchup(&missed, now);
+    if too_old > 0 {
+        info!(trigger_id=?trigger.id, "skipping {} periods beyond the catchup limit", too_old);
+    }
+
+    for (i, datetime) in missed.into_iter().enumerate() {
+        let trigger_time = trigger.at(datetime);
+        let skipped = if i < too_old {
+            if get_skip(&mut txn, trigger_time).await? == Some(false) {
+                false
+            } else {
+                record_skip(&mut txn, trigger_time, "catchup limit").await?;
+                update_trigger_times(&mut txn, trigger_time).await?;
+                true
+            }
+        } else {
+            is_skipped(&mut txn, trigger_time).await?
+        };
+
+        if !skipped {
+            let mut tokens = do_activate_trigger(&pool, &mut txn, trigger_time).await?;
+            tokens_to_tx.append(&mut tokens);
+        }
+    }
+
     if trigger.end_datetime.is_none() || next < trigger.end_datetime.unwrap() {
         // push one trigger in the future
         trace!(trigger_id=?trigger.id, "queueing trigger at {}", next);
@@ -339,7 +580,7 @@ async fn catchup_trigger(
     Ok(())
 }
 
-async fn send_to_token_processor(
+pub async fn send_to_token_processor(
     server: &Server,
     tokens_to_tx: Vec<Token>,
     priority: TaskPriority,
@@ -407,11 +648,14 @@ async fn update_one_trigger(server: &Server, uuid: Uuid, queue: &mut Queue) -> R
             period,
             cron,
             trigger_offset,
-            catchup
+            catchup,
+            catchup_max_runs,
+            catchup_max_age
         FROM trigger t
         JOIN job j ON t.job_id = j.id
         WHERE t.id = $1
         AND NOT j.paused
+        AND t.kind = 'time'
     ",
     )
     .bind(uuid)
@@ -422,7 +666,7 @@ async fn update_one_trigger(server: &Server, uuid: Uuid, queue: &mut Queue) -> R
         catchup_trigger(server, &trigger, queue).await?;
     } else {
         debug!(trigger_id=?uuid,
-            "trigger has been paused, it has been removed from the queue"
+            "trigger has been paused or isn't scheduled, it has been removed from the queue"
         );
     }
 
@@ -445,7 +689,9 @@ async fn requeue_next_triggertime(
             period,
             cron,
             trigger_offset,
-            catchup
+            catchup,
+            catchup_max_runs,
+            catchup_max_age
         FROM trigger
         WHERE id = $1
     ",
@@ -471,53 +717,70 @@ async fn requeue_next_triggertime(
 pub async fn trigger_cluster_changes(server: Arc<Server>) -> Result<!> {
     let mut cluster_rx = server.on_cluster_membership_change.subscribe();
     let mut change_tx = server.post_office.post_mail::<TriggerChange>().await?;
+    let mut amqp_change_tx = server.post_office.post_mail::<AmqpTriggerChange>().await?;
     let mut current_triggers = HashSet::new();
+    let mut current_amqp_triggers = HashSet::new();
 
     loop {
         info!("cluster membership changed");
-        let triggers = get_all_triggers(&server.db_pool).await?;
+        let (triggers, amqp_triggers) = get_all_triggers(&server.db_pool).await?;
 
-        let new_triggers = {
+        let (new_triggers, new_amqp_triggers) = {
             let rendezvous = cluster_rx.borrow();
 
-            let mut new_triggers = HashSet::new();
-            for trigger in triggers {
-                if rendezvous.item_is_mine(&server.node_id, &trigger) {
-                    new_triggers.insert(trigger);
-                }
-            }
-            new_triggers
+            let mine = |triggers: HashSet<Uuid>| -> HashSet<Uuid> {
+                triggers
+                    .into_iter()
+                    .filter(|trigger| rendezvous.item_is_mine(&server.node_id, trigger))
+                    .collect()
+            };
+            (mine(triggers), mine(amqp_triggers))
         };
 
-        let to_remove: Vec<_> = current_triggers
-            .difference(&new_triggers)
-            .map(deref)
-            .collect();
+        let (to_remove, to_add) = diff_triggers(&current_triggers, &new_triggers);
         trace!("removing triggers: {:?}", to_remove);
         info!("removing {} triggers", to_remove.len());
         change_tx.send(TriggerChange::Remove(to_remove)).await?;
-
-        let to_add: Vec<_> = new_triggers
-            .difference(&current_triggers)
-            .map(deref)
-            .collect();
         trace!("adding triggers: {:?}", to_add);
         info!("adding {} triggers", to_add.len());
         change_tx.send(TriggerChange::Add(to_add)).await?;
 
+        let (to_remove, to_add) = diff_triggers(&current_amqp_triggers, &new_amqp_triggers);
+        info!(
+            "removing {} and adding {} AMQP triggers",
+            to_remove.len(),
+            to_add.len()
+        );
+        amqp_change_tx
+            .send(AmqpTriggerChange(TriggerChange::Remove(to_remove)))
+            .await?;
+        amqp_change_tx
+            .send(AmqpTriggerChange(TriggerChange::Add(to_add)))
+            .await?;
+
         current_triggers = new_triggers;
+        current_amqp_triggers = new_amqp_triggers;
 
         cluster_rx.changed().await?;
     }
 }
 
+/// Get the triggers to remove and add to get from the current to the new triggers
+fn diff_triggers(current: &HashSet<Uuid>, new: &HashSet<Uuid>) -> (Vec<Uuid>, Vec<Uuid>) {
+    (
+        current.difference(new).map(deref).collect(),
+        new.difference(current).map(deref).collect(),
+    )
+}
+
 pub async fn trigger_update(server: Arc<Server>, update: TriggerUpdate) -> Result<()> {
     let mut change_tx = server.post_office.post_mail::<TriggerChange>().await?;
+    let mut amqp_change_tx = server.post_office.post_mail::<AmqpTriggerChange>().await?;
 
     let TriggerUpdate(uuids) = update;
     trace!(?uuids, "got trigger update");
 
-    let to_add: Vec<_> = {
+    let mine: Vec<_> = {
         let rendezvous = server.on_cluster_membership_change.borrow();
         uuids
             .iter()
@@ -526,25 +789,158 @@ pub async fn trigger_update(server: Arc<Server>, update: TriggerUpdate) -> Resul
             .collect()
     };
 
-    trace!(?to_add, "filtered triggers by rendezvous hash");
+    trace!(?mine, "filtered triggers by rendezvous hash");
+
+    if mine.is_empty() {
+        return Ok(());
+    }
 
+    // triggers which no longer exist have been removed from their job, and triggers may have
+    // changed kind so they have to be removed from whatever was running them before
+    let kinds: HashMap<Uuid, TriggerKind> = sqlx::query_as(
+        "SELECT id, kind
+        FROM trigger
+        WHERE id = ANY($1)",
+    )
+    .bind(&mine)
+    .fetch_all(&server.db_pool)
+    .await?
+    .into_iter()
+    .collect();
+
+    let has_kind = |id: &Uuid, kind: TriggerKind| kinds.get(id) == Some(&kind);
+
+    let (to_add, to_remove): (Vec<_>, Vec<_>) = mine
+        .iter()
+        .copied()
+        .partition(|id| has_kind(id, TriggerKind::Time));
+    if !to_remove.is_empty() {
+        trace!(?to_remove, "removing deleted or unscheduled triggers");
+        change_tx.send(TriggerChange::Remove(to_remove)).await?;
+    }
     if !to_add.is_empty() {
         change_tx.send(TriggerChange::Add(to_add)).await?;
     }
 
+    let (to_add, to_remove): (Vec<_>, Vec<_>) = mine
+        .into_iter()
+        .partition(|id| has_kind(id, TriggerKind::Amqp));
+    if !to_remove.is_empty() {
+        amqp_change_tx
+            .send(AmqpTriggerChange(TriggerChange::Remove(to_remove)))
+            .await?;
+    }
+    if !to_add.is_empty() {
+        amqp_change_tx
+            .send(AmqpTriggerChange(TriggerChange::Add(to_add)))
+            .await?;
+    }
+
     Ok(())
 }
 
-async fn get_all_triggers(db: &PgPool) -> Result<HashSet<Uuid>> {
-    let triggers = sqlx::query_as(
+/// Get the unpaused triggers run by the scheduler - the time triggers and the AMQP triggers
+async fn get_all_triggers(db: &PgPool) -> Result<(HashSet<Uuid>, HashSet<Uuid>)> {
+    let triggers: Vec<(Uuid, TriggerKind)> = sqlx::query_as(
         "
-        SELECT t.id
+        SELECT t.id, t.kind
         FROM trigger t
         JOIN job j ON t.job_id = j.id
-        WHERE NOT j.paused",
+        WHERE NOT j.paused
+        AND t.kind IN ('time', 'amqp')",
     )
     .fetch_all(db)
     .await?;
 
-    Ok(triggers.into_iter().map(first).collect())
+    let (time, amqp): (Vec<_>, Vec<_>) = triggers
+        .into_iter()
+        .partition(|(_, kind)| *kind == TriggerKind::Time);
+
+    Ok((
+        time.into_iter().map(|(id, _)| id).collect(),
+        amqp.into_iter().map(|(id, _)| id).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn trigger(period: Option<i64>, cron: Option<&str>, offset: Option<i64>) -> Trigger {
+        Trigger {
+            id: Uuid::nil(),
+            start_datetime: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            end_datetime: Some(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()),
+            earliest_trigger_datetime: None,
+            latest_trigger_datetime: None,
+            period,
+            cron: cron.map(str::to_owned),
+            trigger_offset: offset,
+            catchup: Catchup::Earliest,
+            catchup_max_runs: None,
+            catchup_max_age: None,
+        }
+    }
+
+    #[test]
+    fn test_forecast() -> Result<()> {
+        let daily = trigger(Some(86400), None, Some(3600));
+        let after = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+
+        let times = daily.forecast(after, None, 3)?;
+        let scheduled: Vec<_> = times.iter().map(|t| t.scheduled_datetime).collect();
+        assert_eq!(
+            scheduled,
+            vec![
+                Utc.with_ymd_and_hms(2024, 1, 11, 1, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 1, 12, 1, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 1, 13, 1, 0, 0).unwrap(),
+            ]
+        );
+        assert_eq!(
+            times[0].trigger_datetime,
+            Utc.with_ymd_and_hms(2024, 1, 11, 0, 0, 0).unwrap()
+        );
+
+        // stops at the end of the trigger
+        let times = daily.forecast(after, None, 100)?;
+        assert_eq!(times.len(), 21);
+
+        let until = Utc.with_ymd_and_hms(2024, 1, 12, 1, 0, 0).unwrap();
+        assert_eq!(daily.forecast(after, Some(until), 100)?.len(), 1);
+
+        let weekly = trigger(None, Some("0 0 9 * * Mon *"), None);
+        let times = weekly.forecast(after, None, 2)?;
+        let scheduled: Vec<_> = times.iter().map(|t| t.scheduled_datetime).collect();
+        assert_eq!(
+            scheduled,
+            vec![
+                Utc.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap(),
+                Utc.with_ymd_and_hms(2024, 1, 22, 9, 0, 0).unwrap(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_too_old_to_catchup() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 12, 0, 0).unwrap();
+        let missed: Vec<_> = (1..10)
+            .map(|day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap())
+            .collect();
+
+        let mut daily = trigger(Some(86400), None, None);
+        assert_eq!(daily.too_old_to_catchup(&missed, now), 0);
+
+        daily.catchup_max_runs = Some(3);
+        assert_eq!(daily.too_old_to_catchup(&missed, now), 6);
+
+        daily.catchup_max_age = Some(2 * 86400);
+        assert_eq!(daily.too_old_to_catchup(&missed, now), 8);
+
+        daily.catchup_max_runs = Some(20);
+        assert_eq!(daily.too_old_to_catchup(&missed, now), 8);
+    }
 }
@@ -26,6 +26,7 @@ pub mod env;
 pub mod heartbeat;
 mod kube;
 mod kubejob;
+mod sensor;
 pub mod work;
 
 // TODO - move these statics
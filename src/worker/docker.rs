@@ -1,6 +1,10 @@
 use crate::{
     messages::{TaskDef, TaskRequest},
-    worker::{Worker, engine::TaskEngineImpl, env},
+    worker::{
+        Worker,
+        engine::{TaskEngineImpl, TaskExit},
+        env,
+    },
 };
 use anyhow::Result;
 use bollard::{
@@ -24,12 +28,12 @@ impl TaskEngineImpl for DockerEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
+    ) -> Result<TaskExit> {
         run_docker(worker, task_req, task_def).await
     }
 }
 
-async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<bool> {
+async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<TaskExit> {
     let docker = bollard::Docker::connect_with_local_defaults()?;
 
     let env = env::get_env_string(worker, &task_req, &task_def)?;
@@ -141,9 +145,20 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
     let mut waiter = docker.wait_container(&container.id, None::<WaitContainerOptions<String>>);
 
     let mut exit = 0;
-    while let Some(x) = waiter.try_next().await? {
-        trace!(id=?container.id, "container exit code: {}", x.status_code);
-        exit = x.status_code;
+    loop {
+        match waiter.try_next().await {
+            Ok(Some(x)) => {
+                trace!(id=?container.id, "container exit code: {}", x.status_code);
+                exit = x.status_code;
+            }
+            Ok(None) => break,
+            // bollard reports a non-zero exit code as an error
+            Err(bollard::errors::Error::DockerContainerWaitError { code, .. }) => {
+                trace!(id=?container.id, "container exit code: {}", code);
+                exit = code;
+            }
+            Err(err) => return Err(err.into()),
+        }
     }
 
     // ____________________________________________________
@@ -154,5 +169,8 @@ async fn run_docker(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -
 
     trace!(id=?container.id, "container removed");
 
-    Ok(exit == 0)
+    Ok(TaskExit {
+        success: exit == 0,
+        exit_code: Some(exit),
+    })
 }
@@ -50,6 +50,14 @@ impl TaskEngine {
     }
 }
 
+/// How a task's container finished
+#[derive(Debug)]
+pub struct TaskExit {
+    pub success: bool,
+    /// not every engine can report the exit code
+    pub exit_code: Option<i64>,
+}
+
 #[async_trait::async_trait]
 pub trait TaskEngineImpl {
     async fn run_task(
@@ -57,14 +65,17 @@ pub trait TaskEngineImpl {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool>;
+    ) -> Result<TaskExit>;
 }
 
 #[cfg(debug_assertions)]
 mod null {
     use crate::{
         messages::{TaskDef, TaskRequest},
-        worker::{Worker, engine::TaskEngineImpl},
+        worker::{
+            Worker,
+            engine::{TaskEngineImpl, TaskExit},
+        },
     };
 
     pub struct NullEngine;
@@ -76,8 +87,11 @@ mod null {
             _worker: &Worker,
             _task_req: TaskRequest,
             _task_def: TaskDef,
-        ) -> anyhow::Result<bool> {
-            Ok(true)
+        ) -> anyhow::Result<TaskExit> {
+            Ok(TaskExit {
+                success: true,
+                exit_code: Some(0),
+            })
         }
     }
 }
@@ -29,13 +29,18 @@ fn envvar(name: &str, val: impl std::fmt::Display) -> EnvVar {
 }
 
 pub fn get_env(worker: &Worker, task_req: &TaskRequest, task_def: &TaskDef) -> Result<Vec<EnvVar>> {
-    let provided_env = task_def.env.clone().unwrap_or_default();
+    let provided_env = task_def.env.iter().flatten();
+    // overrides from a manual activation replace variables with the same name
+    let overrides = task_req.env_overrides.iter().flatten();
 
-    let mut env = vec![];
+    let mut env: Vec<EnvVar> = vec![];
 
-    for kv in provided_env {
+    for kv in provided_env.chain(overrides) {
         if let Some((k, v)) = kv.splitn(2, '=').collect_tuple() {
-            env.push(envvar(k, v));
+            match env.iter_mut().find(|ev| ev.name == k) {
+                Some(existing) => existing.value = Some(v.to_owned()),
+                None => env.push(envvar(k, v)),
+            }
         } else {
             return Err(anyhow::Error::msg(
                 "invalid environment variable (only KEY=VALUE syntax is supported)",
@@ -59,6 +64,12 @@ pub fn get_env(worker: &Worker, task_req: &TaskRequest, task_def: &TaskDef) -> R
     env.push(envvar("WATERWHEEL_PROJECT_ID", task_def.project_id));
     env.push(envvar("WATERWHEEL_SERVER_ADDR", server_addr));
 
+    if let Some(map) = &task_req.map {
+        env.push(envvar("WATERWHEEL_MAP_INDEX", map.index));
+        env.push(envvar("WATERWHEEL_MAP_COUNT", map.count));
+        env.push(envvar("WATERWHEEL_MAP_ITEM", &map.item));
+    }
+
     let stash_jwt = jwt::generate_stash_jwt(&worker.jwt_keys, &task_req.task_id.to_string())?;
     env.push(envvar("WATERWHEEL_JWT", stash_jwt));
 
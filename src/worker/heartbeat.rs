@@ -21,6 +21,7 @@ pub async fn post_heartbeat(config: &Config, client: &reqwest::Client) -> Result
             last_seen_datetime: Utc::now(),
             running_tasks: RUNNING_TASKS.get(),
             total_tasks: TOTAL_TASKS.get(),
+            max_tasks: Some(config.max_tasks as i32),
             version: GIT_VERSION.to_owned(),
         })
         .send()
@@ -1,6 +1,11 @@
 use crate::{
     messages::{TaskDef, TaskRequest},
-    worker::{WORKER_ID, Worker, config_cache::get_project_config, engine::TaskEngineImpl, env},
+    worker::{
+        WORKER_ID, Worker,
+        config_cache::get_project_config,
+        engine::{TaskEngineImpl, TaskExit},
+        env,
+    },
 };
 use anyhow::Result;
 use futures::{AsyncBufReadExt, StreamExt, TryStreamExt};
@@ -26,12 +31,16 @@ impl TaskEngineImpl for KubeEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
+    ) -> Result<TaskExit> {
         run_kube(worker, task_req, task_def).await
     }
 }
 
-pub async fn run_kube(worker: &Worker, task_req: TaskRequest, task_def: TaskDef) -> Result<bool> {
+pub async fn run_kube(
+    worker: &Worker,
+    task_req: TaskRequest,
+    task_def: TaskDef,
+) -> Result<TaskExit> {
     trace!("loading kubernetes config");
     let kube_config = Config::infer().await?;
     trace!("kubernetes namespace {}", kube_config.default_namespace);
@@ -51,6 +60,7 @@ pub async fn run_kube(worker: &Worker, task_req: TaskRequest, task_def: TaskDef)
     let mut watcher = kube_runtime::watcher::watch_object(pods.clone(), &name).boxed();
 
     let mut result = false;
+    let mut exit_code = None;
 
     trace!(pod_name=%name, "watching pod");
 
@@ -65,6 +75,14 @@ pub async fn run_kube(worker: &Worker, task_req: TaskRequest, task_def: TaskDef)
                 let phase = status.phase.clone().unwrap_or_default();
                 trace!(pod_name=%pod.name_any(), "pod modified, phase is '{}'", phase);
 
+                exit_code = status
+                    .container_statuses
+                    .iter()
+                    .flatten()
+                    .find(|cs| cs.name == "task")
+                    .and_then(|cs| cs.state.as_ref()?.terminated.as_ref())
+                    .map(|terminated| terminated.exit_code.into());
+
                 if phase == "Succeeded" {
                     result = true;
                     break;
@@ -124,7 +142,10 @@ pub async fn run_kube(worker: &Worker, task_req: TaskRequest, task_def: TaskDef)
     }
     trace!(pod_name=%name, "deleted pod");
 
-    Ok(result)
+    Ok(TaskExit {
+        success: result,
+        exit_code,
+    })
 }
 
 // TODO - make this a util, we should use this grist in a few other places too
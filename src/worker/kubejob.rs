@@ -1,6 +1,11 @@
 use crate::{
     messages::{TaskDef, TaskRequest},
-    worker::{WORKER_ID, Worker, config_cache::get_project_config, engine::TaskEngineImpl, env},
+    worker::{
+        WORKER_ID, Worker,
+        config_cache::get_project_config,
+        engine::{TaskEngineImpl, TaskExit},
+        env,
+    },
 };
 use anyhow::Result;
 use futures::{StreamExt, TryStreamExt};
@@ -21,8 +26,13 @@ impl TaskEngineImpl for KubeJobEngine {
         worker: &Worker,
         task_req: TaskRequest,
         task_def: TaskDef,
-    ) -> Result<bool> {
-        run_kubejob(worker, task_req, task_def).await
+    ) -> Result<TaskExit> {
+        // the job's pods aren't watched, so the exit code isn't known
+        let success = run_kubejob(worker, task_req, task_def).await?;
+        Ok(TaskExit {
+            success,
+            exit_code: None,
+        })
     }
 }
 
@@ -0,0 +1,89 @@
+use crate::{
+    messages::{SensorCondition, StashScope, TaskDef, TaskRequest},
+    server::api::jwt,
+    worker::Worker,
+};
+use anyhow::Result;
+use chrono::SecondsFormat;
+use reqwest::StatusCode;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+/// how long a single check of a sensor's condition may take
+const POKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Check a sensor's condition once. Returns true if the condition holds, false if the task
+/// should be poked again later.
+pub async fn poke(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    condition: &SensorCondition,
+) -> Result<bool> {
+    let holds = match condition {
+        SensorCondition::Http { url, status } => poke_http(url, *status).await?,
+        SensorCondition::File { path } => tokio::fs::try_exists(path).await?,
+        SensorCondition::Stash { key, scope } => {
+            poke_stash(worker, task_req, task_def, key, *scope).await?
+        }
+    };
+
+    debug!(?condition, holds, "poked sensor");
+    Ok(holds)
+}
+
+fn client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().timeout(POKE_TIMEOUT).build()?)
+}
+
+async fn poke_http(url: &str, status: Option<u16>) -> Result<bool> {
+    // connection errors mean the condition doesn't hold yet, rather than an error
+    let resp = match client()?.get(url).send().await {
+        Ok(resp) => resp,
+        Err(err) => {
+            trace!(url, "sensor request failed: {}", err);
+            return Ok(false);
+        }
+    };
+
+    Ok(match status {
+        Some(status) => resp.status().as_u16() == status,
+        None => resp.status().is_success(),
+    })
+}
+
+async fn poke_stash(
+    worker: &Worker,
+    task_req: &TaskRequest,
+    task_def: &TaskDef,
+    key: &str,
+    scope: StashScope,
+) -> Result<bool> {
+    let token = "Bearer ".to_owned()
+        + &jwt::generate_stash_jwt(&worker.jwt_keys, &task_req.task_id.to_string())?;
+
+    let path = match scope {
+        StashScope::Global => format!("int-api/stash/{key}"),
+        StashScope::Project => format!("int-api/projects/{}/stash/{key}", task_def.project_id),
+        StashScope::Job => format!(
+            "int-api/jobs/{}/stash/{}/{key}",
+            task_def.job_id,
+            task_req
+                .trigger_datetime
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+        ),
+    };
+    let url = reqwest::Url::parse(&worker.config.server_addr)?.join(&path)?;
+
+    let resp = client()?
+        .get(url)
+        .header(reqwest::header::AUTHORIZATION, token)
+        .send()
+        .await?;
+
+    match resp.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        otherwise => anyhow::bail!("unexpected status code while reading stash: {}", otherwise),
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     config::Config,
     instrumented,
     messages::{TaskProgress, TaskRequest, TokenState},
-    worker::{Worker, config_cache},
+    worker::{Worker, config_cache, sensor},
 };
 use anyhow::Result;
 use cadence::{CountedExt, Gauged};
@@ -137,14 +137,27 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
 
             let maybe_task_def = config_cache::get_task_def(&worker, task_req.task_id).await?;
 
-            let result = if let Some(task_def) = maybe_task_def {
+            let (result, exit_code) = if let Some(task_def) = maybe_task_def {
                 if task_def.paused {
                     // job has been paused - task will get rerun by the
                     // requeue processor when the job is unpaused
-                    TokenState::Cancelled
+                    (TokenState::Cancelled, None)
+                } else if let Some(condition) = &task_def.sensor {
+                    // the scheduler pokes the sensor again later if the condition doesn't
+                    // hold, so the worker isn't kept busy waiting
+                    let result = match sensor::poke(&worker, &task_req, &task_def, condition).await
+                    {
+                        Ok(true) => TokenState::Success,
+                        Ok(false) => TokenState::Sensing,
+                        Err(err) => {
+                            error!("failed to poke sensor: {:#}", err);
+                            TokenState::Error
+                        }
+                    };
+                    (result, None)
                 } else if task_def.image.is_none() {
                     // task has no image, mark success immediately
-                    TokenState::Success
+                    (TokenState::Success, None)
                 } else {
                     let task_timeout = task_def.timeout.unwrap_or(default_task_timeout);
 
@@ -157,7 +170,7 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
                         tokio::select! {
                             _ = &mut timeout => {
                                 error!("timeout running task");
-                                break TokenState::Timeout;
+                                break (TokenState::Timeout, None);
                             }
                             _ = ticker.tick() => {
                                 trace!("task heartbeat");
@@ -165,13 +178,14 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
                             }
                             result = &mut task => {
                                 trace!("task engine returned: {:?}", result);
-                                break TokenState::from_result(result);
+                                let exit_code = result.as_ref().ok().and_then(|exit| exit.exit_code);
+                                break (TokenState::from_result(result.map(|exit| exit.success)), exit_code);
                             }
                         }
                     }
                 }
             } else {
-                TokenState::Error
+                (TokenState::Error, None)
             };
 
             let finished_datetime = Utc::now();
@@ -193,7 +207,9 @@ pub async fn process_work(worker: Arc<Worker>) -> Result<!> {
                 started_datetime=?progress.started_datetime.to_rfc3339(),
                 "task completed");
 
-            progress.finish(finished_datetime, result).await?;
+            progress
+                .finish(finished_datetime, result, exit_code)
+                .await?;
 
             delivery.ack(BasicAckOptions::default()).await?;
             debug!("task acked");
@@ -211,17 +227,24 @@ struct ProgressPublisher<'a> {
 
 impl ProgressPublisher<'_> {
     async fn publish(&self, result: TokenState) -> Result<()> {
-        self.do_publish(None, result).await
+        self.do_publish(None, result, None).await
     }
 
-    async fn finish(&self, finished_datetime: DateTime<Utc>, result: TokenState) -> Result<()> {
-        self.do_publish(Some(finished_datetime), result).await
+    async fn finish(
+        &self,
+        finished_datetime: DateTime<Utc>,
+        result: TokenState,
+        exit_code: Option<i64>,
+    ) -> Result<()> {
+        self.do_publish(Some(finished_datetime), result, exit_code)
+            .await
     }
 
     async fn do_publish(
         &self,
         finished_datetime: Option<DateTime<Utc>>,
         result: TokenState,
+        exit_code: Option<i64>,
     ) -> Result<()> {
         let payload = serde_json::to_vec(&TaskProgress {
             task_run_id: self.task_req.task_run_id,
@@ -231,6 +254,7 @@ impl ProgressPublisher<'_> {
             finished_datetime,
             worker_id: *WORKER_ID,
             result,
+            exit_code,
         })?;
 
         self.chan
@@ -0,0 +1,573 @@
+use highnoon::StatusCode;
+use pretty_assertions::assert_eq;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use uuid::Uuid;
+use waterwheel::server::api::make_app;
+
+mod common;
+
+const PROJECT_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+async fn get_task_id(pool: &PgPool, job_id: &str, name: &str) -> highnoon::Result<Uuid> {
+    let (id,): (Uuid,) = sqlx::query_as(
+        "SELECT id
+        FROM task
+        WHERE job_id = $1
+        AND name = $2",
+    )
+    .bind(Uuid::parse_str(job_id)?)
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+    Ok(id)
+}
+
+/// Count the linked and pending edges into a task from other tasks
+async fn count_edges_into(pool: &PgPool, task_id: Uuid) -> highnoon::Result<(i64, i64)> {
+    let counts = sqlx::query_as(
+        "SELECT
+            (SELECT COUNT(*) FROM task_edge WHERE child_task_id = $1),
+            (SELECT COUNT(*) FROM pending_edge WHERE task_id = $1)",
+    )
+    .bind(task_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(counts)
+}
+
+/// Get the ends of the edges in a job diff, as (from, to) pairs
+fn edge_ends(edges: &Value) -> Vec<(String, String)> {
+    edges
+        .as_array()
+        .expect("edges are a list")
+        .iter()
+        .map(|e| {
+            (
+                e["from"].as_str().expect("from is a string").to_owned(),
+                e["to"].as_str().expect("to is a string").to_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Get the values of a field from each item in a list
+fn names(list: &Value, field: &str) -> Vec<String> {
+    list.as_array()
+        .expect("response is a list")
+        .iter()
+        .map(|item| item[field].as_str().expect("field is a string").to_owned())
+        .collect()
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_upsert_removes_members() -> highnoon::Result<()> {
+    common::with_external_services(|config| async {
+        let tc = make_app(config).await?.test();
+
+        // CREATE A PROJECT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // CREATE A JOB WITH TWO TRIGGERS AND THREE TASKS
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "reconciled_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": true,
+                "triggers": [
+                    { "name": "daily", "start": "2000-01-01T00:00:00Z", "period": "1d" },
+                    { "name": "hourly", "start": "2000-01-01T00:00:00Z", "period": "1h" },
+                ],
+                "tasks": [
+                    {
+                        "name": "a",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/daily"],
+                    },
+                    {
+                        "name": "b",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/a"],
+                    },
+                    {
+                        "name": "c",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/hourly"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // UPDATE IT, DROPPING A TRIGGER, TWO TASKS AND THEIR EDGES
+        let mut resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "reconciled_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": true,
+                "triggers": [
+                    { "name": "daily", "start": "2000-01-01T00:00:00Z", "period": "1d" },
+                ],
+                "tasks": [
+                    {
+                        "name": "a",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/daily"],
+                    },
+                    {
+                        "name": "b2",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/daily"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // THE DIFF LISTS WHAT WAS REMOVED
+        let diff: Value = resp.body_json().await?;
+        assert_eq!(diff["created"], json!(false));
+        assert_eq!(
+            diff["triggers"],
+            json!({ "added": [], "updated": ["daily"], "removed": ["hourly"] })
+        );
+        assert_eq!(
+            diff["tasks"],
+            json!({ "added": ["b2"], "updated": ["a"], "removed": ["b", "c"] })
+        );
+
+        let edge = |from: &str, to: &str| {
+            (
+                format!("integration_tests/reconciled_job/{from}"),
+                format!("integration_tests/reconciled_job/task/{to}"),
+            )
+        };
+        assert_eq!(
+            edge_ends(&diff["edges"]["added"]),
+            vec![edge("trigger/daily", "b2")]
+        );
+        assert_eq!(
+            edge_ends(&diff["edges"]["removed"]),
+            vec![edge("task/a", "b"), edge("trigger/hourly", "c")]
+        );
+
+        // ONLY THE REMAINING TRIGGERS AND TASKS ARE LISTED
+        let mut resp = tc
+            .get(format!("/api/jobs/{job_uuid}/triggers"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let triggers: Value = resp.body_json().await?;
+        assert_eq!(names(&triggers, "trigger_name"), vec!["daily"]);
+
+        let mut resp = tc.get(format!("/api/jobs/{job_uuid}/tasks")).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let tasks: Value = resp.body_json().await?;
+        assert_eq!(names(&tasks, "name"), vec!["a", "b2"]);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_archive_and_purge() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // CREATE AN UPSTREAM JOB AND A JOB DEPENDING ON IT
+        let upstream_uuid = "00000000-0000-0000-0000-000000000001";
+        let upstream = json!({
+            "uuid": upstream_uuid,
+            "name": "upstream_job",
+            "project": "integration_tests",
+            "description": "A test job",
+            "paused": false,
+            "triggers": [],
+            "tasks": [
+                {
+                    "name": "x",
+                    "docker": { "image": "bash", "args": [] },
+                },
+            ],
+        });
+        let resp = tc.post("/api/jobs").json(&upstream)?.send().await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let downstream_uuid = "00000000-0000-0000-0000-000000000002";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": downstream_uuid,
+                "name": "downstream_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "y",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["integration_tests/upstream_job/task/x"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let x_id = get_task_id(&pool, upstream_uuid, "x").await?;
+        let y_id = get_task_id(&pool, downstream_uuid, "y").await?;
+        assert_eq!(count_edges_into(&pool, y_id).await?, (1, 0));
+
+        // GIVE THE UPSTREAM TASK SOME HISTORY
+        let run_id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO task_run(id, task_id, trigger_datetime, queued_datetime,
+                finish_datetime, state, priority, attempt)
+            VALUES ($1, $2, '2000-01-01T00:00:00Z', CURRENT_TIMESTAMP,
+                CURRENT_TIMESTAMP, 'success', 'normal', 1)",
+        )
+        .bind(run_id)
+        .bind(x_id)
+        .execute(&pool)
+        .await?;
+
+        let count_runs = || async {
+            let (count,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*)
+                FROM task_run
+                WHERE id = $1",
+            )
+            .bind(run_id)
+            .fetch_one(&pool)
+            .await?;
+            highnoon::Result::Ok(count)
+        };
+
+        // ARCHIVE THE UPSTREAM JOB
+        let resp = tc
+            .delete(format!("/api/jobs/{upstream_uuid}"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // IT IS ONLY LISTED WHEN ASKING FOR ARCHIVED JOBS
+        let mut resp = tc
+            .get(format!("/api/projects/{PROJECT_UUID}/jobs"))
+            .send()
+            .await?;
+        let jobs: Value = resp.body_json().await?;
+        assert_eq!(names(&jobs, "name"), vec!["downstream_job"]);
+
+        let mut resp = tc
+            .get(format!("/api/projects/{PROJECT_UUID}/jobs?archived=true"))
+            .send()
+            .await?;
+        let jobs: Value = resp.body_json().await?;
+        assert_eq!(names(&jobs, "name"), vec!["downstream_job", "upstream_job"]);
+        assert!(jobs[1]["archived_datetime"].is_string());
+        assert_eq!(jobs[1]["paused"], json!(true));
+
+        // THE DEPENDENCY IS PENDING BUT THE HISTORY IS KEPT
+        assert_eq!(count_edges_into(&pool, y_id).await?, (0, 1));
+        assert_eq!(count_runs().await?, 1);
+
+        // PURGE THE UPSTREAM JOB
+        let resp = tc
+            .delete(format!("/api/jobs/{upstream_uuid}?purge=true"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = tc.get(format!("/api/jobs/{upstream_uuid}")).send().await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // THE HISTORY IS GONE, THE DEPENDENCY IS STILL PENDING
+        assert_eq!(count_runs().await?, 0);
+        assert_eq!(count_edges_into(&pool, y_id).await?, (0, 1));
+
+        // CREATING THE UPSTREAM JOB AGAIN LINKS THE DEPENDENCY
+        let mut resp = tc.post("/api/jobs").json(&upstream)?.send().await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let diff: Value = resp.body_json().await?;
+        assert_eq!(diff["created"], json!(true));
+        assert_eq!(
+            edge_ends(&diff["edges"]["added"]),
+            vec![(
+                "integration_tests/upstream_job/task/x".to_owned(),
+                "integration_tests/downstream_job/task/y".to_owned(),
+            )]
+        );
+        assert_eq!(count_edges_into(&pool, y_id).await?, (1, 0));
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_apply_project() -> highnoon::Result<()> {
+    common::with_external_services(|config| async {
+        let tc = make_app(config).await?.test();
+
+        // CREATE A PROJECT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_a_uuid = "00000000-0000-0000-0000-000000000001";
+        let job_b_uuid = "00000000-0000-0000-0000-000000000002";
+        let job = |uuid: &str, name: &str, description: &str, tasks: Value| {
+            json!({
+                "uuid": uuid,
+                "name": name,
+                "project": "integration_tests",
+                "description": description,
+                "paused": false,
+                "triggers": [],
+                "tasks": tasks,
+            })
+        };
+
+        let job_a = job(
+            job_a_uuid,
+            "job_a",
+            "A test job",
+            json!([{ "name": "a", "docker": { "image": "bash", "args": [] } }]),
+        );
+        let job_b = job(
+            job_b_uuid,
+            "job_b",
+            "A test job",
+            json!([{
+                "name": "b",
+                "docker": { "image": "bash", "args": [] },
+                "depends": ["integration_tests/job_a/task/a"],
+            }]),
+        );
+
+        let list_jobs = || async {
+            let mut resp = tc
+                .get(format!("/api/projects/{PROJECT_UUID}/jobs"))
+                .send()
+                .await?;
+            let jobs: Value = resp.body_json().await?;
+            highnoon::Result::Ok(names(&jobs, "name"))
+        };
+
+        // APPLY TWO JOBS, ONE DEPENDING ON THE OTHER
+        let mut resp = tc
+            .post(format!("/api/projects/{PROJECT_UUID}/apply"))
+            .json(json!([job_b, job_a]))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let applied: Value = resp.body_json().await?;
+        assert_eq!(names(&applied["jobs"], "name"), vec!["job_b", "job_a"]);
+        assert_eq!(applied["pruned"], json!([]));
+        assert_eq!(
+            edge_ends(&applied["jobs"][0]["edges"]["added"]),
+            vec![(
+                "integration_tests/job_a/task/a".to_owned(),
+                "integration_tests/job_b/task/b".to_owned(),
+            )]
+        );
+
+        // A CHANGE ALONGSIDE AN INVALID JOB ISN'T APPLIED
+        let mut resp = tc
+            .post(format!("/api/projects/{PROJECT_UUID}/apply"))
+            .json(json!([
+                job(
+                    job_a_uuid,
+                    "job_a",
+                    "A changed job",
+                    json!([{ "name": "a", "docker": { "image": "bash", "args": [] } }]),
+                ),
+                job(
+                    "00000000-0000-0000-0000-000000000003",
+                    "job_c",
+                    "An invalid job",
+                    json!([{
+                        "name": "c",
+                        "docker": { "image": "bash", "args": [] },
+                        "threshold": 0,
+                    }]),
+                ),
+            ]))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let failed: Value = resp.body_json().await?;
+        assert_eq!(names(&failed, "job"), vec!["job_c"]);
+
+        let mut resp = tc.get(format!("/api/jobs/{job_a_uuid}")).send().await?;
+        let got: Value = resp.body_json().await?;
+        assert_eq!(got["description"], json!("A test job"));
+        assert_eq!(list_jobs().await?, vec!["job_a", "job_b"]);
+
+        // PRUNING A JOB THAT AN APPLIED JOB DEPENDS ON IS REFUSED
+        let mut resp = tc
+            .post(format!("/api/projects/{PROJECT_UUID}/apply?prune=true"))
+            .json(json!([job_b]))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let failed: Value = resp.body_json().await?;
+        assert_eq!(names(&failed, "job"), vec!["job_b"]);
+        assert_eq!(list_jobs().await?, vec!["job_a", "job_b"]);
+
+        // APPLY ONE JOB AND PRUNE THE OTHER
+        let mut resp = tc
+            .post(format!("/api/projects/{PROJECT_UUID}/apply?prune=true"))
+            .json(json!([job_a]))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let applied: Value = resp.body_json().await?;
+        assert_eq!(names(&applied["jobs"], "name"), vec!["job_a"]);
+        assert_eq!(
+            applied["pruned"],
+            json!([{ "job_id": job_b_uuid, "name": "job_b" }])
+        );
+        assert_eq!(list_jobs().await?, vec!["job_a"]);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_pending_dependency() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // CREATE A JOB DEPENDING ON A JOB THAT DOESN'T EXIST YET
+        let downstream_uuid = "00000000-0000-0000-0000-000000000002";
+        let downstream = json!({
+            "uuid": downstream_uuid,
+            "name": "downstream_job",
+            "project": "integration_tests",
+            "description": "A test job",
+            "paused": false,
+            "triggers": [],
+            "tasks": [
+                {
+                    "name": "y",
+                    "docker": { "image": "bash", "args": [] },
+                    "depends": ["integration_tests/upstream_job/task/x"],
+                },
+            ],
+        });
+        let mut resp = tc.post("/api/jobs").json(&downstream)?.send().await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let diff: Value = resp.body_json().await?;
+        assert_eq!(diff["edges"]["added"], json!([]));
+        assert_eq!(
+            diff["warnings"],
+            json!([
+                "task 'y' depends on integration_tests/upstream_job/task/x which doesn't exist \
+                yet, it will be linked once it is created"
+            ])
+        );
+
+        let y_id = get_task_id(&pool, downstream_uuid, "y").await?;
+        assert_eq!(count_edges_into(&pool, y_id).await?, (0, 1));
+
+        // CREATING THE UPSTREAM JOB LINKS THE PENDING EDGE
+        let mut resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": "00000000-0000-0000-0000-000000000001",
+                "name": "upstream_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "x",
+                        "docker": { "image": "bash", "args": [] },
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let diff: Value = resp.body_json().await?;
+        assert_eq!(
+            edge_ends(&diff["edges"]["added"]),
+            vec![(
+                "integration_tests/upstream_job/task/x".to_owned(),
+                "integration_tests/downstream_job/task/y".to_owned(),
+            )]
+        );
+        assert_eq!(count_edges_into(&pool, y_id).await?, (1, 0));
+
+        // THE DOWNSTREAM JOB NO LONGER HAS WARNINGS
+        let mut resp = tc.post("/api/jobs").json(&downstream)?.send().await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let diff: Value = resp.body_json().await?;
+        assert_eq!(diff["warnings"], json!([]));
+        assert_eq!(diff["edges"]["added"], json!([]));
+        assert_eq!(count_edges_into(&pool, y_id).await?, (1, 0));
+
+        Ok(())
+    })
+    .await
+}
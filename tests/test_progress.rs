@@ -0,0 +1,920 @@
+use chrono::{DateTime, Utc};
+use highnoon::StatusCode;
+use lapin::{
+    BasicProperties, Channel,
+    options::{BasicPublishOptions, QueueDeclareOptions},
+    types::FieldTable,
+};
+use pretty_assertions::assert_eq;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+use waterwheel::server::{Server, api::make_app, execute, progress, retries, tokens};
+
+mod common;
+
+const TRIGGER_DATETIME: &str = "2000-01-01T00:00:00Z";
+
+async fn get_task_id(pool: &PgPool, job_id: &str, name: &str) -> highnoon::Result<Uuid> {
+    let (id,): (Uuid,) = sqlx::query_as(
+        "SELECT id
+        FROM task
+        WHERE job_id = $1
+        AND name = $2",
+    )
+    .bind(Uuid::parse_str(job_id)?)
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+    Ok(id)
+}
+
+async fn add_worker(pool: &PgPool) -> highnoon::Result<Uuid> {
+    let worker_id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO worker(id, last_seen_datetime)
+        VALUES ($1, CURRENT_TIMESTAMP)",
+    )
+    .bind(worker_id)
+    .execute(pool)
+    .await?;
+    Ok(worker_id)
+}
+
+/// Record a task as running on a worker, as if the scheduler had sent it
+async fn start_run(pool: &PgPool, worker_id: Uuid, task_id: Uuid) -> highnoon::Result<Uuid> {
+    let trigger_datetime: DateTime<Utc> = TRIGGER_DATETIME.parse()?;
+    let run_id = Uuid::new_v4();
+
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+        VALUES ($1, $2, 0, 'running')",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO task_run(id, task_id, trigger_datetime, queued_datetime,
+            started_datetime, worker_id, state, priority, attempt)
+        VALUES ($1, $2, $3, CURRENT_TIMESTAMP,
+            CURRENT_TIMESTAMP, $4, 'running', 'normal', 1)",
+    )
+    .bind(run_id)
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(worker_id)
+    .execute(pool)
+    .await?;
+
+    Ok(run_id)
+}
+
+/// Start processing task progress, returning a channel to publish results on
+async fn start_progress(server: &Arc<Server>) -> highnoon::Result<Channel> {
+    let amqp_chan = server.amqp_conn.create_channel().await?;
+    amqp_chan
+        .queue_declare(
+            "waterwheel.results",
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    tokio::spawn(progress::process_progress(server.clone()));
+
+    Ok(amqp_chan)
+}
+
+async fn publish_success(
+    amqp_chan: &Channel,
+    worker_id: Uuid,
+    run_id: Uuid,
+    task_id: Uuid,
+) -> highnoon::Result<()> {
+    publish_success_at(amqp_chan, worker_id, run_id, task_id, TRIGGER_DATETIME).await
+}
+
+async fn publish_success_at(
+    amqp_chan: &Channel,
+    worker_id: Uuid,
+    run_id: Uuid,
+    task_id: Uuid,
+    trigger_datetime: &str,
+) -> highnoon::Result<()> {
+    let payload = json!({
+        "task_run_id": run_id,
+        "task_id": task_id,
+        "trigger_datetime": trigger_datetime,
+        "started_datetime": Utc::now(),
+        "finished_datetime": Utc::now(),
+        "result": "success",
+        "worker_id": worker_id,
+        "exit_code": 0,
+    });
+    publish(amqp_chan, payload).await
+}
+
+async fn publish_result(
+    amqp_chan: &Channel,
+    worker_id: Uuid,
+    run_id: Uuid,
+    task_id: Uuid,
+    result: &str,
+    exit_code: Option<i64>,
+) -> highnoon::Result<()> {
+    let payload = json!({
+        "task_run_id": run_id,
+        "task_id": task_id,
+        "trigger_datetime": TRIGGER_DATETIME,
+        "started_datetime": Utc::now(),
+        "finished_datetime": Utc::now(),
+        "result": result,
+        "worker_id": worker_id,
+        "exit_code": exit_code,
+    });
+    publish(amqp_chan, payload).await
+}
+
+async fn publish(amqp_chan: &Channel, payload: Value) -> highnoon::Result<()> {
+    let payload = serde_json::to_vec(&payload)?;
+
+    amqp_chan
+        .basic_publish(
+            "",
+            "waterwheel.results",
+            BasicPublishOptions::default(),
+            &payload,
+            BasicProperties::default(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Start re-executing tasks when their retries (or pokes) come due
+fn start_retries(server: &Arc<Server>) {
+    tokio::spawn(retries::process_retries(server.clone()));
+}
+
+/// Start activating tokens which reach their threshold
+fn start_tokens(server: &Arc<Server>) {
+    tokio::spawn(tokens::process_tokens(server.clone()));
+}
+
+/// Start recording task runs for activated tokens (nothing runs them)
+fn start_executions(server: &Arc<Server>) {
+    tokio::spawn(execute::process_executions(server.clone()));
+}
+
+async fn insert_token(
+    pool: &PgPool,
+    task_id: Uuid,
+    trigger_datetime: &str,
+    count: i32,
+    state: &str,
+) -> highnoon::Result<()> {
+    let trigger_datetime: DateTime<Utc> = trigger_datetime.parse()?;
+    sqlx::query(
+        "INSERT INTO token(task_id, trigger_datetime, count, state)
+        VALUES ($1, $2, $3, $4)",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .bind(count)
+    .bind(state)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn get_token(pool: &PgPool, task_id: Uuid) -> highnoon::Result<(i32, String)> {
+    get_token_at(pool, task_id, TRIGGER_DATETIME).await
+}
+
+async fn get_token_at(
+    pool: &PgPool,
+    task_id: Uuid,
+    trigger_datetime: &str,
+) -> highnoon::Result<(i32, String)> {
+    let trigger_datetime: DateTime<Utc> = trigger_datetime.parse()?;
+    let token = sqlx::query_as(
+        "SELECT count, state
+        FROM token
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_one(pool)
+    .await?;
+    Ok(token)
+}
+
+async fn get_run_state(pool: &PgPool, run_id: Uuid) -> highnoon::Result<String> {
+    let (state,): (String,) = sqlx::query_as(
+        "SELECT state
+        FROM task_run
+        WHERE id = $1",
+    )
+    .bind(run_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(state)
+}
+
+async fn count_runs(pool: &PgPool, task_id: Uuid) -> highnoon::Result<(i64, i64)> {
+    let counts = sqlx::query_as(
+        "SELECT COUNT(*), MAX(attempt)
+        FROM task_run
+        WHERE task_id = $1",
+    )
+    .bind(task_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(counts)
+}
+
+async fn count_runs_at(
+    pool: &PgPool,
+    task_id: Uuid,
+    trigger_datetime: &str,
+) -> highnoon::Result<i64> {
+    let trigger_datetime: DateTime<Utc> = trigger_datetime.parse()?;
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*)
+        FROM task_run
+        WHERE task_id = $1
+        AND trigger_datetime = $2",
+    )
+    .bind(task_id)
+    .bind(trigger_datetime)
+    .fetch_one(pool)
+    .await?;
+    Ok(count)
+}
+
+/// Poll until a token has been activated, returning the task run recorded for it
+async fn wait_for_active_run(
+    pool: &PgPool,
+    task_id: Uuid,
+    trigger_datetime: &str,
+) -> highnoon::Result<Uuid> {
+    let trigger_datetime: DateTime<Utc> = trigger_datetime.parse()?;
+    for _ in 0..300 {
+        let run: Option<(Uuid,)> = sqlx::query_as(
+            "SELECT id
+            FROM task_run
+            WHERE task_id = $1
+            AND trigger_datetime = $2
+            AND state = 'active'",
+        )
+        .bind(task_id)
+        .bind(trigger_datetime)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some((run_id,)) = run {
+            return Ok(run_id);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("token was not activated");
+}
+
+/// Poll until a task run has finished with the given state
+async fn wait_for_run(pool: &PgPool, run_id: Uuid, state: &str) -> highnoon::Result<()> {
+    for _ in 0..300 {
+        if get_run_state(pool, run_id).await? == state {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("task progress was not processed");
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_progress_after_mark_token() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "progress_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "upstream",
+                        "docker": { "image": "bash", "args": [] },
+                    },
+                    {
+                        "name": "downstream",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/upstream"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let upstream_id = get_task_id(&pool, job_uuid, "upstream").await?;
+        let downstream_id = get_task_id(&pool, job_uuid, "downstream").await?;
+
+        // PRETEND BOTH TASKS ARE RUNNING ON A WORKER
+        let worker_id = add_worker(&pool).await?;
+        let upstream_run = start_run(&pool, worker_id, upstream_id).await?;
+        let downstream_run = start_run(&pool, worker_id, downstream_id).await?;
+
+        // MARK THE UPSTREAM TASK WHILE IT'S STILL RUNNING
+        let resp = tc
+            .put(format!(
+                "/api/tasks/{upstream_id}/tokens/{TRIGGER_DATETIME}/state"
+            ))
+            .json(json!({ "state": "success" }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // THE ABANDONED RUN REPORTS IN, FOLLOWED BY THE DOWNSTREAM RUN
+        let amqp_chan = start_progress(&server).await?;
+        publish_success(&amqp_chan, worker_id, upstream_run, upstream_id).await?;
+        publish_success(&amqp_chan, worker_id, downstream_run, downstream_id).await?;
+
+        // results are processed in order, so once the downstream run is done so is the upstream
+        wait_for_run(&pool, downstream_run, "success").await?;
+
+        // THE LATE REPORT DIDN'T ADVANCE THE DOWNSTREAM TASK A SECOND TIME
+        assert_eq!(get_run_state(&pool, upstream_run).await?, "error");
+        assert_eq!(get_token(&pool, downstream_id).await?.0, 1);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_skip_propagates_downstream() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH A BRANCH TWO TASKS DEEP
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "branch_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "check",
+                        "docker": { "image": "bash", "args": [] },
+                        "branches": { "names": ["load", "ignore"] },
+                    },
+                    {
+                        "name": "load",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/check#load"],
+                    },
+                    {
+                        "name": "transform",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/load"],
+                    },
+                    {
+                        "name": "publish",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/transform"],
+                    },
+                    {
+                        "name": "report",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/check", "task/transform"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let check_id = get_task_id(&pool, job_uuid, "check").await?;
+
+        // THE CHECK FINISHES WITHOUT CHOOSING A BRANCH
+        let worker_id = add_worker(&pool).await?;
+        let check_run = start_run(&pool, worker_id, check_id).await?;
+
+        let amqp_chan = start_progress(&server).await?;
+        publish_success(&amqp_chan, worker_id, check_run, check_id).await?;
+        wait_for_run(&pool, check_run, "success").await?;
+
+        // EVERYTHING ONLY DOWNSTREAM OF THE BRANCH IS SKIPPED
+        for name in ["load", "transform", "publish"] {
+            let task_id = get_task_id(&pool, job_uuid, name).await?;
+            assert_eq!(get_token(&pool, task_id).await?, (0, "skipped".to_owned()));
+        }
+
+        // A TASK WITH AN UPSTREAM THAT SUCCEEDED IS LEFT WAITING
+        let report_id = get_task_id(&pool, job_uuid, "report").await?;
+        assert_eq!(
+            get_token(&pool, report_id).await?,
+            (1, "waiting".to_owned())
+        );
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_exit_code_chooses_branch() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH A BRANCH CHOSEN BY EXIT CODE
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "exit_code_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "check",
+                        "docker": { "image": "bash", "args": [] },
+                        "branches": { "names": ["load", "skip"], "exit_codes": { "3": "skip" } },
+                    },
+                    {
+                        "name": "load",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/check#load"],
+                    },
+                    {
+                        "name": "report_nothing_new",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["task/check#skip"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let check_id = get_task_id(&pool, job_uuid, "check").await?;
+
+        // THE CHECK EXITS WITH A CODE MAPPED TO A BRANCH
+        let worker_id = add_worker(&pool).await?;
+        let check_run = start_run(&pool, worker_id, check_id).await?;
+
+        let amqp_chan = start_progress(&server).await?;
+        publish_result(
+            &amqp_chan,
+            worker_id,
+            check_run,
+            check_id,
+            "failure",
+            Some(3),
+        )
+        .await?;
+
+        // THE NON-ZERO EXIT CODE COUNTS AS SUCCESS
+        wait_for_run(&pool, check_run, "success").await?;
+        assert_eq!(get_token(&pool, check_id).await?.1, "success");
+
+        // ONLY THE CHOSEN BRANCH RECEIVES A TOKEN
+        let load_id = get_task_id(&pool, job_uuid, "load").await?;
+        assert_eq!(get_token(&pool, load_id).await?, (0, "skipped".to_owned()));
+
+        let report_id = get_task_id(&pool, job_uuid, "report_nothing_new").await?;
+        assert_eq!(get_token(&pool, report_id).await?.0, 1);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_sensor_poke_reuses_run() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH A SENSOR
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "sensor_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "ready",
+                        "sensor": { "kind": "file", "path": "/data/ready", "poke_interval": "1s" },
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let ready_id = get_task_id(&pool, job_uuid, "ready").await?;
+
+        // THE CONDITION DOESN'T HOLD YET
+        let worker_id = add_worker(&pool).await?;
+        let ready_run = start_run(&pool, worker_id, ready_id).await?;
+
+        let amqp_chan = start_progress(&server).await?;
+        start_retries(&server);
+        start_executions(&server);
+        publish_result(&amqp_chan, worker_id, ready_run, ready_id, "sensing", None).await?;
+
+        // THE SENSOR IS POKED AGAIN ON THE SAME RUN
+        wait_for_run(&pool, ready_run, "active").await?;
+
+        // WITHOUT USING UP AN ATTEMPT OR TAKING FROM THE TOKEN'S COUNT AGAIN
+        assert_eq!(count_runs(&pool, ready_id).await?, (1, 1));
+        assert_eq!(get_token(&pool, ready_id).await?, (0, "active".to_owned()));
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_sensor_times_out() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH A SENSOR
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "sensor_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    {
+                        "name": "ready",
+                        "sensor": { "kind": "file", "path": "/data/ready", "timeout": "1h" },
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let ready_id = get_task_id(&pool, job_uuid, "ready").await?;
+
+        // THE SENSOR STARTED POKING LONGER AGO THAN ITS TIMEOUT
+        let worker_id = add_worker(&pool).await?;
+        let ready_run = start_run(&pool, worker_id, ready_id).await?;
+
+        sqlx::query(
+            "UPDATE task_run
+            SET started_datetime = CURRENT_TIMESTAMP - INTERVAL '2 hours'
+            WHERE id = $1",
+        )
+        .bind(ready_run)
+        .execute(&pool)
+        .await?;
+
+        let amqp_chan = start_progress(&server).await?;
+        publish_result(&amqp_chan, worker_id, ready_run, ready_id, "sensing", None).await?;
+
+        // IT TIMES OUT INSTEAD OF BEING POKED AGAIN
+        wait_for_run(&pool, ready_run, "timeout").await?;
+        assert_eq!(get_token(&pool, ready_id).await?.1, "timeout");
+
+        let (retries,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*)
+            FROM retry
+            WHERE task_run_id = $1",
+        )
+        .bind(ready_run)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(retries, 0);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_clear_downstream_reruns_once() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A DIAMOND SHAPED JOB, WITH AN OFFSET EDGE ACROSS IT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "diamond_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    { "name": "a" },
+                    { "name": "b", "depends": ["task/a"] },
+                    { "name": "c", "depends": ["task/a"] },
+                    { "name": "d", "depends": ["task/b", "task/c", "task/a@1d"] },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let a_id = get_task_id(&pool, job_uuid, "a").await?;
+        let b_id = get_task_id(&pool, job_uuid, "b").await?;
+        let c_id = get_task_id(&pool, job_uuid, "c").await?;
+        let d_id = get_task_id(&pool, job_uuid, "d").await?;
+
+        // EVERYTHING HAS ALREADY RUN
+        let day1 = "2000-01-02T00:00:00Z";
+        let day2 = "2000-01-03T00:00:00Z";
+        insert_token(&pool, a_id, TRIGGER_DATETIME, 0, "success").await?;
+        for (task_id, trigger_datetime) in [
+            (a_id, day1),
+            (b_id, day1),
+            (c_id, day1),
+            (d_id, day1),
+            (d_id, day2),
+        ] {
+            insert_token(&pool, task_id, trigger_datetime, 0, "success").await?;
+        }
+
+        // CLEAR A ON DAY 1
+        let mut resp = tc
+            .post(format!("/api/tasks/{a_id}/clear-downstream"))
+            .json(json!({ "trigger_datetime": day1 }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let reply: Value = resp.body_json().await?;
+        assert_eq!(reply, json!({ "activated": 1, "cleared": 4 }));
+
+        // EACH CHILD KEEPS THE COUNT FROM PARENTS WHICH WERE NOT CLEARED
+        assert_eq!(
+            get_token_at(&pool, b_id, day1).await?,
+            (0, "waiting".to_owned())
+        );
+        assert_eq!(
+            get_token_at(&pool, c_id, day1).await?,
+            (0, "waiting".to_owned())
+        );
+        // a on day 0 is not cleared
+        assert_eq!(
+            get_token_at(&pool, d_id, day1).await?,
+            (1, "waiting".to_owned())
+        );
+        // b and c on day 2 are not cleared
+        assert_eq!(
+            get_token_at(&pool, d_id, day2).await?,
+            (2, "waiting".to_owned())
+        );
+
+        // A RUNS AGAIN
+        let worker_id = add_worker(&pool).await?;
+        let amqp_chan = start_progress(&server).await?;
+        start_tokens(&server);
+        start_executions(&server);
+
+        let a_run = wait_for_active_run(&pool, a_id, day1).await?;
+        publish_success_at(&amqp_chan, worker_id, a_run, a_id, day1).await?;
+        wait_for_run(&pool, a_run, "success").await?;
+
+        // D ON DAY 2 ONLY WAITED FOR A
+        wait_for_active_run(&pool, d_id, day2).await?;
+
+        // D ON DAY 1 WAITS FOR BOTH B AND C
+        let b_run = wait_for_active_run(&pool, b_id, day1).await?;
+        publish_success_at(&amqp_chan, worker_id, b_run, b_id, day1).await?;
+        wait_for_run(&pool, b_run, "success").await?;
+        assert_eq!(
+            get_token_at(&pool, d_id, day1).await?,
+            (2, "waiting".to_owned())
+        );
+
+        let c_run = wait_for_active_run(&pool, c_id, day1).await?;
+        publish_success_at(&amqp_chan, worker_id, c_run, c_id, day1).await?;
+        wait_for_run(&pool, c_run, "success").await?;
+        wait_for_active_run(&pool, d_id, day1).await?;
+
+        // EVERY CLEARED TOKEN RAN EXACTLY ONCE
+        for (task_id, trigger_datetime) in [
+            (a_id, day1),
+            (b_id, day1),
+            (c_id, day1),
+            (d_id, day1),
+            (d_id, day2),
+        ] {
+            assert_eq!(count_runs_at(&pool, task_id, trigger_datetime).await?, 1);
+        }
+        assert_eq!(count_runs_at(&pool, a_id, TRIGGER_DATETIME).await?, 0);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_backfill_counts_finished_upstream() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let server = Server::new(config.clone()).await?;
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT, AN UPSTREAM JOB AND A JOB DEPENDING ON IT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "progress_tests",
+              "description": "Project used for progress tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let upstream_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": upstream_uuid,
+                "name": "upstream_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    { "name": "extract" },
+                    { "name": "check", "branches": { "names": ["go"] } },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let downstream_uuid = "00000000-0000-0000-0000-000000000002";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": downstream_uuid,
+                "name": "downstream_job",
+                "project": "progress_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [],
+                "tasks": [
+                    { "name": "prepare" },
+                    {
+                        "name": "load",
+                        "depends": [
+                            "task/prepare",
+                            "progress_tests/upstream_job/task/extract",
+                            "progress_tests/upstream_job/task/check#go",
+                        ],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // THE UPSTREAM JOB FINISHED LONG AGO
+        for name in ["extract", "check"] {
+            let task_id = get_task_id(&pool, upstream_uuid, name).await?;
+            insert_token(&pool, task_id, TRIGGER_DATETIME, 0, "success").await?;
+        }
+
+        // THE DOWNSTREAM JOB IS BACKFILLED
+        let prepare_id = get_task_id(&pool, downstream_uuid, "prepare").await?;
+        let worker_id = add_worker(&pool).await?;
+        let prepare_run = start_run(&pool, worker_id, prepare_id).await?;
+
+        let amqp_chan = start_progress(&server).await?;
+        publish_success(&amqp_chan, worker_id, prepare_run, prepare_id).await?;
+        wait_for_run(&pool, prepare_run, "success").await?;
+
+        // THE NEW TOKEN COUNTS THE FINISHED UPSTREAM TASK BUT NOT THE BRANCH
+        let load_id = get_task_id(&pool, downstream_uuid, "load").await?;
+        assert_eq!(get_token(&pool, load_id).await?, (2, "waiting".to_owned()));
+
+        Ok(())
+    })
+    .await
+}
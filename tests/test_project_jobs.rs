@@ -72,6 +72,7 @@ pub async fn test_project_jobs() -> highnoon::Result<()> {
                 "name": job1_name,
                 "description": "A test job",
                 "paused": false,
+                "archived_datetime": null,
                 "success": 0,
                 "running": 0,
                 "failure": 0,
@@ -83,6 +84,7 @@ pub async fn test_project_jobs() -> highnoon::Result<()> {
                 "name": job2_name,
                 "description": "A test job",
                 "paused": false,
+                "archived_datetime": null,
                 "success": 0,
                 "running": 0,
                 "failure": 0,
@@ -108,6 +110,7 @@ pub async fn test_project_jobs() -> highnoon::Result<()> {
                 "name": job1_name,
                 "description": "A test job",
                 "paused": false,
+                "archived_datetime": null,
                 "success": 0,
                 "running": 0,
                 "failure": 0,
@@ -135,3 +138,81 @@ pub async fn test_project_jobs() -> highnoon::Result<()> {
     })
     .await
 }
+
+#[tokio::main]
+#[test]
+pub async fn test_job_versions() -> highnoon::Result<()> {
+    common::with_external_services(|config| async {
+        let tc = make_app(config).await?.test();
+
+        // CREATE A PROJECT
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": "00000000-0000-0000-0000-000000000000",
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // SUBMIT THE SAME JOB TWICE, THEN A CHANGED ONE
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let job = json!({
+            "uuid": job_uuid,
+            "name": "versioned_job",
+            "project": "integration_tests",
+            "description": "A test job",
+            "paused": false,
+            "triggers": [],
+            "tasks": [],
+        });
+
+        for description in ["A test job", "A test job", "A changed job"] {
+            let mut job = job.clone();
+            job["description"] = json!(description);
+            let resp = tc.post("/api/jobs").json(job)?.send().await?;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        // EVERY SUBMISSION IS A VERSION
+        let mut resp = tc
+            .get(format!("/api/jobs/{job_uuid}/versions"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let versions: Value = resp.body_json().await?;
+        let versions: Vec<_> = versions
+            .as_array()
+            .expect("versions are a list")
+            .iter()
+            .map(|v| (v["version"].clone(), v["definition_version"].clone()))
+            .collect();
+        assert_eq!(
+            versions,
+            vec![
+                (json!(3), Value::Null),
+                (json!(2), json!(1)),
+                (json!(1), Value::Null),
+            ]
+        );
+
+        // THE UNCHANGED VERSION REUSES THE EARLIER DEFINITION
+        let mut resp = tc
+            .get(format!("/api/jobs/{job_uuid}/versions/2"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let version: Value = resp.body_json().await?;
+        let definition: Value = serde_json::from_str(
+            version["raw_definition"]
+                .as_str()
+                .expect("definition is a string"),
+        )?;
+        assert_eq!(definition["description"], json!("A test job"));
+
+        Ok(())
+    })
+    .await
+}
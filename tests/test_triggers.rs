@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use highnoon::StatusCode;
+use pretty_assertions::assert_eq;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+use waterwheel::server::api::make_app;
+
+mod common;
+
+const PROJECT_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+async fn get_task_id(pool: &PgPool, job_id: &str, name: &str) -> highnoon::Result<Uuid> {
+    let (id,): (Uuid,) = sqlx::query_as(
+        "SELECT id
+        FROM task
+        WHERE job_id = $1
+        AND name = $2",
+    )
+    .bind(Uuid::parse_str(job_id)?)
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+    Ok(id)
+}
+
+/// Get the trigger datetime and count of each of a task's tokens
+async fn get_tokens(pool: &PgPool, task_id: Uuid) -> highnoon::Result<Vec<(DateTime<Utc>, i32)>> {
+    let tokens = sqlx::query_as(
+        "SELECT trigger_datetime, count
+        FROM token
+        WHERE task_id = $1
+        ORDER BY trigger_datetime",
+    )
+    .bind(task_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(tokens)
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_event_idempotency_key() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH AN EVENT TRIGGER
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "event_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [
+                    { "name": "upload", "kind": "event" },
+                ],
+                "tasks": [
+                    {
+                        "name": "load",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/upload"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let mut resp = tc
+            .get(format!("/api/jobs/{job_uuid}/triggers"))
+            .send()
+            .await?;
+        let triggers: Value = resp.body_json().await?;
+        let trigger_id = triggers[0]["trigger_id"]
+            .as_str()
+            .expect("trigger id is a string")
+            .to_owned();
+        let task_id = get_task_id(&pool, job_uuid, "load").await?;
+
+        // POST AN EVENT
+        let mut resp = tc
+            .post(format!("/api/triggers/{trigger_id}/events"))
+            .json(json!({
+                "trigger_datetime": "2000-01-01T00:00:00Z",
+                "idempotency_key": "upload-1",
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let reply: Value = resp.body_json().await?;
+        assert_eq!(reply["activated"], json!(1));
+        assert_eq!(reply["duplicate"], json!(false));
+
+        // POST IT AGAIN WITH A DIFFERENT DATETIME
+        let mut resp = tc
+            .post(format!("/api/triggers/{trigger_id}/events"))
+            .json(json!({
+                "trigger_datetime": "2000-01-02T00:00:00Z",
+                "idempotency_key": "upload-1",
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let reply: Value = resp.body_json().await?;
+        assert_eq!(reply["activated"], json!(0));
+        assert_eq!(reply["duplicate"], json!(true));
+
+        // THE REPLY HAS THE ORIGINAL DATETIME, AND ONLY IT WAS ACTIVATED
+        let original: DateTime<Utc> = "2000-01-01T00:00:00Z".parse()?;
+        let replied: DateTime<Utc> = reply["trigger_datetime"]
+            .as_str()
+            .expect("trigger datetime is a string")
+            .parse()?;
+        assert_eq!(replied, original);
+        assert_eq!(get_tokens(&pool, task_id).await?, vec![(original, 1)]);
+
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::main]
+#[test]
+pub async fn test_stash_writes_fire_once_per_second() -> highnoon::Result<()> {
+    common::with_external_services(|config| async move {
+        let tc = make_app(config.clone()).await?.test();
+        let pool = PgPool::connect(&config.db_url).await?;
+
+        // CREATE A PROJECT AND A JOB WITH A STASH TRIGGER
+        let resp = tc
+            .post("/api/projects")
+            .json(json!({
+              "uuid": PROJECT_UUID,
+              "name": "integration_tests",
+              "description": "Project used for integration tests"
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let job_uuid = "00000000-0000-0000-0000-000000000001";
+        let resp = tc
+            .post("/api/jobs")
+            .json(json!({
+                "uuid": job_uuid,
+                "name": "stash_job",
+                "project": "integration_tests",
+                "description": "A test job",
+                "paused": false,
+                "triggers": [
+                    {
+                        "name": "orders-ready",
+                        "kind": "stash",
+                        "stash": { "project": "integration_tests", "key": "orders" },
+                    },
+                ],
+                "tasks": [
+                    {
+                        "name": "load",
+                        "docker": { "image": "bash", "args": [] },
+                        "depends": ["trigger/orders-ready"],
+                    },
+                ],
+            }))?
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let task_id = get_task_id(&pool, job_uuid, "load").await?;
+
+        let write_stash = |data: &'static str| {
+            tc.put(format!("/api/projects/{PROJECT_UUID}/stash/orders"))
+                .body(data)
+        };
+
+        // WAIT FOR THE START OF A SECOND, SO BOTH WRITES FALL WITHIN IT
+        let millis = Utc::now().timestamp_subsec_millis();
+        if millis > 200 {
+            tokio::time::sleep(Duration::from_millis(1000u64.saturating_sub(millis.into()))).await;
+        }
+
+        // WRITE THE KEY TWICE
+        for data in ["first", "second"] {
+            let resp = write_stash(data)?.send().await?;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let tokens = get_tokens(&pool, task_id).await?;
+        assert_eq!(tokens.len(), 1, "two writes in one second fire once");
+        assert_eq!(tokens[0].1, 1);
+
+        // A WRITE IN A LATER SECOND FIRES AGAIN
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let resp = write_stash("third")?.send().await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let tokens = get_tokens(&pool, task_id).await?;
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].1, 1);
+
+        Ok(())
+    })
+    .await
+}
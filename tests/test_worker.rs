@@ -45,6 +45,7 @@ pub async fn test_worker() -> highnoon::Result<()> {
                     env: None,
                     paused: false,
                     timeout: None,
+                    sensor: None,
                 }),
             );
         }
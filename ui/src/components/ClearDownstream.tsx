@@ -0,0 +1,65 @@
+import React, { Component } from "react";
+import { Button, Popconfirm, notification } from 'antd';
+
+import axios from 'axios';
+import { ButtonType } from "antd/es/button";
+import { SizeType } from "antd/es/config-provider/SizeContext";
+
+type ClearDownstreamProps = {
+    task_id: string;
+    trigger_datetime: string;
+    type: ButtonType,
+    size?: SizeType,
+};
+
+type ClearDownstreamState = {
+    loading: boolean;
+};
+
+type ClearDownstreamReply = {
+    activated: number;
+    cleared: number;
+};
+
+
+class ClearDownstream extends Component<ClearDownstreamProps, ClearDownstreamState> {
+    constructor(props: ClearDownstreamProps) {
+        super(props);
+        this.state = {
+            loading: false
+        };
+    }
+
+    async clearDownstream() {
+        const { task_id, trigger_datetime } = this.props;
+        this.setState({ loading: true });
+        let resp = await axios.post<ClearDownstreamReply>(`/api/tasks/${task_id}/clear-downstream`, {
+            trigger_datetime: trigger_datetime,
+        });
+        this.setState({ loading: false });
+        notification.success({
+            message: 'Task Activated',
+            description: `The task has been activated and ${resp.data.cleared} downstream tasks will run again after it.`,
+            placement: 'bottomLeft',
+        });
+    }
+
+    render() {
+        const { loading } = this.state;
+        const { type, size } = this.props;
+        return (
+            <Popconfirm
+                title="Clear this task and everything downstream of it?"
+                onConfirm={() => this.clearDownstream()}
+            >
+                <Button
+                    loading={loading}
+                    type={type}
+                    size={size}
+                >Clear Downstream</Button>
+            </Popconfirm>
+        );
+    }
+}
+
+export default ClearDownstream;
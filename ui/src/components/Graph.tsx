@@ -34,9 +34,29 @@ function stateColor(state: States | null) {
         error: orange[3], // TODO - different to timeout
         retry: purple[3],
         cancelled: grey[3],
+        skipped: grey[1],
     }[state] : grey[0];
 }
 
+function formatOffset(seconds: number) {
+    for (const [unit, size] of [['d', 86400], ['h', 3600], ['m', 60]] as const) {
+        if (seconds % size === 0) {
+            return `${seconds / size}${unit}`;
+        }
+    }
+    return `${seconds}s`;
+}
+
+// label edges with their offset, or the range of offsets when there are several
+function offsetLabel(offsets: number[]) {
+    if (offsets.length > 1) {
+        return `${formatOffset(offsets[0])}..${formatOffset(offsets[offsets.length - 1])}`;
+    } else if (offsets.length === 1 && offsets[0] !== 0) {
+        return formatOffset(offsets[0]);
+    }
+    return undefined;
+}
+
 
 type JobGraphProps = {
     id: string;
@@ -77,17 +97,34 @@ class JobGraph extends Component<JobGraphProps, JobGraphState> {
             }   
         }
 
+        // dependencies on jobs which don't exist yet are drawn dashed from a placeholder node
+        const pendingNodes = Array.from(new Set(data.pending.map(p => p.reference))).map(reference => ({
+            id: `pending:${reference}`,
+            label: reference,
+            title: `${reference} doesn't exist yet`,
+            shape: 'box',
+            color: grey[0],
+            shapeProperties: { borderDashes: true },
+        }));
+
+        const pendingEdges = data.pending.map(p => ({
+            to: p.to,
+            "from": `pending:${p.reference}`,
+            dashes: true,
+        }));
+
         return {
-            nodes: data.nodes.map(n => ({
+            nodes: [...data.nodes.map(n => ({
                 id: n.id,
                 label: nodeLabel(n),
                 title: nodeTitle(n),
                 shape: (n.kind === 'trigger' ? 'ellipse': 'box'),
                 color: (n.kind === 'trigger' ? yellow[3] : stateColor(n.state))
-            })),
-            edges: data.edges.map(e => ({
+            })), ...pendingNodes],
+            edges: [...data.edges.map(e => ({
                 to: e.to,
                 "from": e.from,
+                label: offsetLabel(e.offsets),
                 arrows: {
                     middle: {
                         enabled: (e.kind == 'failure'),
@@ -95,7 +132,7 @@ class JobGraph extends Component<JobGraphProps, JobGraphState> {
                         type: 'bar',
                     }
                 }
-            })),
+            })), ...pendingEdges],
           };
     }
 
@@ -0,0 +1,59 @@
+import React, { Component } from "react";
+import { Button, Popconfirm, notification } from 'antd';
+
+import axios from 'axios';
+import { SizeType } from "antd/es/config-provider/SizeContext";
+
+type MarkTokenProps = {
+    task_id: string;
+    trigger_datetime: string;
+    state: 'success' | 'failure';
+    size?: SizeType,
+};
+
+type MarkTokenState = {
+    loading: boolean;
+};
+
+
+class MarkToken extends Component<MarkTokenProps, MarkTokenState> {
+    constructor(props: MarkTokenProps) {
+        super(props);
+        this.state = {
+            loading: false
+        };
+    }
+
+    async markToken() {
+        const { task_id, trigger_datetime, state } = this.props;
+        this.setState({ loading: true });
+        await axios.put(`/api/tasks/${task_id}/tokens/${trigger_datetime}/state`, {
+            state: state,
+        });
+        this.setState({ loading: false });
+        notification.success({
+            message: 'Task Marked',
+            description: `The task has been marked as ${state}.`,
+            placement: 'bottomLeft',
+        });
+    }
+
+    render() {
+        const { loading } = this.state;
+        const { size, state } = this.props;
+        return (
+            <Popconfirm
+                title={`Mark this task as ${state} without running it?`}
+                onConfirm={() => this.markToken()}
+            >
+                <Button
+                    loading={loading}
+                    danger={state === 'failure'}
+                    size={size}
+                >{state === 'success' ? 'Mark Success' : 'Mark Failure'}</Button>
+            </Popconfirm>
+        );
+    }
+}
+
+export default MarkToken;
@@ -11,6 +11,7 @@ import {
   StopOutlined,
   PlusSquareOutlined,
   HourglassOutlined,
+  EyeOutlined,
 } from '@ant-design/icons';
 import { orange } from '@ant-design/colors';
 
@@ -52,6 +53,12 @@ class State extends Component<StateProps> {
     } else if (state == 'retry') {
        color = 'purple';
        icon = <PlusSquareOutlined />;
+    } else if (state == 'skipped') {
+       color = 'default';
+       icon = <MinusCircleOutlined />;
+    } else if (state == 'sensing') {
+       color = 'cyan';
+       icon = <EyeOutlined />;
     } else {
       color = 'warning';
       icon = <WarningOutlined />;
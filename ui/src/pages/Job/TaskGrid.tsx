@@ -1,7 +1,7 @@
 import React, { Component, Fragment } from "react";
 import { Link } from "react-router-dom";
 import { notification, Row, Button, DatePicker, Space, Col, Tooltip } from 'antd';
-import { geekblue, lime, red, grey, orange, purple } from '@ant-design/colors';
+import { geekblue, lime, red, grey, orange, purple, cyan } from '@ant-design/colors';
 import axios from 'axios';
 import styled, { CSSProperties } from 'styled-components';
 import { Dayjs } from "dayjs";
@@ -17,6 +17,7 @@ import {
   StopOutlined,
   PlusSquareOutlined,
   HourglassOutlined,
+  EyeOutlined,
 } from '@ant-design/icons';
 import { TokenOverview, TokenState } from "../../types/Token";
 import { datetime, interval, uuid } from "../../types/common";
@@ -69,6 +70,10 @@ function iconForState(task: TokenState) {
         icon = <StopOutlined style={{color: grey[5]}} />;
     } else if (state == 'retry') {
         icon = <PlusSquareOutlined  style={{color: purple[6]}} />;
+    } else if (state == 'skipped') {
+        icon = <MinusOutlined style={{color: grey[3]}} />;
+    } else if (state == 'sensing') {
+        icon = <EyeOutlined style={{color: cyan[6]}} />;
     } else {
         icon = 'invalid state?';
     }
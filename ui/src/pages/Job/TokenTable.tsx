@@ -117,6 +117,8 @@ class TokenTable extends Component<TokenTableProps, TokenTableState> {
                     <Option value="waiting">Waiting</Option>
                     <Option value="error">Error</Option>
                     <Option value="retry">Retry</Option>
+                    <Option value="skipped">Skipped</Option>
+                    <Option value="sensing">Sensing</Option>
                 </Select>
 
                 <Table rowKey={record => record.trigger_datetime + record.task_name}
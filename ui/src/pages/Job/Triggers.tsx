@@ -27,7 +27,19 @@ function makeColumns(job: Job): ColumnsType<JobTrigger> {
         },{
             title: 'Schedule',
             key: 'period',
-            render: (text, record) => record.period ? <Period period={record.period} /> : <Cron cron={record.cron ?? ''} />,
+            render: (text, record) => (
+                record.kind === 'event' ? <Tag color="purple">event</Tag>
+                : record.kind === 'amqp' ? (
+                    <Tooltip title={`routing key: ${record.amqp_routing_key ?? '(none)'}`}>
+                        <Tag color="purple">amqp: {record.amqp_exchange}</Tag>
+                    </Tooltip>
+                )
+                : record.kind === 'stash' ? (
+                    <Tag color="purple">stash: {record.stash_project}/{record.stash_key}</Tag>
+                )
+                : record.period ? <Period period={record.period} />
+                : <Cron cron={record.cron ?? ''} />
+            ),
         },{
             title: 'Offset',
             dataIndex: 'trigger_offset',
@@ -35,6 +47,13 @@ function makeColumns(job: Job): ColumnsType<JobTrigger> {
         },{
             title: 'Catchup',
             dataIndex: 'catchup',
+            render: (text, record) => (
+                <Space>
+                    {text}
+                    {record.catchup_max_runs && <Tag>max {record.catchup_max_runs} runs</Tag>}
+                    {record.catchup_max_age && <Tag>max age {prettyMilliseconds(record.catchup_max_age * 1000)}</Tag>}
+                </Space>
+            ),
         },{
             title: 'Start',
             dataIndex: 'start_datetime',
@@ -10,6 +10,7 @@ import { PageHeader } from '@ant-design/pro-components';
 import axios from 'axios';
 
 import Body from '../components/Body';
+import Upcoming from './Project/Upcoming';
 import { ProjectExtra, ProjectJob } from "../types/Project";
 import { interval } from "../types/common";
 import { JobExtra } from "../types/Job";
@@ -160,6 +161,12 @@ function Project() {
                         />
                 </Col>
             </Row>
+            <Row>
+                <Col span={24}>
+                    <Typography.Title level={4}>Upcoming Runs (next 7 days)</Typography.Title>
+                    <Upcoming id={id} />
+                </Col>
+            </Row>
         </>
     ) : <Spin size="large" />;
 
@@ -0,0 +1,86 @@
+import React, { useEffect, useState } from "react";
+import { Link } from "react-router-dom";
+import { Table, Tag, Tooltip, Typography } from 'antd';
+import { ColumnsType } from "antd/es/table";
+import { PauseOutlined } from '@ant-design/icons';
+import axios from 'axios';
+
+import Date from '../../components/Date';
+import { ForecastTime } from "../../types/Project";
+
+const { Text } = Typography;
+
+function makeColumns(): ColumnsType<ForecastTime> {
+    return [
+        {
+            title: 'Scheduled',
+            dataIndex: 'scheduled_datetime',
+            render: text => <Date>{text}</Date>,
+        },{
+            title: 'Job',
+            dataIndex: 'job_name',
+            render: (text, record) => (
+                <Link to={`/jobs/${record.job_id}`}>
+                    {text}
+                </Link>
+            ),
+        },{
+            title: 'Trigger',
+            dataIndex: 'trigger_name',
+            render: (text, record) => (
+                <Link to={`/jobs/${record.job_id}/triggers/${record.trigger_id}`}>
+                    {text}
+                </Link>
+            ),
+        },{
+            title: 'Trigger Datetime',
+            dataIndex: 'trigger_datetime',
+            render: text => <Text type="secondary">{text}</Text>,
+        },{
+            title: '',
+            key: 'status',
+            render: (_, record) => (<>
+                {record.paused && <Tag color="warning" icon={<PauseOutlined />}>paused</Tag>}
+                {record.skipped &&
+                    <Tooltip title={record.skip_reason}>
+                        <Tag>skipped</Tag>
+                    </Tooltip>}
+            </>),
+        }
+    ];
+}
+
+const columns = makeColumns();
+
+type UpcomingProps = {
+    id: string;
+};
+
+function Upcoming(props: UpcomingProps) {
+    const [times, setTimes] = useState(null as ForecastTime[] | null);
+
+    async function fetchForecast() {
+        try {
+            let resp = await axios.get<ForecastTime[]>(`/api/projects/${props.id}/forecast`);
+            setTimes(resp.data);
+        } catch(e) {
+            console.log(e);
+            setTimes([]);
+        }
+    }
+
+    useEffect(() => {
+        fetchForecast();
+    }, [props.id]);
+
+    return (
+        <Table rowKey={record => `${record.trigger_id}/${record.trigger_datetime}`}
+            columns={columns}
+            dataSource={times ?? []}
+            loading={times === null}
+            pagination={{position: ['bottomLeft']}}
+            />
+    );
+}
+
+export default Upcoming;
@@ -12,6 +12,8 @@ dayjs.extend(relativeTime);
 import State from '../components/State';
 import Priority from '../components/Priority';
 import ActivateToken from '../components/ActivateToken';
+import ClearDownstream from '../components/ClearDownstream';
+import MarkToken from '../components/MarkToken';
 import { datetime, interval } from "../types/common";
 import { Task, TaskRun } from "../types/Task";
 import RelDate from '../components/Date';
@@ -60,9 +62,12 @@ function expandedRowRender(record: TaskRun) {
                 }
             </Descriptions.Item>
             <Descriptions.Item label="Worker">
-                <Link to={`/workers/${record.worker_id}`}>
-                    {record.worker_id}
-                </Link>
+                {record.manual
+                    ? `marked manually by ${record.principal ?? 'unknown'}`
+                    : <Link to={`/workers/${record.worker_id}`}>
+                        {record.worker_id}
+                    </Link>
+                }
             </Descriptions.Item>
         </Descriptions>
     );
@@ -135,6 +140,21 @@ function TokenRuns({task_id, trigger_datetime}) {
                 size="middle"
                 task_id={task_id ?? ''}
                 trigger_datetime={trigger_datetime ?? ''} />
+            <ClearDownstream
+                type="default"
+                size="middle"
+                task_id={task_id ?? ''}
+                trigger_datetime={trigger_datetime ?? ''} />
+            <MarkToken
+                state="success"
+                size="middle"
+                task_id={task_id ?? ''}
+                trigger_datetime={trigger_datetime ?? ''} />
+            <MarkToken
+                state="failure"
+                size="middle"
+                task_id={task_id ?? ''}
+                trigger_datetime={trigger_datetime ?? ''} />
 
 
             <Descriptions
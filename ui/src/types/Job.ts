@@ -6,6 +6,7 @@ export type Job = {
     name: string;
     description: string;
     paused: boolean;
+    archived_datetime: datetime | null;
 };
 
 export type JobExtra = Job & {
@@ -26,7 +27,8 @@ export type JobQuery = Partial<{
 export type JobTrigger = {
     trigger_id: uuid;
     trigger_name: string;
-    start_datetime: datetime;
+    kind: string;
+    start_datetime: datetime | null;
     end_datetime: datetime;
     earliest_trigger_datetime: datetime | null;
     latest_trigger_datetime: datetime | null;
@@ -34,6 +36,12 @@ export type JobTrigger = {
     cron: string | null;
     trigger_offset: string | null;
     catchup: string | null;
+    catchup_max_runs: number | null;
+    catchup_max_age: number | null;
+    amqp_exchange: string | null;
+    amqp_routing_key: string | null;
+    stash_project: string | null;
+    stash_key: string | null;
 };
 
 export type Trigger = {
@@ -58,6 +66,7 @@ export type TriggerTime = {
 export type JobGraph = {
     nodes: JobGraphNode[];
     edges: JobGraphEdge[];
+    pending: JobGraphPendingEdge[];
 };
 
 export type JobGraphNode = {
@@ -68,10 +77,18 @@ export type JobGraphNode = {
     state: States | null;
 };
 
+// a dependency on a job which doesn't exist yet
+export type JobGraphPendingEdge = {
+    to: uuid;
+    reference: string;
+    kind: string;
+};
+
 export type JobGraphEdge = {
     from: uuid;
     to: uuid;
     kind: string;
+    offsets: number[]; // seconds
 };
 
 export type States = 'active'
@@ -82,4 +99,6 @@ export type States = 'active'
     | 'timeout'
     | 'error'
     | 'retry'
-    | 'cancelled';
+    | 'cancelled'
+    | 'skipped'
+    | 'sensing';
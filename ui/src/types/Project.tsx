@@ -26,3 +26,15 @@ export type ProjectJob = {
     waiting: number;
     error: number;
 };
+
+export type ForecastTime = {
+    job_id: uuid;
+    job_name: string;
+    paused: boolean;
+    trigger_id: uuid;
+    trigger_name: string;
+    trigger_datetime: string;
+    scheduled_datetime: string;
+    skipped: boolean;
+    skip_reason: string | null;
+};
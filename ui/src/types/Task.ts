@@ -33,6 +33,11 @@ export type TaskRun = {
     finish_datetime: datetime;
     state: string;
     worker_id: uuid | null;
+    map_index: number | null;
+    map_item: string | null;
+    manual: boolean;
+    principal: string | null;
+    env_overrides: string[] | null;
 };
 
 export type GetTaskDurationQuery = {